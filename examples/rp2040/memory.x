@@ -1,6 +1,7 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* the last 512K of flash is left for the mass storage `flash` feature */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 1536K - 0x100
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -1,7 +1,7 @@
 mod setup;
 
 use defmt::{error, info, Format};
-pub use setup::init;
+pub use setup::{init, seed};
 
 #[derive(Clone, Format)]
 pub struct Partition {
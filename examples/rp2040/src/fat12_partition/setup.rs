@@ -0,0 +1,59 @@
+use core::convert::Infallible;
+
+use defmt::info;
+use embedded_io_async::{ErrorType, Write};
+use pico_usb_mass_storage::{BlockDevice, BlockDeviceError, TransferError};
+
+use crate::storage::Storage;
+
+static FS_DUMP: &[u8; 102400] = include_bytes!("../../dumps/linux_partitioned.dump");
+
+pub fn init(storage: &mut Storage) {
+    let bytes = storage.as_bytes_mut();
+
+    bytes[..FS_DUMP.len()].copy_from_slice(FS_DUMP);
+}
+
+/// Copy the filesystem onto `block_device` if it's blank, e.g. freshly erased flash. Blocks are
+/// streamed, so any block size that divides the dump will do
+#[allow(dead_code)]
+pub async fn seed<BD: BlockDevice>(block_device: &mut BD) -> Result<(), BlockDeviceError> {
+    let mut blank = Blank(true);
+    block_device
+        .read_to(0, 1, &mut blank)
+        .await
+        .map_err(block_device_error)?;
+    if !blank.0 {
+        return Ok(());
+    }
+
+    info!("storage is blank, writing the initial filesystem");
+    let count = (FS_DUMP.len() / BD::BLOCK_BYTES) as u32;
+    block_device
+        .write_from(0, count, &mut &FS_DUMP[..])
+        .await
+        .map_err(block_device_error)
+}
+
+/// Whether every byte written to it is erased flash
+struct Blank(bool);
+
+impl ErrorType for Blank {
+    type Error = Infallible;
+}
+
+impl Write for Blank {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0 &= buf.iter().all(|&b| b == 0xFF);
+        Ok(buf.len())
+    }
+}
+
+fn block_device_error<E>(e: TransferError<E>) -> BlockDeviceError {
+    match e {
+        TransferError::BlockDevice(e) => e,
+        TransferError::UnexpectedEof | TransferError::Io(_) => {
+            unreachable!("neither the dump nor the blank check fail")
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use embassy_rp::flash::{self, Blocking, Flash, ERASE_SIZE, WRITE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use pico_usb_mass_storage::block_devices::{FlashError, NorFlash};
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// The last 512K of flash, which `memory.x` keeps the firmware out of, less its last sector
+pub const STORAGE_OFFSET: u32 = 1536 * 1024;
+pub const STORAGE_LEN: u32 = 512 * 1024 - ERASE_SIZE as u32;
+
+/// The last sector of flash, for the mode parameters the host saves
+pub const MODE_PARAMETERS_OFFSET: u32 = STORAGE_OFFSET + STORAGE_LEN;
+
+/// The Pico's QSPI flash. The blocking API is used as reads go through XIP anyway, and erasing or
+/// programming needs XIP (and so everything else) stopped
+pub struct RpFlash(pub Flash<'static, FLASH, Blocking, FLASH_SIZE>);
+
+fn flash_error(e: flash::Error) -> FlashError {
+    match e {
+        flash::Error::OutOfBounds => FlashError::OutOfBounds,
+        flash::Error::Unaligned => FlashError::NotAligned,
+        _ => FlashError::Other,
+    }
+}
+
+impl NorFlash for RpFlash {
+    const ERASE_SIZE: usize = ERASE_SIZE;
+    const WRITE_SIZE: usize = WRITE_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.0.blocking_read(offset, bytes).map_err(flash_error)
+    }
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        self.0.blocking_erase(from, to).map_err(flash_error)
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.0.blocking_write(offset, bytes).map_err(flash_error)
+    }
+}
@@ -0,0 +1,291 @@
+#![no_std]
+#![no_main]
+
+use defmt::info;
+use defmt_rtt as _;
+use embassy_executor::Spawner;
+use embassy_rp::usb::Driver;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_usb::{Builder, Config};
+use panic_probe as _;
+
+#[cfg(not(feature = "flash"))]
+use core::ops::Range;
+#[cfg(not(feature = "flash"))]
+use embedded_io_async::{Read, Write};
+#[cfg(not(feature = "flash"))]
+use pico_usb_mass_storage::{BlockDevice, BlockDeviceError, TransferError};
+use pico_usb_mass_storage::{LogicalUnit, State, UsbMassStorage, WriteProtect};
+
+// the RAM disk, unused when storing to flash
+#[cfg_attr(feature = "flash", allow(dead_code))]
+mod storage;
+#[cfg(not(feature = "flash"))]
+use storage::Storage;
+
+#[cfg_attr(feature = "flash", allow(dead_code))]
+mod fat12_partition;
+
+#[cfg(feature = "flash")]
+mod flash;
+
+use pico_usb_mass_storage_rp2040 as lib;
+
+#[cfg(feature = "wifi")]
+mod wifi;
+
+#[cfg(not(feature = "flash"))]
+static mut STORAGE: Storage = Storage::new();
+
+const USB_PACKET_SIZE: u16 = 64; // 8,16,32,64
+
+/// Closed by a switch from GPIO 15 to ground, the disk is read only
+static WRITE_PROTECT: WriteProtect = WriteProtect::new(false);
+
+#[embassy_executor::main]
+async fn main(#[allow(unused_variables)] spawner: Spawner) {
+    #[cfg(not(feature = "flash"))]
+    #[allow(static_mut_refs)]
+    fat12_partition::init(unsafe { &mut STORAGE });
+
+    let p = embassy_rp::init(Default::default());
+
+    let driver = Driver::new(p.USB, lib::Irqs);
+
+    let mut config = Config::new(0xabcd, 0xabcd);
+    config.manufacturer = Some("Chris Price");
+    config.product = Some("100k of your finest bytes");
+    config.serial_number = Some("CP4096OYFB");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    #[cfg(feature = "wifi")]
+    let mut wifi = {
+        use cyw43_pio::PioSpi;
+        use embassy_rp::{
+            gpio::{Level, Output},
+            pio::Pio,
+        };
+
+        let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
+        let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
+
+        // To make flashing faster for development, you may want to flash the firmwares independently
+        // at hardcoded addresses, instead of baking them into the program with `include_bytes!`:
+        //     probe-rs download 43439A0.bin --format bin --chip RP2040 --base-address 0x10100000
+        //     probe-rs download 43439A0_clm.bin --format bin --chip RP2040 --base-address 0x10140000
+        //let fw = unsafe { core::slice::from_raw_parts(0x10100000 as *const u8, 230321) };
+        //let clm = unsafe { core::slice::from_raw_parts(0x10140000 as *const u8, 4752) };
+
+        let pwr = Output::new(p.PIN_23, Level::Low);
+        let cs = Output::new(p.PIN_25, Level::High);
+        let mut pio = Pio::new(p.PIO0, lib::Irqs);
+        let spi = PioSpi::new(
+            &mut pio.common,
+            pio.sm0,
+            pio.irq0,
+            cs,
+            p.PIN_24,
+            p.PIN_29,
+            p.DMA_CH0,
+        );
+
+        //let mut blinky = Blinky::build(fw, clm, pwr, spi, spawner).await;
+        wifi::server::Server::build(fw, clm, pwr, spi, spawner).await
+    };
+
+    let mut device_descriptor = [0; 256];
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut mos_descriptor = [0; 0];
+    let mut control_buf = [0; 64];
+
+    let mut usb_mass_storage_state = State::<NoopRawMutex>::default();
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut device_descriptor,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut mos_descriptor,
+        &mut control_buf,
+    );
+
+    let vendor_id = b"CHRISP  "; // per the spec, unused bytes should be a space
+    let product_id = b"100k of trunc   ";
+    let product_revision = b"1.24";
+
+    #[cfg(not(feature = "flash"))]
+    let mut block_device = InMemoryBlockDevice;
+
+    #[cfg(feature = "flash")]
+    let mut block_device = {
+        use embassy_rp::flash::Flash;
+        use pico_usb_mass_storage::block_devices::{FlashBlockDevice, WriteBackCache};
+
+        let flash = flash::RpFlash(Flash::new_blocking(p.FLASH));
+        let mut block_device: FlashBlockDevice<_> = FlashBlockDevice::new(
+            flash,
+            flash::STORAGE_OFFSET,
+            flash::STORAGE_LEN,
+            flash::MODE_PARAMETERS_OFFSET,
+        );
+        fat12_partition::seed(&mut block_device).await.unwrap();
+        // a sector's worth of blocks, so each sector is erased once however its blocks are written
+        WriteBackCache::<_, 8>::new(block_device)
+    };
+
+    let mut logical_unit =
+        LogicalUnit::new(&mut block_device, vendor_id, product_id, product_revision);
+    logical_unit.set_serial_number(b"CP4096OYFB");
+    logical_unit.set_write_protect(&WRITE_PROTECT);
+
+    let mut write_protect_switch =
+        embassy_rp::gpio::Input::new(p.PIN_15, embassy_rp::gpio::Pull::Up);
+    let write_protect_fut = async {
+        loop {
+            let protected = write_protect_switch.is_low();
+            if protected != WRITE_PROTECT.is_protected() {
+                info!("write protect {}", protected);
+                WRITE_PROTECT.set(protected);
+            }
+            write_protect_switch.wait_for_any_edge().await;
+        }
+    };
+
+    let mut usb_mass_storage = UsbMassStorage::new(
+        &mut usb_mass_storage_state,
+        &mut builder,
+        USB_PACKET_SIZE,
+        [logical_unit],
+    );
+
+    let mut usb = builder.build();
+    let usb_fut = usb.run();
+
+    let usb_mass_storage_fut = usb_mass_storage.run();
+
+    #[cfg(feature = "wifi")]
+    {
+        let wifi_fut = wifi.run();
+        embassy_futures::join::join4(usb_fut, usb_mass_storage_fut, write_protect_fut, wifi_fut)
+            .await;
+    }
+    #[cfg(not(feature = "wifi"))]
+    {
+        embassy_futures::join::join3(usb_fut, usb_mass_storage_fut, write_protect_fut).await;
+    }
+}
+
+#[cfg(not(feature = "flash"))]
+struct InMemoryBlockDevice;
+
+#[cfg(not(feature = "flash"))]
+impl InMemoryBlockDevice {
+    /// The bytes of `count` blocks from `lba` in `STORAGE`
+    fn range(lba: u64, count: u32) -> Result<Range<usize>, BlockDeviceError> {
+        if lba.saturating_add(count as u64) > storage::BLOCKS as u64 {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        let start = lba as usize * storage::BLOCK_SIZE;
+        Ok(start..start + count as usize * storage::BLOCK_SIZE)
+    }
+
+    fn log_fs() {
+        #[allow(static_mut_refs)]
+        let storage = unsafe { &mut STORAGE };
+
+        fat12_partition::log_fs(
+            storage.as_bytes_mut(),
+            storage::BLOCKS as _,
+            storage::BLOCK_SIZE as _,
+        );
+
+        for id in 0..4 {
+            let partition = fat12_partition::read_partition(storage.as_bytes_mut(), id);
+            info!("partition {}: {}", id, partition);
+        }
+    }
+}
+
+#[cfg(not(feature = "flash"))]
+impl BlockDevice for InMemoryBlockDevice {
+    const BLOCK_BYTES: usize = storage::BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u64, output: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let range = Self::range(lba, 1)?;
+
+        #[allow(static_mut_refs)]
+        let storage = unsafe { &STORAGE };
+
+        output.copy_from_slice(&storage.as_bytes()[range]);
+        Ok(())
+    }
+
+    async fn write_block(&mut self, lba: u64, input: &[u8]) -> Result<(), BlockDeviceError> {
+        let range = Self::range(lba, 1)?;
+
+        #[allow(static_mut_refs)]
+        let storage = unsafe { &mut STORAGE };
+
+        storage.as_bytes_mut()[range].copy_from_slice(input);
+
+        Self::log_fs();
+        Ok(())
+    }
+
+    /// Streams straight out of `STORAGE`
+    async fn read_to<W: Write>(
+        &mut self,
+        lba: u64,
+        count: u32,
+        to: &mut W,
+    ) -> Result<(), TransferError<W::Error>> {
+        let range = Self::range(lba, count)?;
+
+        #[allow(static_mut_refs)]
+        let storage = unsafe { &STORAGE };
+
+        to.write_all(&storage.as_bytes()[range])
+            .await
+            .map_err(TransferError::Io)
+    }
+
+    /// Streams straight into `STORAGE`
+    async fn write_from<R: Read>(
+        &mut self,
+        lba: u64,
+        count: u32,
+        from: &mut R,
+    ) -> Result<(), TransferError<R::Error>> {
+        let range = Self::range(lba, count)?;
+
+        #[allow(static_mut_refs)]
+        let storage = unsafe { &mut STORAGE };
+
+        from.read_exact(&mut storage.as_bytes_mut()[range]).await?;
+
+        Self::log_fs();
+        Ok(())
+    }
+
+    /// Zeroes the blocks in `STORAGE`, laying down the initial filesystem once the last is zeroed
+    async fn format(&mut self, lba: u64, count: u32) -> Result<(), BlockDeviceError> {
+        let range = Self::range(lba, count)?;
+
+        #[allow(static_mut_refs)]
+        let storage = unsafe { &mut STORAGE };
+
+        storage.as_bytes_mut()[range].fill(0);
+        if lba + count as u64 == storage::BLOCKS as u64 {
+            fat12_partition::init(storage);
+            Self::log_fs();
+        }
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        storage::BLOCKS as u64 - 1
+    }
+}
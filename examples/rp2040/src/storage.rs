@@ -16,7 +16,6 @@ impl Storage {
         self.0.len() * BLOCK_SIZE
     }
 
-    #[allow(dead_code)]
     pub fn as_bytes(&self) -> &[u8] {
         let p = &self.0 as *const _ as *const u8;
         let len = self.byte_len();
@@ -41,10 +40,12 @@ impl Storage {
         &mut self.0
     }
 
+    #[allow(dead_code)]
     pub fn block(&self, block: u32) -> &Block {
         &self.0[block as usize]
     }
 
+    #[allow(dead_code)]
     pub fn block_mut(&mut self, block: u32) -> &mut Block {
         &mut self.0[block as usize]
     }
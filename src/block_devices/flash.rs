@@ -0,0 +1,588 @@
+use core::{future::Future, ops::Range};
+
+use defmt::{error, Format};
+use embedded_io_async::{Read, Write};
+
+use crate::scsi::{BlockDevice, BlockDeviceError, Fill, TransferError, MODE_PARAMETERS_BYTES};
+
+/// Erased flash reads back as all ones
+const ERASED: u8 = 0xFF;
+
+/// Starts the mode parameter sector once parameters are saved in it
+const MODE_PARAMETERS_MAGIC: [u8; 4] = *b"MODE";
+
+/// The magic, then the saved mode parameters
+const MODE_PARAMETERS_RECORD_BYTES: usize = MODE_PARAMETERS_MAGIC.len() + MODE_PARAMETERS_BYTES;
+
+/// Why a [`NorFlash`] operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum FlashError {
+    /// The address is beyond the end of the flash
+    OutOfBounds,
+    /// The address or length isn't a multiple of the erase or write size
+    NotAligned,
+    /// Any other failure of the flash itself
+    Other,
+}
+
+/// NOR flash: bits can only be programmed from one to zero, and only a whole erase sector at a
+/// time can be set back to ones. This mirrors `embedded_storage_async::nor_flash::NorFlash`, so
+/// HAL flash drivers are straightforward to adapt.
+pub trait NorFlash {
+    /// The number of bytes erased at once, addresses passed to `erase` are multiples of this
+    const ERASE_SIZE: usize;
+
+    /// The number of bytes programmed at once, addresses and lengths passed to `write` are
+    /// multiples of this
+    const WRITE_SIZE: usize;
+
+    /// Read `bytes.len()` bytes from `offset`
+    fn read(
+        &mut self,
+        offset: u32,
+        bytes: &mut [u8],
+    ) -> impl Future<Output = Result<(), FlashError>>;
+
+    /// Erase the sectors from `from` up to (but not including) `to`
+    fn erase(&mut self, from: u32, to: u32) -> impl Future<Output = Result<(), FlashError>>;
+
+    /// Program `bytes` at `offset`, which must have been erased
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> impl Future<Output = Result<(), FlashError>>;
+}
+
+/// A [`BlockDevice`] over a region of NOR flash
+///
+/// Logical blocks are smaller than the flash's erase sectors, so writing a block reads the whole
+/// sector into RAM, erases it and programs it back with the new block in place. That's skipped
+/// where it isn't needed: a block that's unchanged isn't written at all, and a block that's
+/// already erased is programmed directly.
+///
+/// Formatting erases the blocks, a sector at a time. Discarded blocks are erased too, so long as
+/// the whole sector is discarded.
+///
+/// The mode parameters the host saves are kept in a sector of their own, outside the blocks.
+///
+/// `SECTOR_BYTES` must be the flash's [`NorFlash::ERASE_SIZE`], and is the RAM needed for the
+/// read-modify-write.
+pub struct FlashBlockDevice<F, const SECTOR_BYTES: usize = 4096, const BLOCK_BYTES: usize = 512> {
+    flash: F,
+    offset: u32,
+    len: u32,
+    /// The address of the sector the mode parameters are saved in
+    mode_parameters: u32,
+    sector: [u8; SECTOR_BYTES],
+}
+
+impl<F: NorFlash, const SECTOR_BYTES: usize, const BLOCK_BYTES: usize>
+    FlashBlockDevice<F, SECTOR_BYTES, BLOCK_BYTES>
+{
+    /// Store blocks in the `len` bytes of `flash` from `offset`, and saved mode parameters in the
+    /// sector at `mode_parameters`, which mustn't be one of them. All must be a multiple of the
+    /// erase sector size.
+    pub fn new(flash: F, offset: u32, len: u32, mode_parameters: u32) -> Self {
+        assert_eq!(
+            SECTOR_BYTES,
+            F::ERASE_SIZE,
+            "sector size must match the flash"
+        );
+        assert!(
+            SECTOR_BYTES.is_multiple_of(BLOCK_BYTES),
+            "blocks must fit evenly in a sector"
+        );
+        assert!(
+            BLOCK_BYTES.is_multiple_of(F::WRITE_SIZE),
+            "blocks must be programmable"
+        );
+        assert!(
+            (offset as usize).is_multiple_of(SECTOR_BYTES),
+            "region must start on a sector"
+        );
+        assert!(
+            (len as usize).is_multiple_of(SECTOR_BYTES),
+            "region must be whole sectors"
+        );
+        assert!(len > 0);
+        assert!(
+            (mode_parameters as usize).is_multiple_of(SECTOR_BYTES),
+            "mode parameters must start on a sector"
+        );
+        assert!(
+            !(offset..offset + len).contains(&mode_parameters),
+            "mode parameters mustn't overlap the region"
+        );
+        assert!(
+            MODE_PARAMETERS_RECORD_BYTES.next_multiple_of(F::WRITE_SIZE) <= SECTOR_BYTES,
+            "mode parameters must fit in a sector"
+        );
+
+        Self {
+            flash,
+            offset,
+            len,
+            mode_parameters,
+            sector: [0; SECTOR_BYTES],
+        }
+    }
+
+    /// Give back the underlying flash
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// The flash address of `lba`, checking `len` bytes from it are in the region
+    fn address(&self, lba: u64, len: usize) -> Result<u32, BlockDeviceError> {
+        let address = lba.saturating_mul(BLOCK_BYTES as u64);
+        if address.saturating_add(len as u64) > self.len as u64 {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        Ok(self.offset + address as u32)
+    }
+
+    /// Write the blocks at `in_sector` of the sector at `sector_address`, reading them from
+    /// `from`, and erasing the sector only if it has to be
+    async fn write_in_sector<R: Read>(
+        &mut self,
+        sector_address: u32,
+        in_sector: Range<usize>,
+        from: &mut R,
+    ) -> Result<(), TransferError<R::Error>> {
+        self.flash
+            .read(sector_address, &mut self.sector)
+            .await
+            .map_err(|e| {
+                error!("flash: read of {} failed: {}", sector_address, e);
+                BlockDeviceError::ReadError
+            })?;
+
+        let erased = self.sector[in_sector.clone()].iter().all(|&b| b == ERASED);
+        let mut changed = false;
+        let mut block = [0u8; BLOCK_BYTES];
+        for start in in_sector.clone().step_by(BLOCK_BYTES) {
+            from.read_exact(&mut block).await?;
+            let current = &mut self.sector[start..start + BLOCK_BYTES];
+            if *current != block {
+                current.copy_from_slice(&block);
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return Ok(());
+        }
+
+        if erased {
+            let address = sector_address + in_sector.start as u32;
+            return self
+                .flash
+                .write(address, &self.sector[in_sector])
+                .await
+                .map_err(|e| {
+                    error!("flash: write of {} failed: {}", address, e);
+                    BlockDeviceError::WriteError.into()
+                });
+        }
+
+        self.flash
+            .erase(sector_address, sector_address + SECTOR_BYTES as u32)
+            .await
+            .map_err(|e| {
+                error!("flash: erase of {} failed: {}", sector_address, e);
+                BlockDeviceError::EraseError
+            })?;
+        self.flash
+            .write(sector_address, &self.sector)
+            .await
+            .map_err(|e| {
+                error!("flash: write of {} failed: {}", sector_address, e);
+                BlockDeviceError::WriteError.into()
+            })
+    }
+
+    /// Erase the sector at `sector_address`, unless it already is
+    async fn erase_sector(&mut self, sector_address: u32) -> Result<(), BlockDeviceError> {
+        self.flash
+            .read(sector_address, &mut self.sector)
+            .await
+            .map_err(|e| {
+                error!("flash: read of {} failed: {}", sector_address, e);
+                BlockDeviceError::ReadError
+            })?;
+        if self.sector.iter().all(|&b| b == ERASED) {
+            return Ok(());
+        }
+        self.flash
+            .erase(sector_address, sector_address + SECTOR_BYTES as u32)
+            .await
+            .map_err(|e| {
+                error!("flash: erase of {} failed: {}", sector_address, e);
+                BlockDeviceError::EraseError
+            })
+    }
+}
+
+impl<F: NorFlash, const SECTOR_BYTES: usize, const BLOCK_BYTES: usize> BlockDevice
+    for FlashBlockDevice<F, SECTOR_BYTES, BLOCK_BYTES>
+{
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    async fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_blocks(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_blocks(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let address = self.address(lba, blocks.len())?;
+        self.flash.read(address, blocks).await.map_err(|e| {
+            error!("flash: read of {} failed: {}", address, e);
+            BlockDeviceError::ReadError
+        })
+    }
+
+    async fn write_blocks(&mut self, lba: u64, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let count = (blocks.len() / BLOCK_BYTES) as u32;
+        match self.write_from(lba, count, &mut { blocks }).await {
+            Ok(()) => Ok(()),
+            Err(TransferError::BlockDevice(e)) => Err(e),
+            Err(TransferError::UnexpectedEof | TransferError::Io(_)) => {
+                unreachable!("blocks holds all the blocks")
+            }
+        }
+    }
+
+    /// Reads a sector at a time through the sector buffer
+    async fn read_to<W: Write>(
+        &mut self,
+        lba: u64,
+        count: u32,
+        to: &mut W,
+    ) -> Result<(), TransferError<W::Error>> {
+        let len = count as usize * BLOCK_BYTES;
+        let mut address = self.address(lba, len)?;
+        let end = address + len as u32;
+
+        while address < end {
+            let buf = &mut self.sector[..SECTOR_BYTES.min((end - address) as usize)];
+            self.flash.read(address, buf).await.map_err(|e| {
+                error!("flash: read of {} failed: {}", address, e);
+                BlockDeviceError::ReadError
+            })?;
+            to.write_all(buf).await.map_err(TransferError::Io)?;
+            address += buf.len() as u32;
+        }
+
+        Ok(())
+    }
+
+    /// Read-modify-writes each sector once, however many of its blocks are written
+    async fn write_from<R: Read>(
+        &mut self,
+        lba: u64,
+        count: u32,
+        from: &mut R,
+    ) -> Result<(), TransferError<R::Error>> {
+        let len = count as usize * BLOCK_BYTES;
+        let mut address = self.address(lba, len)?;
+        let end = address + len as u32;
+
+        while address < end {
+            let sector_address = address - address % SECTOR_BYTES as u32;
+            let start = (address - sector_address) as usize;
+            let in_sector = start..SECTOR_BYTES.min((end - sector_address) as usize);
+            self.write_in_sector(sector_address, in_sector.clone(), from)
+                .await?;
+            address = sector_address + in_sector.end as u32;
+        }
+
+        Ok(())
+    }
+
+    /// A sector at a time, so each is erased once
+    const FORMAT_BLOCKS: u32 = (SECTOR_BYTES / BLOCK_BYTES) as u32;
+
+    /// Erases the sectors of the formatted blocks, if they aren't already. The blocks of a sector
+    /// that's only partly formatted are erased by reprogramming it
+    async fn format(&mut self, lba: u64, count: u32) -> Result<(), BlockDeviceError> {
+        let len = count as usize * BLOCK_BYTES;
+        let mut address = self.address(lba, len)?;
+        let end = address + len as u32;
+
+        while address < end {
+            let sector_address = address - address % SECTOR_BYTES as u32;
+            let start = (address - sector_address) as usize;
+            let in_sector = start..SECTOR_BYTES.min((end - sector_address) as usize);
+            if in_sector.len() == SECTOR_BYTES {
+                self.erase_sector(sector_address).await?;
+            } else {
+                match self
+                    .write_in_sector(sector_address, in_sector.clone(), &mut Fill(ERASED))
+                    .await
+                {
+                    Ok(()) => {}
+                    Err(TransferError::BlockDevice(e)) => return Err(e),
+                    Err(TransferError::UnexpectedEof | TransferError::Io(_)) => {
+                        unreachable!("the erased bytes don't run out")
+                    }
+                }
+            }
+            address = sector_address + in_sector.end as u32;
+        }
+
+        Ok(())
+    }
+
+    const DISCARDS: bool = true;
+
+    /// Erases the sectors wholly within the discarded blocks, if they aren't already. Those
+    /// partly discarded are kept
+    async fn discard(&mut self, lba: u64, count: u32) -> Result<(), BlockDeviceError> {
+        let len = count as usize * BLOCK_BYTES;
+        let address = self.address(lba, len)?;
+        let end = address + len as u32;
+        let mut sector_address = address.next_multiple_of(SECTOR_BYTES as u32);
+
+        while sector_address + SECTOR_BYTES as u32 <= end {
+            self.erase_sector(sector_address).await?;
+            sector_address += SECTOR_BYTES as u32;
+        }
+
+        Ok(())
+    }
+
+    const SAVES_MODE_PARAMETERS: bool = true;
+
+    /// None are saved while the sector is erased, and so doesn't start with the magic
+    async fn load_mode_parameters(
+        &mut self,
+        parameters: &mut [u8; MODE_PARAMETERS_BYTES],
+    ) -> Result<bool, BlockDeviceError> {
+        let record = &mut self.sector[..MODE_PARAMETERS_RECORD_BYTES];
+        self.flash
+            .read(self.mode_parameters, record)
+            .await
+            .map_err(|e| {
+                error!("flash: read of {} failed: {}", self.mode_parameters, e);
+                BlockDeviceError::ReadError
+            })?;
+
+        let (magic, saved) = record.split_at(MODE_PARAMETERS_MAGIC.len());
+        if magic != MODE_PARAMETERS_MAGIC {
+            return Ok(false);
+        }
+        parameters.copy_from_slice(saved);
+        Ok(true)
+    }
+
+    /// Erases the mode parameter sector and programs the magic and parameters at its start
+    async fn save_mode_parameters(
+        &mut self,
+        parameters: &[u8; MODE_PARAMETERS_BYTES],
+    ) -> Result<(), BlockDeviceError> {
+        self.erase_sector(self.mode_parameters).await?;
+
+        let record =
+            &mut self.sector[..MODE_PARAMETERS_RECORD_BYTES.next_multiple_of(F::WRITE_SIZE)];
+        record.fill(ERASED);
+        let (magic, saved) = record.split_at_mut(MODE_PARAMETERS_MAGIC.len());
+        magic.copy_from_slice(&MODE_PARAMETERS_MAGIC);
+        saved[..MODE_PARAMETERS_BYTES].copy_from_slice(parameters);
+        self.flash
+            .write(self.mode_parameters, record)
+            .await
+            .map_err(|e| {
+                error!("flash: write of {} failed: {}", self.mode_parameters, e);
+                BlockDeviceError::WriteError
+            })
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.len / BLOCK_BYTES as u32 - 1) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::SimulatedFlash;
+
+    const SECTOR: usize = 4096;
+
+    fn device(sectors: usize) -> FlashBlockDevice<SimulatedFlash> {
+        // the mode parameters in the first sector, the blocks after
+        FlashBlockDevice::new(
+            SimulatedFlash::new(sectors + 1),
+            SECTOR as u32,
+            (sectors * SECTOR) as u32,
+            0,
+        )
+    }
+
+    #[test]
+    fn block_count() {
+        assert_eq!(device(2).block_count(), 15);
+    }
+
+    #[test]
+    fn write_to_erased_flash_programs_without_erasing() {
+        let mut device = device(1);
+
+        block_on(device.write_block(1, &[0xAA; 512])).unwrap();
+
+        let flash = device.release();
+        assert_eq!(flash.erases, [0, 0]);
+        assert_eq!(&flash.bytes()[SECTOR + 512..SECTOR + 1024], [0xAA; 512]);
+        assert_eq!(&flash.bytes()[SECTOR..SECTOR + 512], [0xFF; 512]);
+    }
+
+    #[test]
+    fn overwrite_preserves_rest_of_sector() {
+        let mut device = device(2);
+        let mut block = [0; 512];
+
+        block_on(async {
+            for lba in 0..16 {
+                device.write_block(lba, &[lba as u8; 512]).await.unwrap();
+            }
+            device.write_block(9, &[0x55; 512]).await.unwrap();
+
+            for lba in 0..16 {
+                device.read_block(lba, &mut block).await.unwrap();
+                let expected = if lba == 9 { 0x55 } else { lba as u8 };
+                assert_eq!(block, [expected; 512], "lba {}", lba);
+            }
+        });
+
+        // only the second sector was erased, once
+        assert_eq!(device.release().erases, [0, 0, 1]);
+    }
+
+    #[test]
+    fn unchanged_write_is_skipped() {
+        let mut device = device(1);
+
+        block_on(async {
+            device.write_block(0, &[0x12; 512]).await.unwrap();
+            device.write_block(0, &[0x12; 512]).await.unwrap();
+        });
+
+        let flash = device.release();
+        assert_eq!(flash.writes, 1);
+        assert_eq!(flash.erases, [0, 0]);
+    }
+
+    #[test]
+    fn multiple_blocks_erase_each_sector_once() {
+        let mut device = device(3);
+        let written: std::vec::Vec<u8> = (0..16 * 512).map(|i| (i % 251) as u8).collect();
+        let mut read = std::vec![0; written.len()];
+
+        block_on(async {
+            device.write_blocks(0, &[0; 24 * 512]).await.unwrap();
+            // the second half of the first sector to the first half of the third
+            device.write_blocks(4, &written).await.unwrap();
+            device.read_blocks(4, &mut read).await.unwrap();
+        });
+
+        assert_eq!(read, written);
+        assert_eq!(device.release().erases, [0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn format_erases_each_sector_once() {
+        let mut device = device(3);
+        let mut block = [0; 512];
+
+        block_on(async {
+            device.write_blocks(0, &[0xAA; 24 * 512]).await.unwrap();
+            // the first two sectors, then part of the third
+            device.format(0, 8).await.unwrap();
+            device.format(8, 8).await.unwrap();
+            device.format(16, 2).await.unwrap();
+            // already erased
+            device.format(0, 8).await.unwrap();
+
+            for lba in 0..24 {
+                device.read_block(lba, &mut block).await.unwrap();
+                let expected = if lba < 18 { 0xFF } else { 0xAA };
+                assert_eq!(block, [expected; 512], "lba {}", lba);
+            }
+        });
+
+        assert_eq!(device.release().erases, [0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn discard_erases_whole_sectors() {
+        let mut device = device(3);
+        let mut block = [0; 512];
+
+        block_on(async {
+            device.write_blocks(0, &[0xAA; 24 * 512]).await.unwrap();
+            // the second half of the first sector to the first half of the third
+            device.discard(4, 16).await.unwrap();
+            // already erased
+            device.discard(8, 8).await.unwrap();
+
+            for lba in 0..24 {
+                device.read_block(lba, &mut block).await.unwrap();
+                let expected = if (8..16).contains(&lba) { 0xFF } else { 0xAA };
+                assert_eq!(block, [expected; 512], "lba {}", lba);
+            }
+        });
+
+        assert_eq!(device.release().erases, [0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn mode_parameters_saved_in_their_own_sector() {
+        let mut device = device(1);
+        let mut parameters = [0; MODE_PARAMETERS_BYTES];
+
+        block_on(async {
+            // none saved while the sector's erased
+            assert_eq!(
+                device.load_mode_parameters(&mut parameters).await,
+                Ok(false)
+            );
+
+            device
+                .save_mode_parameters(&[0x12; MODE_PARAMETERS_BYTES])
+                .await
+                .unwrap();
+            device
+                .save_mode_parameters(&[0x34; MODE_PARAMETERS_BYTES])
+                .await
+                .unwrap();
+            assert_eq!(device.load_mode_parameters(&mut parameters).await, Ok(true));
+        });
+
+        assert_eq!(parameters, [0x34; MODE_PARAMETERS_BYTES]);
+        let flash = device.release();
+        // erased only to save over the first
+        assert_eq!(flash.erases, [1, 0]);
+        assert_eq!(flash.bytes()[SECTOR..], [0xFF; SECTOR]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut device = device(1);
+        let mut block = [0; 512];
+
+        assert_eq!(
+            block_on(device.read_block(8, &mut block)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+        assert_eq!(
+            block_on(device.write_block(8, &block)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+        assert_eq!(
+            block_on(device.write_blocks(7, &[0; 1024])),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    }
+}
@@ -0,0 +1,10 @@
+//! [`BlockDevice`](crate::BlockDevice) implementations for common kinds of storage
+
+mod flash;
+pub use flash::*;
+
+mod sd;
+pub use sd::*;
+
+mod write_back_cache;
+pub use write_back_cache::*;
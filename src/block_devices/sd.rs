@@ -0,0 +1,610 @@
+use core::future::Future;
+
+use defmt::{error, info, Format};
+
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+const BLOCK_BYTES: usize = 512;
+
+// commands, SD Physical Layer Simplified Specification section 7.3.1
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const STOP_TRANSMISSION: u8 = 12;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const READ_MULTIPLE_BLOCK: u8 = 18;
+const WRITE_BLOCK: u8 = 24;
+const WRITE_MULTIPLE_BLOCK: u8 = 25;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+const SD_SEND_OP_COND: u8 = 41; // ACMD41
+
+/// SEND_IF_COND argument: 2.7-3.6V, check pattern 0xAA
+const IF_COND: u32 = 0x1AA;
+/// SD_SEND_OP_COND argument: the host supports high capacity cards
+const HOST_CAPACITY_SUPPORT: u32 = 1 << 30;
+/// OCR byte 0: card capacity status, the card is block (rather than byte) addressed
+const OCR_CCS: u8 = 1 << 6;
+
+// R1 response bits
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+// data tokens, section 7.3.3
+const START_BLOCK: u8 = 0xFE;
+const START_MULTIPLE_WRITE: u8 = 0xFC;
+const STOP_TRAN: u8 = 0xFD;
+const DATA_RESPONSE_MASK: u8 = 0x1F;
+const DATA_ACCEPTED: u8 = 0x05;
+const ERROR_TOKEN_OUT_OF_RANGE: u8 = 0x08;
+
+/// Bytes to wait for a response to a command
+const RESPONSE_POLLS: usize = 16;
+/// Bytes to wait for a data token, or for the card to finish programming
+const BUSY_POLLS: usize = 100_000;
+/// SD_SEND_OP_COND attempts before giving up on the card initialising (around a second at
+/// 400kHz)
+const INIT_POLLS: usize = 2_000;
+
+/// The SPI bus failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct SpiError;
+
+/// An SPI bus with an SD card on it, and the card's chip select
+pub trait SdSpi {
+    /// Drive chip select active (low) or inactive (high)
+    fn select(&mut self, selected: bool);
+
+    /// Clock `buf` out to the card, replacing its contents with the bytes clocked in
+    fn transfer(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<(), SpiError>>;
+}
+
+/// Why talking to an SD card failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SdError {
+    Spi,
+    /// The card didn't respond
+    Timeout,
+    /// The blocks aren't all on the card
+    OutOfRange,
+    /// The card isn't one we know how to talk to (e.g. MMC, or an unsupported voltage)
+    UnsupportedCard,
+    /// The card responded to `command` with an error
+    Command {
+        command: u8,
+        r1: u8,
+    },
+    /// The card sent a data error token rather than a block
+    Read {
+        token: u8,
+    },
+    /// The card didn't accept a block written to it
+    Write {
+        response: u8,
+    },
+}
+
+impl From<SpiError> for SdError {
+    fn from(_: SpiError) -> Self {
+        SdError::Spi
+    }
+}
+
+impl From<SdError> for BlockDeviceError {
+    fn from(e: SdError) -> Self {
+        error!("sd: {}", e);
+        match e {
+            SdError::Command { r1, .. } if r1 & (R1_ADDRESS_ERROR | R1_PARAMETER_ERROR) != 0 => {
+                BlockDeviceError::InvalidAddress
+            }
+            SdError::OutOfRange => BlockDeviceError::InvalidAddress,
+            SdError::Read { token } if token & ERROR_TOKEN_OUT_OF_RANGE != 0 => {
+                BlockDeviceError::InvalidAddress
+            }
+            SdError::Read { .. } => BlockDeviceError::ReadError,
+            SdError::Write { .. } => BlockDeviceError::WriteError,
+            SdError::Spi
+            | SdError::Timeout
+            | SdError::UnsupportedCard
+            | SdError::Command { .. } => BlockDeviceError::HardwareError,
+        }
+    }
+}
+
+/// A [`BlockDevice`] on an SD (SDSC, SDHC or SDXC) card in SPI mode
+///
+/// The SPI clock must be at most 400kHz until [`SdCard::init`] returns, after which it can be
+/// raised to 25MHz through [`SdCard::spi_mut`]. CRCs aren't used, as SPI mode doesn't require
+/// them.
+pub struct SdCard<S> {
+    spi: S,
+    /// SDHC and SDXC cards are addressed by block, SDSC cards by byte
+    high_capacity: bool,
+    blocks: u64,
+}
+
+impl<S: SdSpi> SdCard<S> {
+    /// Bring the card out of reset, into SPI mode, and find its capacity
+    pub async fn init(spi: S) -> Result<Self, SdError> {
+        let mut card = Self {
+            spi,
+            high_capacity: false,
+            blocks: 0,
+        };
+
+        // at least 74 clocks with chip select inactive, so the card is ready for commands
+        card.spi.select(false);
+        card.spi.transfer(&mut [0xFF; 10]).await?;
+
+        card.spi.select(true);
+        let result = card.init_selected().await;
+        card.deselect().await?;
+        result?;
+
+        info!(
+            "sd: {} blocks, {}",
+            card.blocks,
+            if card.high_capacity {
+                "SDHC/SDXC"
+            } else {
+                "SDSC"
+            }
+        );
+        Ok(card)
+    }
+
+    async fn init_selected(&mut self) -> Result<(), SdError> {
+        let r1 = self.command(GO_IDLE_STATE, 0).await?;
+        if r1 != R1_IDLE {
+            return Err(SdError::Command {
+                command: GO_IDLE_STATE,
+                r1,
+            });
+        }
+
+        // version 1 cards don't know SEND_IF_COND
+        let version_2 = self.command(SEND_IF_COND, IF_COND).await? & R1_ILLEGAL_COMMAND == 0;
+        if version_2 {
+            let mut r7 = [0xFF; 4];
+            self.spi.transfer(&mut r7).await?;
+            if u32::from_be_bytes(r7) & 0xFFF != IF_COND {
+                return Err(SdError::UnsupportedCard);
+            }
+        }
+
+        let op_cond = if version_2 { HOST_CAPACITY_SUPPORT } else { 0 };
+        let mut ready = false;
+        for _ in 0..INIT_POLLS {
+            match self.app_command(SD_SEND_OP_COND, op_cond).await? {
+                0 => {
+                    ready = true;
+                    break;
+                }
+                R1_IDLE => {}
+                // MMC cards don't know SD_SEND_OP_COND
+                r1 if r1 & R1_ILLEGAL_COMMAND != 0 => return Err(SdError::UnsupportedCard),
+                r1 => {
+                    return Err(SdError::Command {
+                        command: SD_SEND_OP_COND,
+                        r1,
+                    })
+                }
+            }
+        }
+        if !ready {
+            return Err(SdError::Timeout);
+        }
+
+        if version_2 {
+            self.command_ok(READ_OCR, 0).await?;
+            let mut ocr = [0xFF; 4];
+            self.spi.transfer(&mut ocr).await?;
+            self.high_capacity = ocr[0] & OCR_CCS != 0;
+        }
+
+        if !self.high_capacity {
+            self.command_ok(SET_BLOCKLEN, BLOCK_BYTES as u32).await?;
+        }
+
+        self.command_ok(SEND_CSD, 0).await?;
+        let mut csd = [0; 16];
+        self.read_data(&mut csd).await?;
+        self.blocks = csd_blocks(&csd)?;
+
+        Ok(())
+    }
+
+    /// The SPI bus, e.g. to raise the clock once the card is initialised
+    pub fn spi_mut(&mut self) -> &mut S {
+        &mut self.spi
+    }
+
+    /// Give back the SPI bus
+    pub fn release(self) -> S {
+        self.spi
+    }
+
+    /// Read consecutive blocks from `lba` into `blocks`, a multiple of the block size
+    async fn read(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), SdError> {
+        let address = self.address(lba, blocks.len())?;
+
+        self.spi.select(true);
+        let result = if blocks.len() == BLOCK_BYTES {
+            self.read_single(address, blocks).await
+        } else {
+            self.read_multiple(address, blocks).await
+        };
+        self.deselect().await?;
+        result
+    }
+
+    async fn read_single(&mut self, address: u32, block: &mut [u8]) -> Result<(), SdError> {
+        self.command_ok(READ_SINGLE_BLOCK, address).await?;
+        self.read_data(block).await
+    }
+
+    async fn read_multiple(&mut self, address: u32, blocks: &mut [u8]) -> Result<(), SdError> {
+        self.command_ok(READ_MULTIPLE_BLOCK, address).await?;
+        let mut result = Ok(());
+        for block in blocks.chunks_mut(BLOCK_BYTES) {
+            result = self.read_data(block).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        // stopped even if a block failed, or the card carries on sending blocks
+        let stopped = self.stop_transmission().await;
+        result.and(stopped)
+    }
+
+    async fn stop_transmission(&mut self) -> Result<(), SdError> {
+        self.command_ok(STOP_TRANSMISSION, 0).await?;
+        self.wait_not_busy().await
+    }
+
+    /// Write `blocks`, a multiple of the block size, to consecutive blocks from `lba`
+    async fn write(&mut self, lba: u64, blocks: &[u8]) -> Result<(), SdError> {
+        let address = self.address(lba, blocks.len())?;
+
+        self.spi.select(true);
+        let result = if blocks.len() == BLOCK_BYTES {
+            self.write_single(address, blocks).await
+        } else {
+            self.write_multiple(address, blocks).await
+        };
+        self.deselect().await?;
+        result
+    }
+
+    async fn write_single(&mut self, address: u32, block: &[u8]) -> Result<(), SdError> {
+        self.command_ok(WRITE_BLOCK, address).await?;
+        self.write_data(START_BLOCK, block).await
+    }
+
+    async fn write_multiple(&mut self, address: u32, blocks: &[u8]) -> Result<(), SdError> {
+        self.command_ok(WRITE_MULTIPLE_BLOCK, address).await?;
+        let mut result = Ok(());
+        for block in blocks.chunks(BLOCK_BYTES) {
+            result = self.write_data(START_MULTIPLE_WRITE, block).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        // stopped even if a block failed, or the card waits for the next block
+        let stopped = self.stop_tran().await;
+        result.and(stopped)
+    }
+
+    async fn stop_tran(&mut self) -> Result<(), SdError> {
+        // the byte after the stop token is undefined, then the card is busy until it's done
+        self.spi.transfer(&mut [STOP_TRAN, 0xFF]).await?;
+        self.wait_not_busy().await
+    }
+
+    /// The command argument addressing `lba`, checking `len` bytes from it are on the card
+    fn address(&self, lba: u64, len: usize) -> Result<u32, SdError> {
+        assert!(len > 0 && len.is_multiple_of(BLOCK_BYTES));
+
+        let end = lba.saturating_add((len / BLOCK_BYTES) as u64);
+        if end > self.blocks {
+            return Err(SdError::OutOfRange);
+        }
+
+        let address = if self.high_capacity {
+            lba
+        } else {
+            lba * BLOCK_BYTES as u64
+        };
+        address.try_into().map_err(|_| SdError::OutOfRange)
+    }
+
+    /// Release chip select, with the extra clocks the card needs to release the bus
+    async fn deselect(&mut self) -> Result<(), SdError> {
+        self.spi.select(false);
+        self.spi.transfer(&mut [0xFF]).await?;
+        Ok(())
+    }
+
+    async fn transfer_byte(&mut self, byte: u8) -> Result<u8, SdError> {
+        let mut buf = [byte];
+        self.spi.transfer(&mut buf).await?;
+        Ok(buf[0])
+    }
+
+    /// Send a command, returning its R1 response
+    async fn command(&mut self, command: u8, arg: u32) -> Result<u8, SdError> {
+        // CRCs are only checked for these, before we're in SPI mode
+        let crc = match command {
+            GO_IDLE_STATE => 0x95,
+            SEND_IF_COND => 0x87,
+            _ => 0x01,
+        };
+        let arg = arg.to_be_bytes();
+        let mut frame = [0x40 | command, arg[0], arg[1], arg[2], arg[3], crc];
+        self.spi.transfer(&mut frame).await?;
+
+        if command == STOP_TRANSMISSION {
+            // a stuff byte comes before the response
+            self.transfer_byte(0xFF).await?;
+        }
+
+        for _ in 0..RESPONSE_POLLS {
+            let r1 = self.transfer_byte(0xFF).await?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(SdError::Timeout)
+    }
+
+    /// Send a command, failing unless the card is ready and accepts it
+    async fn command_ok(&mut self, command: u8, arg: u32) -> Result<(), SdError> {
+        match self.command(command, arg).await? {
+            0 => Ok(()),
+            r1 => Err(SdError::Command { command, r1 }),
+        }
+    }
+
+    async fn app_command(&mut self, command: u8, arg: u32) -> Result<u8, SdError> {
+        self.command(APP_CMD, 0).await?;
+        self.command(command, arg).await
+    }
+
+    /// Read a data block, which follows a start token
+    async fn read_data(&mut self, buf: &mut [u8]) -> Result<(), SdError> {
+        let mut token = 0xFF;
+        for _ in 0..BUSY_POLLS {
+            token = self.transfer_byte(0xFF).await?;
+            if token != 0xFF {
+                break;
+            }
+        }
+        match token {
+            START_BLOCK => {}
+            0xFF => return Err(SdError::Timeout),
+            token => return Err(SdError::Read { token }),
+        }
+
+        buf.fill(0xFF);
+        self.spi.transfer(buf).await?;
+        self.spi.transfer(&mut [0xFF; 2]).await?; // CRC
+        Ok(())
+    }
+
+    /// Write a data block, and wait for the card to program it
+    async fn write_data(&mut self, token: u8, block: &[u8]) -> Result<(), SdError> {
+        let mut buf = [0; BLOCK_BYTES];
+        buf.copy_from_slice(block);
+
+        self.spi.transfer(&mut [token]).await?;
+        self.spi.transfer(&mut buf).await?;
+        self.spi.transfer(&mut [0xFF; 2]).await?; // CRC
+
+        let response = self.transfer_byte(0xFF).await? & DATA_RESPONSE_MASK;
+        if response != DATA_ACCEPTED {
+            return Err(SdError::Write { response });
+        }
+        self.wait_not_busy().await
+    }
+
+    /// The card holds its output low while it's busy
+    async fn wait_not_busy(&mut self) -> Result<(), SdError> {
+        for _ in 0..BUSY_POLLS {
+            if self.transfer_byte(0xFF).await? == 0xFF {
+                return Ok(());
+            }
+        }
+        Err(SdError::Timeout)
+    }
+}
+
+impl<S: SdSpi> BlockDevice for SdCard<S> {
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    async fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        Ok(self.read(lba, block).await?)
+    }
+
+    async fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        Ok(self.write(lba, block).await?)
+    }
+
+    async fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        Ok(self.read(lba, blocks).await?)
+    }
+
+    async fn write_blocks(&mut self, lba: u64, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        Ok(self.write(lba, blocks).await?)
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks - 1
+    }
+}
+
+/// Bits `msb..=lsb` of the 128 bit `csd`
+fn csd_bits(csd: &[u8; 16], msb: usize, lsb: usize) -> u32 {
+    (lsb..=msb).rev().fold(0, |value, bit| {
+        value << 1 | ((csd[15 - bit / 8] >> (bit % 8)) & 1) as u32
+    })
+}
+
+/// The card's capacity in blocks, from its card-specific data (section 5.3)
+fn csd_blocks(csd: &[u8; 16]) -> Result<u64, SdError> {
+    match csd_bits(csd, 127, 126) {
+        // SDSC
+        0 => {
+            let c_size = csd_bits(csd, 73, 62) as u64;
+            let c_size_mult = csd_bits(csd, 49, 47);
+            let read_bl_len = csd_bits(csd, 83, 80);
+            let bytes = (c_size + 1) << (c_size_mult + 2) << read_bl_len;
+            Ok(bytes / BLOCK_BYTES as u64)
+        }
+        // SDHC and SDXC
+        1 => Ok((csd_bits(csd, 69, 48) as u64 + 1) * 1024),
+        _ => Err(SdError::UnsupportedCard),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::SdCardEmulator;
+
+    #[test]
+    fn sdhc_capacity() {
+        let mut emulator = SdCardEmulator::sdhc(2048);
+        emulator.init_polls = 3;
+
+        let card = block_on(SdCard::init(emulator)).unwrap();
+
+        assert_eq!(card.block_count(), 2047);
+        assert!(card.high_capacity);
+        // SEND_IF_COND, and SD_SEND_OP_COND until the card is ready
+        let emulator = card.release();
+        assert_eq!(&emulator.commands[..2], [0, 8]);
+        assert_eq!(emulator.commands.iter().filter(|&&c| c == 41).count(), 4);
+    }
+
+    #[test]
+    fn sdsc_v1_capacity() {
+        let card = block_on(SdCard::init(SdCardEmulator::sdsc_v1(64))).unwrap();
+
+        assert_eq!(card.block_count(), 63);
+        assert!(!card.high_capacity);
+        assert!(card.release().commands.contains(&16)); // SET_BLOCKLEN
+    }
+
+    #[test]
+    fn single_blocks() {
+        for emulator in [SdCardEmulator::sdhc(1024), SdCardEmulator::sdsc_v1(64)] {
+            let mut card = block_on(SdCard::init(emulator)).unwrap();
+            let mut block = [0; 512];
+
+            block_on(async {
+                card.write_block(5, &[0xA5; 512]).await.unwrap();
+                card.read_block(5, &mut block).await.unwrap();
+            });
+
+            assert_eq!(block, [0xA5; 512]);
+            let emulator = card.release();
+            assert_eq!(emulator.block(5), [0xA5; 512]);
+            assert_eq!(emulator.block(4), [0; 512]);
+            assert!(emulator.commands.ends_with(&[24, 17]));
+        }
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let mut card = block_on(SdCard::init(SdCardEmulator::sdhc(1024))).unwrap();
+        let written: std::vec::Vec<u8> = (0..3 * 512).map(|i| (i % 253) as u8).collect();
+        let mut read = std::vec![0; 3 * 512];
+
+        block_on(async {
+            card.write_blocks(10, &written).await.unwrap();
+            card.read_blocks(10, &mut read).await.unwrap();
+            // the card is still usable after stopping the multiple block read
+            card.read_blocks(9, &mut read[..512]).await.unwrap();
+        });
+
+        let emulator = card.release();
+        assert_eq!(read[..512], [0; 512]);
+        assert_eq!(emulator.block(10), &written[..512]);
+        assert_eq!(emulator.block(12), &written[1024..]);
+        assert_eq!(emulator.block(13), [0; 512]);
+        assert!(emulator.commands.ends_with(&[25, 18, 12, 17]));
+    }
+
+    #[test]
+    fn rejected_write() {
+        let mut card = block_on(SdCard::init(SdCardEmulator::sdhc(1024))).unwrap();
+        card.spi_mut().reject_writes = true;
+
+        let result = block_on(card.write_block(0, &[0xA5; 512]));
+
+        assert_eq!(result, Err(BlockDeviceError::WriteError));
+    }
+
+    #[test]
+    fn failed_multiple_block_read_is_stopped() {
+        let mut card = block_on(SdCard::init(SdCardEmulator::sdhc(1024))).unwrap();
+        card.spi_mut().block_mut(11).fill(0xA5);
+        card.spi_mut().unreadable = Some(11);
+        let mut blocks = [0; 3 * 512];
+
+        let result = block_on(card.read_blocks(10, &mut blocks));
+        assert_eq!(result, Err(BlockDeviceError::ReadError));
+
+        // the card is ready for the next command
+        card.spi_mut().unreadable = None;
+        block_on(card.read_blocks(11, &mut blocks[..1024])).unwrap();
+        assert_eq!(blocks[..512], [0xA5; 512]);
+        assert!(card.release().commands.ends_with(&[18, 12, 18, 12]));
+    }
+
+    #[test]
+    fn failed_multiple_block_write_is_stopped() {
+        let mut card = block_on(SdCard::init(SdCardEmulator::sdhc(1024))).unwrap();
+        card.spi_mut().reject_writes = true;
+
+        let result = block_on(card.write_blocks(10, &[0xA5; 3 * 512]));
+        assert_eq!(result, Err(BlockDeviceError::WriteError));
+
+        // the card is ready for the next command
+        card.spi_mut().reject_writes = false;
+        block_on(card.write_blocks(10, &[0x5A; 2 * 512])).unwrap();
+        assert_eq!(card.release().block(11), [0x5A; 512]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut card = block_on(SdCard::init(SdCardEmulator::sdsc_v1(64))).unwrap();
+        let mut blocks = [0; 1024];
+
+        let result = block_on(card.read_blocks(63, &mut blocks));
+
+        assert_eq!(result, Err(BlockDeviceError::InvalidAddress));
+    }
+
+    #[test]
+    fn no_card() {
+        struct NoCard;
+        impl SdSpi for NoCard {
+            fn select(&mut self, _: bool) {}
+            async fn transfer(&mut self, buf: &mut [u8]) -> Result<(), SpiError> {
+                buf.fill(0xFF);
+                Ok(())
+            }
+        }
+
+        assert!(matches!(
+            block_on(SdCard::init(NoCard)),
+            Err(SdError::Timeout)
+        ));
+    }
+}
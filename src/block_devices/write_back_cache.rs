@@ -0,0 +1,359 @@
+use core::convert::Infallible;
+
+use embedded_io_async::{ErrorType, Read};
+
+use crate::scsi::{BlockDevice, BlockDeviceError, TransferError, MODE_PARAMETERS_BYTES};
+
+/// A write-back cache of `BLOCKS` blocks in front of another [`BlockDevice`]
+///
+/// Writes are held in RAM until the host synchronizes the cache, or the cache is full, when the
+/// least recently used block makes way. Whenever blocks are written back it's all of them, in
+/// order, as runs of consecutive blocks, so a backend that has to erase or program more than a
+/// block at a time (flash, SD cards) does so once per run rather than once per block. Reads are
+/// only served from the cache for the blocks it holds; other blocks aren't cached as they're read.
+///
+/// The host is told the write cache is enabled, so it synchronizes the cache before expecting
+/// writes to survive a power cut. It may disable the cache with MODE SELECT, making every write
+/// durable before it completes.
+pub struct WriteBackCache<BD, const BLOCKS: usize = 8, const BLOCK_BYTES: usize = 512> {
+    block_device: BD,
+    entries: [Option<Entry>; BLOCKS],
+    blocks: [[u8; BLOCK_BYTES]; BLOCKS],
+    /// Counts accesses, to find the least recently used block
+    clock: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    lba: u64,
+    /// Not yet written back
+    dirty: bool,
+    last_used: u32,
+}
+
+impl<BD: BlockDevice, const BLOCKS: usize, const BLOCK_BYTES: usize>
+    WriteBackCache<BD, BLOCKS, BLOCK_BYTES>
+{
+    /// Cache writes to `block_device`, whose blocks must be `BLOCK_BYTES` long
+    pub fn new(block_device: BD) -> Self {
+        assert_eq!(
+            BLOCK_BYTES,
+            BD::BLOCK_BYTES,
+            "block size must match the block device"
+        );
+        assert!(BLOCKS > 0);
+
+        Self {
+            block_device,
+            entries: [None; BLOCKS],
+            blocks: [[0; BLOCK_BYTES]; BLOCKS],
+            clock: 0,
+        }
+    }
+
+    /// The block device behind the cache, which doesn't have any writes still cached
+    pub fn get_ref(&self) -> &BD {
+        &self.block_device
+    }
+
+    /// Give back the block device behind the cache. Writes that haven't been
+    /// [`flush`](BlockDevice::flush)ed are lost
+    pub fn release(self) -> BD {
+        self.block_device
+    }
+
+    fn touch(&mut self, index: usize, lba: u64, dirty: bool) {
+        self.clock = self.clock.wrapping_add(1);
+        self.entries[index] = Some(Entry {
+            lba,
+            dirty,
+            last_used: self.clock,
+        });
+    }
+
+    /// Where to cache `lba`: where it's cached already, an empty block, or the least recently used
+    async fn slot(&mut self, lba: u64) -> Result<usize, BlockDeviceError> {
+        if let Some(index) = position(&self.entries, lba) {
+            return Ok(index);
+        }
+        if let Some(index) = self.entries.iter().position(Option::is_none) {
+            return Ok(index);
+        }
+
+        let clock = self.clock;
+        let (index, entry) = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.map(|entry| (index, entry)))
+            .max_by_key(|(_, entry)| clock.wrapping_sub(entry.last_used))
+            .unwrap();
+        if entry.dirty {
+            self.write_back().await?;
+        }
+        Ok(index)
+    }
+
+    /// Write every dirty block to the block device, lowest first
+    async fn write_back(&mut self) -> Result<(), BlockDeviceError> {
+        while let Some(lba) = self
+            .entries
+            .iter()
+            .flatten()
+            .filter(|entry| entry.dirty)
+            .map(|entry| entry.lba)
+            .min()
+        {
+            let mut count = 1;
+            while self.dirty(lba + count) {
+                count += 1;
+            }
+
+            let mut run = Run {
+                entries: &self.entries,
+                blocks: &self.blocks,
+                lba,
+                offset: 0,
+            };
+            match self
+                .block_device
+                .write_from(lba, count as u32, &mut run)
+                .await
+            {
+                Ok(()) => {}
+                Err(TransferError::BlockDevice(e)) => return Err(e),
+                Err(TransferError::UnexpectedEof | TransferError::Io(_)) => {
+                    unreachable!("the run holds all the blocks")
+                }
+            }
+
+            for entry in self.entries.iter_mut().flatten() {
+                if (lba..lba + count).contains(&entry.lba) {
+                    entry.dirty = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop `count` blocks from `lba` from the cache, without writing them back
+    fn forget(&mut self, lba: u64, count: u32) {
+        for entry in self.entries.iter_mut() {
+            if entry.is_some_and(|entry| (lba..lba + count as u64).contains(&entry.lba)) {
+                *entry = None;
+            }
+        }
+    }
+
+    fn dirty(&self, lba: u64) -> bool {
+        position(&self.entries, lba).is_some_and(|index| self.entries[index].unwrap().dirty)
+    }
+}
+
+impl<BD: BlockDevice, const BLOCKS: usize, const BLOCK_BYTES: usize> BlockDevice
+    for WriteBackCache<BD, BLOCKS, BLOCK_BYTES>
+{
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    const CACHES_WRITES: bool = true;
+
+    const SAVES_MODE_PARAMETERS: bool = BD::SAVES_MODE_PARAMETERS;
+
+    async fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        match position(&self.entries, lba) {
+            Some(index) => {
+                block.copy_from_slice(&self.blocks[index]);
+                let dirty = self.entries[index].unwrap().dirty;
+                self.touch(index, lba, dirty);
+                Ok(())
+            }
+            None => self.block_device.read_block(lba, block).await,
+        }
+    }
+
+    async fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if lba > self.block_device.block_count() {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        let index = self.slot(lba).await?;
+        self.blocks[index].copy_from_slice(block);
+        self.touch(index, lba, true);
+        Ok(())
+    }
+
+    /// Reads all the blocks from the block device, then replaces those that are cached
+    async fn read_blocks(&mut self, lba: u64, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.block_device.read_blocks(lba, blocks).await?;
+        for (i, block) in blocks.chunks_mut(BLOCK_BYTES).enumerate() {
+            let lba = lba + i as u64;
+            if let Some(index) = position(&self.entries, lba) {
+                block.copy_from_slice(&self.blocks[index]);
+                let dirty = self.entries[index].unwrap().dirty;
+                self.touch(index, lba, dirty);
+            }
+        }
+        Ok(())
+    }
+
+    const FORMAT_BLOCKS: u32 = BD::FORMAT_BLOCKS;
+
+    /// Formats the block device, forgetting the blocks cached from the formatted range
+    async fn format(&mut self, lba: u64, count: u32) -> Result<(), BlockDeviceError> {
+        self.block_device.format(lba, count).await?;
+        self.forget(lba, count);
+        Ok(())
+    }
+
+    const DISCARDS: bool = BD::DISCARDS;
+
+    /// Forgets the blocks cached from the discarded range, then discards them from the block
+    /// device
+    async fn discard(&mut self, lba: u64, count: u32) -> Result<(), BlockDeviceError> {
+        self.forget(lba, count);
+        self.block_device.discard(lba, count).await
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.write_back().await?;
+        self.block_device.flush().await
+    }
+
+    async fn load_mode_parameters(
+        &mut self,
+        parameters: &mut [u8; MODE_PARAMETERS_BYTES],
+    ) -> Result<bool, BlockDeviceError> {
+        self.block_device.load_mode_parameters(parameters).await
+    }
+
+    async fn save_mode_parameters(
+        &mut self,
+        parameters: &[u8; MODE_PARAMETERS_BYTES],
+    ) -> Result<(), BlockDeviceError> {
+        self.block_device.save_mode_parameters(parameters).await
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_device.block_count()
+    }
+}
+
+/// Where `lba` is cached, if it is
+fn position(entries: &[Option<Entry>], lba: u64) -> Option<usize> {
+    entries
+        .iter()
+        .position(|entry| entry.is_some_and(|entry| entry.lba == lba))
+}
+
+/// Reads a run of consecutive cached blocks, from `lba`, so they can be written with
+/// [`BlockDevice::write_from`]
+struct Run<'a, const BLOCKS: usize, const BLOCK_BYTES: usize> {
+    entries: &'a [Option<Entry>; BLOCKS],
+    blocks: &'a [[u8; BLOCK_BYTES]; BLOCKS],
+    lba: u64,
+    /// How far through block `lba`
+    offset: usize,
+}
+
+impl<const BLOCKS: usize, const BLOCK_BYTES: usize> ErrorType for Run<'_, BLOCKS, BLOCK_BYTES> {
+    type Error = Infallible;
+}
+
+impl<const BLOCKS: usize, const BLOCK_BYTES: usize> Read for Run<'_, BLOCKS, BLOCK_BYTES> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let Some(index) = position(self.entries, self.lba) else {
+            return Ok(0);
+        };
+        let block = &self.blocks[index][self.offset..];
+        let len = block.len().min(buf.len());
+        buf[..len].copy_from_slice(&block[..len]);
+
+        self.offset += len;
+        if self.offset == BLOCK_BYTES {
+            self.lba += 1;
+            self.offset = 0;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::mock::RamBlockDevice;
+
+    fn cache<const BLOCKS: usize>() -> WriteBackCache<RamBlockDevice, BLOCKS> {
+        WriteBackCache::new(RamBlockDevice::new(16))
+    }
+
+    #[test]
+    fn writes_held_until_flushed() {
+        let mut cache = cache::<4>();
+        let mut block = [0; 512];
+
+        block_on(cache.write_block(3, &[0xAA; 512])).unwrap();
+        block_on(cache.read_block(3, &mut block)).unwrap();
+        assert_eq!(block, [0xAA; 512]);
+        assert_eq!(cache.get_ref().block(3), [0; 512]);
+
+        block_on(cache.flush()).unwrap();
+        assert_eq!(cache.get_ref().block(3), [0xAA; 512]);
+    }
+
+    #[test]
+    fn read_blocks_sees_cached_blocks() {
+        let mut cache = cache::<4>();
+        let mut blocks = [0; 3 * 512];
+
+        block_on(cache.write_block(1, &[0xAA; 512])).unwrap();
+        block_on(cache.read_blocks(0, &mut blocks)).unwrap();
+        assert_eq!(blocks[..512], [0; 512]);
+        assert_eq!(blocks[512..1024], [0xAA; 512]);
+        assert_eq!(blocks[1024..], [0; 512]);
+    }
+
+    #[test]
+    fn least_recently_used_block_makes_way() {
+        let mut cache = cache::<2>();
+        let mut block = [0; 512];
+
+        block_on(cache.write_block(0, &[0xAA; 512])).unwrap();
+        block_on(cache.write_block(1, &[0xBB; 512])).unwrap();
+        block_on(cache.read_block(0, &mut block)).unwrap();
+        // block 1 is evicted, having written back both blocks
+        block_on(cache.write_block(2, &[0xCC; 512])).unwrap();
+
+        assert_eq!(cache.get_ref().block(0), [0xAA; 512]);
+        assert_eq!(cache.get_ref().block(1), [0xBB; 512]);
+        assert_eq!(cache.get_ref().block(2), [0; 512]);
+        assert_eq!(position(&cache.entries, 0), Some(0));
+        assert_eq!(position(&cache.entries, 1), None);
+        assert_eq!(position(&cache.entries, 2), Some(1));
+    }
+
+    #[test]
+    fn format_forgets_cached_blocks() {
+        let mut cache = cache::<4>();
+        let mut block = [0; 512];
+
+        block_on(cache.write_block(3, &[0xAA; 512])).unwrap();
+        block_on(cache.format(0, 16)).unwrap();
+        assert_eq!(position(&cache.entries, 3), None);
+
+        block_on(cache.flush()).unwrap();
+        block_on(cache.read_block(3, &mut block)).unwrap();
+        assert_eq!(block, [0; 512]);
+        assert_eq!(cache.get_ref().block(3), [0; 512]);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let mut cache = cache::<2>();
+
+        assert_eq!(
+            block_on(cache.write_block(16, &[0xAA; 512])),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    }
+}
@@ -6,7 +6,7 @@ const MIN_CB_LEN: usize = 1;
 const MAX_CB_LEN: usize = 16;
 
 #[repr(u8)]
-#[derive(Default, Debug, Copy, Clone, Format)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Format)]
 pub enum DataDirection {
     Out,
     In,
@@ -32,6 +32,10 @@ pub enum Error {
 
 impl CommandBlockWrapper {
     pub fn from_le_bytes(value: &[u8]) -> Result<Self, Error> {
+        if value.len() != CBW_LEN {
+            return Err(Error::InvalidLength);
+        }
+
         if !value.starts_with(&CBW_SIGNATURE_LE) {
             return Err(Error::InvalidSignature);
         }
@@ -61,3 +65,53 @@ impl CommandBlockWrapper {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::cbw;
+
+    #[test]
+    fn parse() {
+        let bytes = cbw(0xdeadbeef, 512, true, 3, &[0x28, 0, 0, 0, 0, 1, 0, 0, 1, 0]);
+        let cbw = CommandBlockWrapper::from_le_bytes(&bytes).unwrap();
+
+        assert_eq!(cbw.tag, 0xdeadbeef);
+        assert_eq!(cbw.data_transfer_len, 512);
+        assert!(matches!(cbw.direction, DataDirection::In));
+        assert_eq!(cbw.lun, 3);
+        assert_eq!(
+            &cbw.block[..cbw.block_len],
+            &[0x28, 0, 0, 0, 0, 1, 0, 0, 1, 0]
+        );
+    }
+
+    #[test]
+    fn no_data_is_not_expected() {
+        let bytes = cbw(1, 0, true, 0, &[0x00, 0, 0, 0, 0, 0]);
+        let cbw = CommandBlockWrapper::from_le_bytes(&bytes).unwrap();
+
+        assert!(matches!(cbw.direction, DataDirection::NotExpected));
+    }
+
+    #[test]
+    fn invalid() {
+        let mut bytes = cbw(1, 0, false, 0, &[0x00, 0, 0, 0, 0, 0]);
+        bytes[14] = 17;
+        assert!(matches!(
+            CommandBlockWrapper::from_le_bytes(&bytes),
+            Err(Error::InvalidLength)
+        ));
+
+        assert!(matches!(
+            CommandBlockWrapper::from_le_bytes(&bytes[..30]),
+            Err(Error::InvalidLength)
+        ));
+
+        bytes[0] = b'X';
+        assert!(matches!(
+            CommandBlockWrapper::from_le_bytes(&bytes),
+            Err(Error::InvalidSignature)
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+use embedded_io_async::{ErrorType, Read, Write};
+
+use crate::usb_mass_storage::TransportError;
+
+use super::{cbw::DataDirection, MAX_PACKET_SIZE};
+
+/// The data stage of a single command, limited to the direction and length the host gave in the
+/// CBW.
+///
+/// Handlers read or write through this as the command requires, without needing to know what
+/// the host expected. Anything the host didn't ask for (data in the wrong direction, or more than
+/// `dCBWDataTransferLength`) is dropped and recorded, so the transport can report a phase error.
+///
+/// Handlers may also read and write in pieces of any size: data-in is coalesced into full packets,
+/// as a short packet ends the data stage as far as the host is concerned, and data-out is received
+/// a packet at a time so it can be read into smaller buffers.
+///
+/// Where the device moves less than the host expected, the transport terminates the data-in stage
+/// with a short packet, or accepts and discards the rest of the data-out stage. The bulk-only
+/// transport spec allows either of these in place of a STALL, which embassy-usb doesn't let a
+/// class request.
+pub struct DataPhase<'a, T> {
+    io: &'a mut T,
+    direction: DataDirection,
+    expected: u32,
+    transferred: u32,
+    overrun: bool,
+    packet_size: usize,
+    /// Data-in waiting for a full packet, or data-out received but not yet read
+    packet: [u8; MAX_PACKET_SIZE],
+    packet_start: usize,
+    packet_end: usize,
+}
+
+impl<'a, T> DataPhase<'a, T>
+where
+    T: Read<Error = TransportError> + Write<Error = TransportError>,
+{
+    pub fn new(io: &'a mut T, direction: DataDirection, expected: u32, packet_size: u16) -> Self {
+        assert!(packet_size as usize <= MAX_PACKET_SIZE);
+        Self {
+            io,
+            direction,
+            expected,
+            transferred: 0,
+            overrun: false,
+            packet_size: packet_size as usize,
+            packet: [0; MAX_PACKET_SIZE],
+            packet_start: 0,
+            packet_end: 0,
+        }
+    }
+
+    /// `dCSWDataResidue`: how much of the host's expected data wasn't transferred
+    pub fn residue(&self) -> u32 {
+        self.expected - self.transferred
+    }
+
+    /// The device attempted to transfer data the host didn't expect, either in the wrong
+    /// direction or beyond the host's length
+    pub fn overrun(&self) -> bool {
+        self.overrun
+    }
+
+    /// How much more data-in the host expects, `None` if it expects data-out or no data
+    pub fn data_in_remaining(&self) -> Option<u32> {
+        (self.direction == DataDirection::In).then(|| self.residue())
+    }
+
+    fn remaining(&self, direction: DataDirection) -> u32 {
+        if self.direction == direction {
+            self.residue()
+        } else {
+            0
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        self.packet_end - self.packet_start
+    }
+
+    /// Complete the data stage, so the host is ready for the CSW
+    pub async fn finish(&mut self) -> Result<(), TransportError> {
+        match self.direction {
+            DataDirection::In => {
+                if self.packet_end > 0 {
+                    // a short packet, which ends the data stage
+                    self.io.write_all(&self.packet[..self.packet_end]).await?;
+                    self.packet_end = 0;
+                } else if self.residue() > 0
+                    && (self.transferred as usize).is_multiple_of(self.packet_size)
+                {
+                    // a full-sized final packet (or no data) doesn't tell the host we're done
+                    self.io.write(&[]).await?;
+                }
+            }
+            DataDirection::Out => {
+                // discarded data wasn't processed, so doesn't count towards the residue
+                let mut remaining = self.residue() as usize - self.buffered();
+                self.packet_start = self.packet_end;
+                while remaining > 0 {
+                    let n = self.io.read(&mut self.packet[..self.packet_size]).await?;
+                    remaining = remaining.saturating_sub(n);
+                }
+            }
+            DataDirection::NotExpected => {}
+        }
+        Ok(())
+    }
+}
+
+impl<T> ErrorType for DataPhase<'_, T> {
+    type Error = TransportError;
+}
+
+impl<T> Read for DataPhase<'_, T>
+where
+    T: Read<Error = TransportError> + Write<Error = TransportError>,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.buffered() == 0 {
+            let remaining = self.remaining(DataDirection::Out) as usize;
+            if remaining == 0 {
+                self.overrun = true;
+                return Ok(0);
+            }
+
+            let len = buf.len().min(remaining);
+            if len >= self.packet_size {
+                let n = self.io.read(&mut buf[..len]).await?;
+                self.transferred += n as u32;
+                return Ok(n);
+            }
+
+            // too small for a packet, which must be received whole
+            self.packet_start = 0;
+            self.packet_end = self.io.read(&mut self.packet[..self.packet_size]).await?;
+        }
+
+        let n = buf.len().min(self.buffered());
+        buf[..n].copy_from_slice(&self.packet[self.packet_start..self.packet_start + n]);
+        self.packet_start += n;
+        self.transferred += n as u32;
+        Ok(n)
+    }
+}
+
+impl<T> Write for DataPhase<'_, T>
+where
+    T: Read<Error = TransportError> + Write<Error = TransportError>,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let remaining = self.remaining(DataDirection::In) as usize;
+        if remaining == 0 {
+            // drop it, the host isn't expecting any (more) data
+            self.overrun = true;
+            return Ok(buf.len());
+        }
+        let buf = &buf[..buf.len().min(remaining)];
+
+        if self.packet_end == 0 && buf.len() >= self.packet_size {
+            // a whole packet can go straight out
+            self.io.write_all(&buf[..self.packet_size]).await?;
+            self.transferred += self.packet_size as u32;
+            return Ok(self.packet_size);
+        }
+
+        let n = buf.len().min(self.packet_size - self.packet_end);
+        self.packet[self.packet_end..self.packet_end + n].copy_from_slice(&buf[..n]);
+        self.packet_end += n;
+        self.transferred += n as u32;
+
+        if self.packet_end == self.packet_size {
+            self.io.write_all(&self.packet[..self.packet_size]).await?;
+            self.packet_end = 0;
+        }
+        Ok(n)
+    }
+}
@@ -1,49 +1,66 @@
+use core::convert::Infallible;
 use core::future::Future;
 
-use defmt::warn;
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
 use embassy_sync::blocking_mutex::raw::RawMutex;
-use embassy_usb::driver::Driver;
-use embedded_io_async::{Read, ReadExactError, Write};
+use embassy_usb::driver::{Driver, EndpointError};
+use embedded_io_async::{Read, Write};
 
 use crate::usb_mass_storage::{endpoints::Endpoints, TransportError};
 
 use self::{
-    cbw::{CommandBlockWrapper, DataDirection, CBW_LEN},
+    cbw::{CommandBlockWrapper, CBW_LEN},
     csw::{build_csw, CommandStatus},
 };
 
 pub mod cbw;
 pub mod csw;
 
+/// Largest bulk endpoint max packet size (high speed)
+const MAX_PACKET_SIZE: usize = 512;
+
+mod data_phase;
+pub use data_phase::DataPhase;
+
+#[cfg(test)]
+mod tests;
+
+/// The command block carried by a CBW, along with the logical unit it is addressed to
 pub struct CommandBlock<'a> {
     pub bytes: &'a [u8],
-    #[allow(dead_code)]
     pub lun: u8,
 }
 
+/// Why a [`Handler`] couldn't complete a command
 pub enum CommandError {
     Failed,
     Invalid,
     TransportError(TransportError),
 }
 
+/// Processes the command blocks received by [`BulkOnlyTransport`]
 pub trait Handler {
-    fn data_transfer_from_host(
-        &mut self,
-        cb: &CommandBlock,
-        reader: &mut impl embedded_io_async::Read<Error = TransportError>,
-    ) -> impl Future<Output = Result<(), CommandError>>;
-    fn data_transfer_to_host(
-        &mut self,
-        cb: &CommandBlock,
-        writer: &mut impl embedded_io_async::Write<Error = TransportError>,
-    ) -> impl Future<Output = Result<(), CommandError>>;
-    fn no_data_transfer(
+    /// Process the command in `cb`, reading any data it carries from `data`, or writing any data
+    /// it produces to `data`
+    fn handle(
         &mut self,
         cb: &CommandBlock,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
     ) -> impl Future<Output = Result<(), CommandError>>;
+
+    /// The host reset the device, abandoning whatever command was in progress
+    fn reset(&mut self) {}
+
+    /// Carry on with work left over from earlier commands while waiting for the next, never
+    /// completing. It's abandoned as soon as a CBW arrives, so may be cut short at any await
+    fn background(&mut self) -> impl Future<Output = Infallible> {
+        core::future::pending()
+    }
 }
 
+/// USB mass storage bulk-only transport (BBB): reads CBWs from the bulk-out endpoint, passes
+/// them to a [`Handler`] and reports the outcome back to the host in a CSW
 pub struct BulkOnlyTransport<'d, D: Driver<'d>, M: RawMutex> {
     endpoints: Endpoints<'d, D, M>,
 }
@@ -53,54 +70,103 @@ impl<'d, D: Driver<'d>, M: RawMutex> BulkOnlyTransport<'d, D, M> {
         Self { endpoints }
     }
 
+    /// Process commands forever, passing each one to `handler`
     pub async fn run(&mut self, handler: &mut impl Handler) -> ! {
         loop {
-            // TODO: the error handling is non-existent here
-            let mut buf = [0u8; CBW_LEN];
-            match self.endpoints.read_exact(&mut buf).await {
+            match self.process_command(handler).await {
                 Ok(()) => {}
-                Err(ReadExactError::Other(e)) => {
-                    warn!("Transport error reading CBW {}", e);
-                    continue;
+                Err(TransportError::Reset()) => {
+                    // whatever we were doing is abandoned, the host expects a CBW next
+                    info!("Bulk-only mass storage reset");
+                    handler.reset();
                 }
-                Err(ReadExactError::UnexpectedEof) => {
-                    warn!("Unexpected EOF reading CBW");
-                    continue;
+                Err(TransportError::Endpoint(EndpointError::Disabled)) => {
+                    warn!("Endpoints disabled, waiting for the host to configure the device");
+                    self.endpoints.wait_enabled().await;
                 }
-            };
-            let cbw = CommandBlockWrapper::from_le_bytes(&buf).unwrap();
-            let cb = CommandBlock {
-                bytes: &cbw.block[..cbw.block_len],
-                lun: cbw.lun,
-            };
-            let response = match cbw.direction {
-                DataDirection::Out => {
-                    handler
-                        .data_transfer_from_host(&cb, &mut self.endpoints)
-                        .await
-                }
-                DataDirection::In => {
-                    handler
-                        .data_transfer_to_host(&cb, &mut self.endpoints)
-                        .await
-                }
-                DataDirection::NotExpected => handler.no_data_transfer(&cb).await,
-            };
-            let status = match response {
-                Ok(()) => CommandStatus::Passed,
-                Err(CommandError::Failed | CommandError::Invalid) => CommandStatus::Failed,
-                Err(CommandError::TransportError(e)) => {
+                Err(e) => {
                     warn!("Transport error processing command: {}", e);
-                    continue;
                 }
-            };
-            let buf = build_csw(&cbw, status);
-            match self.endpoints.write_all(&buf).await {
-                Ok(_) => {}
-                Err(e) => {
-                    warn!("Transport error writing CSW: {}", e);
-                    continue;
+            }
+        }
+    }
+
+    /// Read one CBW and carry out the command it contains, finishing with the CSW
+    async fn process_command(&mut self, handler: &mut impl Handler) -> Result<(), TransportError> {
+        // a valid CBW starts a transfer and is ended by a short packet, so it's one packet unless
+        // the packets are smaller than a CBW (spec. section 6.2.1)
+        let packet_size = self.endpoints.packet_size();
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let mut len = 0;
+        loop {
+            let read = self
+                .endpoints
+                .read(&mut buf[len..len + packet_size as usize]);
+            let n = if len == 0 {
+                // the handler gets on with anything in the background until the host's next command
+                match select(read, handler.background()).await {
+                    Either::First(n) => n?,
+                    Either::Second(never) => match never {},
                 }
+            } else {
+                read.await?
+            };
+            len += n;
+            if n < packet_size as usize || len >= CBW_LEN {
+                break;
+            }
+        }
+        let cbw = match CommandBlockWrapper::from_le_bytes(&buf[..len]) {
+            Ok(cbw) => cbw,
+            Err(e) => {
+                warn!("Invalid CBW ({} bytes): {}", len, e);
+                return Err(self.halt().await);
+            }
+        };
+
+        let cb = CommandBlock {
+            bytes: &cbw.block[..cbw.block_len],
+            lun: cbw.lun,
+        };
+        let mut data = DataPhase::new(
+            &mut self.endpoints,
+            cbw.direction,
+            cbw.data_transfer_len,
+            packet_size,
+        );
+
+        let status = match handler.handle(&cb, &mut data).await {
+            Ok(()) => CommandStatus::Passed,
+            Err(CommandError::Failed | CommandError::Invalid) => CommandStatus::Failed,
+            Err(CommandError::TransportError(e)) => return Err(e),
+        };
+        let status = if data.overrun() {
+            // the device and host disagree on the data direction, or the host expected
+            // less data than the device had (cases 2, 3, 7, 8, 10 & 13 of the spec)
+            warn!("Phase error, host expected {} bytes", cbw.data_transfer_len);
+            CommandStatus::PhaseError
+        } else {
+            status
+        };
+
+        data.finish().await?;
+
+        let buf = build_csw(&cbw, status, data.residue());
+        self.endpoints.write_all(&buf).await
+    }
+
+    /// After an invalid CBW the device must not process any more commands until the host performs
+    /// reset recovery (spec. section 6.6.1).
+    ///
+    /// The spec has us STALL both bulk endpoints until then, but embassy-usb doesn't let a class
+    /// stall its endpoints, so instead everything the host sends is ignored and nothing is sent
+    /// back. The host times out and recovers in the same way. The CLEAR_FEATURE(HALT) requests
+    /// that complete reset recovery are handled by the USB device itself.
+    async fn halt(&mut self) -> TransportError {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        loop {
+            if let Err(e) = self.endpoints.read(&mut buf).await {
+                return e;
             }
         }
     }
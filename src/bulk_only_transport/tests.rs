@@ -0,0 +1,350 @@
+//! The thirteen host/device data transfer cases of the bulk-only transport spec (section 6.7).
+//! Hn/Hi/Ho is what the host expects (none, in, out), Dn/Di/Do is what the device does.
+
+use std::vec::Vec;
+
+use embedded_io_async::{Read, Write};
+
+use super::{CommandBlock, CommandError, DataPhase, Handler};
+use crate::mock::{cbw, run_transport, ControlResponse, Csw};
+use crate::usb_mass_storage::TransportError;
+
+const PACKET_SIZE: u16 = 64;
+
+const STATUS_PASSED: u8 = 0x00;
+const STATUS_PHASE_ERROR: u8 = 0x02;
+
+/// Does the same thing for every command: sends `data_in` bytes, or receives up to `data_out`
+/// bytes, in pieces of `piece` bytes (or all at once)
+#[derive(Default)]
+struct Device {
+    data_in: usize,
+    data_out: usize,
+    piece: Option<usize>,
+    received: Vec<u8>,
+}
+
+impl Handler for Device {
+    async fn handle(
+        &mut self,
+        _cb: &CommandBlock<'_>,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let data_in: Vec<u8> = (0..self.data_in).map(|i| i as u8).collect();
+        for piece in data_in.chunks(self.piece.unwrap_or(self.data_in.max(1))) {
+            data.write_all(piece).await?;
+        }
+
+        let mut buf = std::vec![0u8; self.data_out];
+        let mut received = 0;
+        while received < buf.len() {
+            let end = buf.len().min(received + self.piece.unwrap_or(buf.len()));
+            match data.read(&mut buf[received..end]).await? {
+                0 => break,
+                n => received += n,
+            }
+        }
+        self.received.extend_from_slice(&buf[..received]);
+        Ok(())
+    }
+}
+
+/// Run one command with the host expecting `host_in` bytes from the device, or to send it
+/// `host_out`, then check the transport is still in step with a second (no data) command
+fn transfer(device: &mut Device, host_in: u32, host_out: &[u8]) -> (Vec<u8>, Csw) {
+    let (data, csw, next_csw) = run_transport(device, PACKET_SIZE, |usb| async move {
+        let (data, csw) = usb.command(1, 0, &[0x00], host_out, host_in).await;
+        let (_, next_csw) = usb.command(2, 0, &[0x00], &[], 0).await;
+        (data, csw, next_csw)
+    });
+
+    assert_eq!(csw.tag, 1);
+    assert_eq!(next_csw.tag, 2);
+    (data, csw)
+}
+
+#[test]
+fn case_1_hn_dn() {
+    let (data, csw) = transfer(&mut Device::default(), 0, &[]);
+
+    assert!(data.is_empty());
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn case_2_hn_di() {
+    let mut device = Device {
+        data_in: 36,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 0, &[]);
+
+    assert!(data.is_empty());
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn case_3_hn_do() {
+    let mut device = Device {
+        data_out: 64,
+        ..Default::default()
+    };
+    let (_, csw) = transfer(&mut device, 0, &[]);
+
+    assert!(device.received.is_empty());
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn case_4_hi_dn() {
+    let (data, csw) = transfer(&mut Device::default(), 64, &[]);
+
+    // terminated with a zero length packet
+    assert!(data.is_empty());
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 64);
+}
+
+#[test]
+fn case_5_hi_gt_di() {
+    let mut device = Device {
+        data_in: 36,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 128, &[]);
+
+    assert_eq!(data.len(), 36);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 92);
+}
+
+#[test]
+fn case_5_hi_gt_di_full_packets() {
+    let mut device = Device {
+        data_in: 64,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 128, &[]);
+
+    // the full packet is followed by a zero length packet
+    assert_eq!(data.len(), 64);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 64);
+}
+
+#[test]
+fn case_6_hi_eq_di() {
+    let mut device = Device {
+        data_in: 36,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 36, &[]);
+
+    assert_eq!(data, (0..36).collect::<Vec<u8>>());
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn case_7_hi_lt_di() {
+    let mut device = Device {
+        data_in: 100,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 36, &[]);
+
+    assert_eq!(data.len(), 36);
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn case_8_hi_do() {
+    let mut device = Device {
+        data_out: 64,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 64, &[]);
+
+    assert!(data.is_empty());
+    assert!(device.received.is_empty());
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert_eq!(csw.data_residue, 64);
+}
+
+#[test]
+fn case_9_ho_dn() {
+    let (_, csw) = transfer(&mut Device::default(), 0, &[0xAA; 64]);
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 64);
+}
+
+#[test]
+fn case_10_ho_di() {
+    let mut device = Device {
+        data_in: 36,
+        ..Default::default()
+    };
+    let (data, csw) = transfer(&mut device, 0, &[0xAA; 64]);
+
+    assert!(data.is_empty());
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert_eq!(csw.data_residue, 64);
+}
+
+#[test]
+fn case_11_ho_gt_do() {
+    let mut device = Device {
+        data_out: 64,
+        ..Default::default()
+    };
+    let (_, csw) = transfer(&mut device, 0, &[0xAA; 128]);
+
+    assert_eq!(device.received, [0xAA; 64]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 64);
+}
+
+#[test]
+fn case_12_ho_eq_do() {
+    let mut device = Device {
+        data_out: 64,
+        ..Default::default()
+    };
+    let (_, csw) = transfer(&mut device, 0, &[0xAA; 64]);
+
+    assert_eq!(device.received, [0xAA; 64]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn case_13_ho_lt_do() {
+    let mut device = Device {
+        data_out: 128,
+        ..Default::default()
+    };
+    let (_, csw) = transfer(&mut device, 0, &[0xAA; 64]);
+
+    assert_eq!(device.received, [0xAA; 64]);
+    assert_eq!(csw.status, STATUS_PHASE_ERROR);
+    assert_eq!(csw.data_residue, 0);
+}
+
+#[test]
+fn data_in_pieces_are_coalesced_into_packets() {
+    let mut device = Device {
+        data_in: 150,
+        piece: Some(10),
+        ..Default::default()
+    };
+    let packets = run_transport(&mut device, PACKET_SIZE, |usb| async move {
+        usb.host_write(&cbw(1, 200, true, 0, &[0x00]));
+        let mut packets = Vec::new();
+        for _ in 0..3 {
+            packets.push(usb.host_read_packet().await.len());
+        }
+        packets
+    });
+
+    // only the last is short
+    assert_eq!(packets, [64, 64, 22]);
+}
+
+#[test]
+fn data_out_read_in_pieces() {
+    let mut device = Device {
+        data_out: 150,
+        piece: Some(7),
+        ..Default::default()
+    };
+    let host_out: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let (_, csw) = transfer(&mut device, 0, &host_out);
+
+    assert_eq!(device.received, host_out[..150]);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 50);
+}
+
+#[test]
+fn get_max_lun() {
+    let response = run_transport(&mut Device::default(), PACKET_SIZE, |usb| async move {
+        usb.get_max_lun().await
+    });
+
+    assert_eq!(response, ControlResponse::Accepted(std::vec![0]));
+}
+
+#[test]
+fn reset_with_data_stage_is_rejected() {
+    let response = run_transport(&mut Device::default(), PACKET_SIZE, |usb| async move {
+        usb.control(0x21, 0xFF, 0, 0, 1).await
+    });
+
+    assert_eq!(response, ControlResponse::Rejected);
+}
+
+#[test]
+fn invalid_cbw_is_ignored_until_reset() {
+    let csw = run_transport(&mut Device::default(), PACKET_SIZE, |usb| async move {
+        let mut bad_signature = cbw(1, 0, false, 0, &[0x00]);
+        bad_signature[3] = b'X';
+        usb.host_write(&bad_signature);
+        usb.host_expect_nothing().await;
+
+        // not even a valid CBW gets a response
+        usb.host_write(&cbw(2, 0, false, 0, &[0x00]));
+        usb.host_expect_nothing().await;
+
+        let reset = usb.bulk_only_mass_storage_reset().await;
+        assert_eq!(reset, ControlResponse::Accepted(std::vec![]));
+
+        let (_, csw) = usb.command(3, 0, &[0x00], &[], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.tag, 3);
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn short_cbw_is_invalid() {
+    let csw = run_transport(&mut Device::default(), PACKET_SIZE, |usb| async move {
+        usb.host_write(&cbw(1, 0, false, 0, &[0x00])[..30]);
+        usb.host_expect_nothing().await;
+
+        usb.bulk_only_mass_storage_reset().await;
+        let (_, csw) = usb.command(2, 0, &[0x00], &[], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.tag, 2);
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn reset_abandons_command() {
+    let mut device = Device {
+        data_out: 128,
+        ..Default::default()
+    };
+    let csw = run_transport(&mut device, PACKET_SIZE, |usb| async move {
+        // only half the data arrives before the host gives up
+        usb.host_write(&cbw(1, 128, false, 0, &[0x00]));
+        usb.host_write(&[0xAA; 64]);
+        usb.host_expect_nothing().await;
+
+        usb.bulk_only_mass_storage_reset().await;
+        let (_, csw) = usb.command(2, 0, &[0x00], &[0xBB; 128], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.tag, 2);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(csw.data_residue, 0);
+    assert_eq!(device.received, [0xBB; 128]);
+}
@@ -1,14 +1,44 @@
-#![no_std]
+//! USB mass storage for [`embassy_usb`]: the bulk-only transport and a SCSI block device target.
+//!
+//! Nothing here is specific to a chip; provide a [`BlockDevice`] for your storage, wrap it in a
+//! [`LogicalUnit`] and pass it, along with your HAL's USB [`Builder`](embassy_usb::Builder), to
+//! [`UsbMassStorage::new`]:
+//!
+//! ```ignore
+//! let mut state = State::<NoopRawMutex>::default();
+//! let logical_unit = LogicalUnit::new(&mut block_device, b"VENDOR  ", b"PRODUCT         ", b"1.00");
+//! let mut usb_mass_storage = UsbMassStorage::new(
+//!     &mut state,
+//!     &mut builder,
+//!     64, // packet size
+//!     [logical_unit],
+//! );
+//! let mut usb = builder.build();
+//!
+//! join(usb.run(), usb_mass_storage.run()).await;
+//! ```
+//!
+//! To insert, eject or swap media while the host is connected, share a [`RemovableMedium`] with
+//! [`UsbMassStorage::set_removable_medium`].
+//!
+//! The layers can also be used on their own: [`Scsi`] runs the SCSI command set over a
+//! [`BulkOnlyTransport`], which in turn can drive any [`bulk_only_transport::Handler`].
+//!
+//! See `examples/rp2040` for a complete firmware.
 
-use embassy_rp::{
-    bind_interrupts,
-    peripherals::{PIO0, USB},
-};
+#![cfg_attr(not(test), no_std)]
+
+pub mod block_devices;
+pub mod bulk_only_transport;
+pub mod scsi;
+pub mod usb_mass_storage;
 
-#[cfg(feature = "wifi")]
-pub mod wifi;
+pub use bulk_only_transport::BulkOnlyTransport;
+pub use scsi::{
+    BlockDevice, BlockDeviceError, LogicalUnit, MediumRequest, RemovableMedium, Scsi,
+    TransferError, WriteProtect,
+};
+pub use usb_mass_storage::{State, UsbMassStorage};
 
-bind_interrupts!(pub struct Irqs {
-    USBCTRL_IRQ => embassy_rp::usb::InterruptHandler<USB>;
-    PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<PIO0>;
-});
+#[cfg(test)]
+mod mock;
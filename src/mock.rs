@@ -0,0 +1,1079 @@
+//! Host-side stand-ins for the USB peripheral, so the transport and SCSI layers can be driven
+//! from `cargo test`. The test plays the part of the USB host via [`MockUsb`].
+
+use core::future::{poll_fn, Future};
+use core::task::Poll;
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+use embassy_futures::{
+    block_on,
+    select::{select3, Either3},
+};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_usb::driver::{
+    Bus, ControlPipe, Direction, Driver, Endpoint, EndpointAddress, EndpointAllocError,
+    EndpointError, EndpointIn, EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+};
+use embassy_usb::{Builder, Config};
+
+use crate::block_devices::{FlashError, NorFlash, SdSpi, SpiError};
+use crate::bulk_only_transport::{BulkOnlyTransport, Handler};
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, LogicalUnit, RemovableMedium, MODE_PARAMETERS_BYTES,
+};
+use crate::usb_mass_storage::{add_function, State, UsbMassStorage};
+
+pub const VENDOR_IDENTIFICATION: &[u8; 8] = b"MOCK    ";
+pub const PRODUCT_IDENTIFICATION: &[u8; 16] = b"Mock Storage    ";
+pub const PRODUCT_REVISION_LEVEL: &[u8; 4] = b"0.01";
+
+/// How many times the host will poll for a packet before assuming the device has hung
+const MAX_HOST_POLLS: usize = 10_000;
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+#[derive(Default)]
+struct Pipe {
+    packets: RefCell<VecDeque<Vec<u8>>>,
+}
+
+/// The outcome of a control transfer, as seen by the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlResponse {
+    Accepted(Vec<u8>),
+    Rejected,
+}
+
+/// State shared between a [`MockDriver`] (the device side) and the test (the host side)
+pub struct MockUsb {
+    packet_size: u16,
+    /// Packets sent by the host on the bulk-out endpoint, not yet read by the device
+    bulk_out: Pipe,
+    /// Packets written by the device on the bulk-in endpoint, not yet read by the host
+    bulk_in: Pipe,
+    /// Setup packet sent by the host, not yet read by the device
+    setup: RefCell<Option<[u8; 8]>>,
+    /// Data stage of the current control-in transfer
+    control_in: RefCell<Vec<u8>>,
+    /// How the device completed the last control transfer, not yet read by the host
+    control_response: RefCell<Option<ControlResponse>>,
+}
+
+/// The 13 byte Command Status Wrapper, as seen by the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csw {
+    pub tag: u32,
+    pub data_residue: u32,
+    pub status: u8,
+}
+
+impl MockUsb {
+    pub fn new(packet_size: u16) -> Rc<Self> {
+        Rc::new(Self {
+            packet_size,
+            bulk_out: Default::default(),
+            bulk_in: Default::default(),
+            setup: Default::default(),
+            control_in: Default::default(),
+            control_response: Default::default(),
+        })
+    }
+
+    pub fn driver(self: &Rc<Self>) -> MockDriver {
+        MockDriver {
+            usb: self.clone(),
+            next_ep: 1,
+        }
+    }
+
+    fn info(&self, index: usize, dir: Direction, ep_type: EndpointType) -> EndpointInfo {
+        EndpointInfo {
+            addr: EndpointAddress::from_parts(index, dir),
+            ep_type,
+            max_packet_size: self.packet_size,
+            interval_ms: 0,
+        }
+    }
+
+    /// Send `data` to the device on the bulk-out endpoint, split into max-packet-sized packets
+    pub fn host_write(&self, data: &[u8]) {
+        let mut packets = self.bulk_out.packets.borrow_mut();
+        for chunk in data.chunks(self.packet_size as usize) {
+            packets.push_back(chunk.to_vec());
+        }
+    }
+
+    /// Wait for the device to send a packet on the bulk-in endpoint
+    pub async fn host_read_packet(&self) -> Vec<u8> {
+        let mut polls = 0;
+        poll_fn(|_| match self.bulk_in.packets.borrow_mut().pop_front() {
+            Some(packet) => Poll::Ready(packet),
+            None => {
+                polls += 1;
+                assert!(polls < MAX_HOST_POLLS, "device never sent a packet");
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Give the device plenty of opportunity to send something on the bulk-in endpoint, and check
+    /// it doesn't
+    pub async fn host_expect_nothing(&self) {
+        let mut polls = 0;
+        poll_fn(|_| {
+            assert!(
+                self.bulk_in.packets.borrow().is_empty(),
+                "device sent a packet"
+            );
+            polls += 1;
+            if polls < MAX_HOST_POLLS / 10 {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await
+    }
+
+    /// Perform a control transfer on the default control pipe. Any data-out stage is empty.
+    pub async fn control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> ControlResponse {
+        let mut setup = [request_type, request, 0, 0, 0, 0, 0, 0];
+        setup[2..4].copy_from_slice(&value.to_le_bytes());
+        setup[4..6].copy_from_slice(&index.to_le_bytes());
+        setup[6..8].copy_from_slice(&length.to_le_bytes());
+        assert!(self.setup.replace(Some(setup)).is_none());
+
+        let mut polls = 0;
+        poll_fn(|_| match self.control_response.take() {
+            Some(response) => Poll::Ready(response),
+            None => {
+                polls += 1;
+                assert!(
+                    polls < MAX_HOST_POLLS,
+                    "device never completed control transfer"
+                );
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Bulk-Only Mass Storage Reset, to interface 0
+    pub async fn bulk_only_mass_storage_reset(&self) -> ControlResponse {
+        self.control(0x21, 0xFF, 0, 0, 0).await
+    }
+
+    /// Get Max LUN, from interface 0
+    pub async fn get_max_lun(&self) -> ControlResponse {
+        self.control(0xA1, 0xFE, 0, 0, 1).await
+    }
+
+    /// Run a whole bulk-only transport command, as a host would: send the CBW (and `data_out`,
+    /// if any), then read data-in until `data_in_len` bytes or a short packet has arrived,
+    /// followed by the CSW.
+    pub async fn command(
+        &self,
+        tag: u32,
+        lun: u8,
+        cb: &[u8],
+        data_out: &[u8],
+        data_in_len: u32,
+    ) -> (Vec<u8>, Csw) {
+        assert!(data_out.is_empty() || data_in_len == 0);
+
+        let data_transfer_len = data_out.len() as u32 + data_in_len;
+        self.host_write(&cbw(tag, data_transfer_len, data_in_len > 0, lun, cb));
+        self.host_write(data_out);
+
+        let mut data_in = Vec::new();
+        while data_in.len() < data_in_len as usize {
+            let packet = self.host_read_packet().await;
+            let short = packet.len() < self.packet_size as usize;
+            data_in.extend_from_slice(&packet);
+            if short {
+                break;
+            }
+        }
+
+        // a CSW spans packets smaller than it
+        let mut csw = self.host_read_packet().await;
+        while csw.len() < 13 && csw.len().is_multiple_of(self.packet_size as usize) {
+            csw.extend(self.host_read_packet().await);
+        }
+        assert_eq!(csw.len(), 13, "expected a CSW, got {:x?}", csw);
+        assert_eq!(&csw[..4], b"USBS");
+
+        let csw = Csw {
+            tag: u32::from_le_bytes(csw[4..8].try_into().unwrap()),
+            data_residue: u32::from_le_bytes(csw[8..12].try_into().unwrap()),
+            status: csw[12],
+        };
+        (data_in, csw)
+    }
+}
+
+/// Buffers for the descriptors and control transfers of a mock [`Builder`]
+struct Descriptors {
+    device: [u8; 256],
+    config: [u8; 256],
+    bos: [u8; 256],
+    control: [u8; 64],
+}
+
+impl Descriptors {
+    fn new() -> Self {
+        Self {
+            device: [0; 256],
+            config: [0; 256],
+            bos: [0; 256],
+            control: [0; 64],
+        }
+    }
+
+    fn builder<'d>(&'d mut self, usb: &Rc<MockUsb>) -> Builder<'d, MockDriver> {
+        Builder::new(
+            usb.driver(),
+            Config::new(0xabcd, 0xabcd),
+            &mut self.device,
+            &mut self.config,
+            &mut self.bos,
+            &mut [],
+            &mut self.control,
+        )
+    }
+}
+
+/// Enumerate a [`UsbMassStorage`] on a mock bus, backed by `block_device`, and run it until
+/// `host` completes
+pub fn run_device<BD: BlockDevice, F: Future>(
+    block_device: &mut BD,
+    packet_size: u16,
+    host: impl FnOnce(Rc<MockUsb>) -> F,
+) -> F::Output {
+    run_logical_units([logical_unit(block_device)], packet_size, host)
+}
+
+/// A [`LogicalUnit`] identifying itself with the mock strings
+pub fn logical_unit<BD: BlockDevice>(block_device: &mut BD) -> LogicalUnit<'_, BD> {
+    LogicalUnit::new(
+        block_device,
+        VENDOR_IDENTIFICATION,
+        PRODUCT_IDENTIFICATION,
+        PRODUCT_REVISION_LEVEL,
+    )
+}
+
+/// Enumerate a [`UsbMassStorage`] on a mock bus with `logical_units`, and run it until `host`
+/// completes. The power on unit attention of each logical unit is cleared before `host` starts
+pub fn run_logical_units<BD: BlockDevice, F: Future, const LUNS: usize>(
+    logical_units: [LogicalUnit<'_, BD>; LUNS],
+    packet_size: u16,
+    host: impl FnOnce(Rc<MockUsb>) -> F,
+) -> F::Output {
+    run_mass_storage(logical_units, [None; LUNS], packet_size, |usb| async move {
+        clear_unit_attention(&usb, LUNS).await;
+        host(usb).await
+    })
+}
+
+/// [`run_logical_units`], leaving the host to clear the power on unit attention
+pub fn run_logical_units_from_power_on<BD: BlockDevice, F: Future, const LUNS: usize>(
+    logical_units: [LogicalUnit<'_, BD>; LUNS],
+    packet_size: u16,
+    host: impl FnOnce(Rc<MockUsb>) -> F,
+) -> F::Output {
+    run_mass_storage(logical_units, [None; LUNS], packet_size, host)
+}
+
+/// [`run_logical_units`] with a single logical unit, whose medium is changed through
+/// `removable_medium`
+pub fn run_removable_medium<'bd, BD: BlockDevice, F: Future>(
+    logical_unit: LogicalUnit<'bd, BD>,
+    removable_medium: &'bd RemovableMedium<'bd, NoopRawMutex, BD>,
+    packet_size: u16,
+    host: impl FnOnce(Rc<MockUsb>) -> F,
+) -> F::Output {
+    let removable_media = [Some(removable_medium)];
+    run_mass_storage(
+        [logical_unit],
+        removable_media,
+        packet_size,
+        |usb| async move {
+            clear_unit_attention(&usb, 1).await;
+            host(usb).await
+        },
+    )
+}
+
+fn run_mass_storage<'bd, BD: BlockDevice, F: Future, const LUNS: usize>(
+    logical_units: [LogicalUnit<'bd, BD>; LUNS],
+    removable_media: [Option<&'bd RemovableMedium<'bd, NoopRawMutex, BD>>; LUNS],
+    packet_size: u16,
+    host: impl FnOnce(Rc<MockUsb>) -> F,
+) -> F::Output {
+    let usb = MockUsb::new(packet_size);
+    let mut descriptors = Descriptors::new();
+    let mut state = State::<NoopRawMutex>::default();
+    let mut builder = descriptors.builder(&usb);
+
+    let mut usb_mass_storage =
+        UsbMassStorage::new(&mut state, &mut builder, packet_size, logical_units);
+    for (lun, removable_medium) in removable_media.into_iter().enumerate() {
+        if let Some(removable_medium) = removable_medium {
+            usb_mass_storage.set_removable_medium(lun as u8, removable_medium);
+        }
+    }
+    let mut usb_device = builder.build();
+
+    match block_on(select3(usb_mass_storage.run(), usb_device.run(), host(usb))) {
+        Either3::First(never) | Either3::Second(never) => never,
+        Either3::Third(output) => output,
+    }
+}
+
+/// TEST UNIT READY to fail with the power on unit attention, then REQUEST SENSE so no sense is
+/// left over
+async fn clear_unit_attention(usb: &MockUsb, luns: usize) {
+    for lun in 0..luns as u8 {
+        usb.command(0, lun, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+        usb.command(0, lun, &[0x03, 0, 0, 0, 18, 0], &[], 18).await;
+    }
+}
+
+/// Enumerate a bare [`BulkOnlyTransport`] on a mock bus, passing commands to `handler`, and run
+/// it until `host` completes
+pub fn run_transport<H: Handler, F: Future>(
+    handler: &mut H,
+    packet_size: u16,
+    host: impl FnOnce(Rc<MockUsb>) -> F,
+) -> F::Output {
+    let usb = MockUsb::new(packet_size);
+    let mut descriptors = Descriptors::new();
+    let mut state = State::<NoopRawMutex>::default();
+    let mut builder = descriptors.builder(&usb);
+
+    let endpoints = add_function(&mut state, &mut builder, packet_size, 0);
+    let mut transport = BulkOnlyTransport::new(endpoints);
+    let mut usb_device = builder.build();
+
+    match block_on(select3(transport.run(handler), usb_device.run(), host(usb))) {
+        Either3::First(never) | Either3::Second(never) => never,
+        Either3::Third(output) => output,
+    }
+}
+
+/// Build a 31 byte Command Block Wrapper
+pub fn cbw(tag: u32, data_transfer_len: u32, direction_in: bool, lun: u8, cb: &[u8]) -> [u8; 31] {
+    let mut cbw = [0u8; 31];
+    cbw[..4].copy_from_slice(b"USBC");
+    cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+    cbw[8..12].copy_from_slice(&data_transfer_len.to_le_bytes());
+    cbw[12] = if direction_in { 1 << 7 } else { 0 };
+    cbw[13] = lun;
+    cbw[14] = cb.len() as u8;
+    cbw[15..15 + cb.len()].copy_from_slice(cb);
+    cbw
+}
+
+pub struct MockDriver {
+    usb: Rc<MockUsb>,
+    next_ep: usize,
+}
+
+impl<'a> Driver<'a> for MockDriver {
+    type EndpointOut = MockEndpointOut;
+    type EndpointIn = MockEndpointIn;
+    type ControlPipe = MockControlPipe;
+    type Bus = MockBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        assert_eq!(max_packet_size, self.usb.packet_size);
+        self.next_ep += 1;
+        Ok(MockEndpointOut {
+            usb: self.usb.clone(),
+            info: self.usb.info(self.next_ep, Direction::Out, ep_type),
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        assert_eq!(max_packet_size, self.usb.packet_size);
+        self.next_ep += 1;
+        Ok(MockEndpointIn {
+            usb: self.usb.clone(),
+            info: self.usb.info(self.next_ep, Direction::In, ep_type),
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        (
+            MockBus,
+            MockControlPipe {
+                usb: self.usb,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+pub struct MockEndpointOut {
+    usb: Rc<MockUsb>,
+    info: EndpointInfo,
+}
+
+impl Endpoint for MockEndpointOut {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointOut for MockEndpointOut {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        poll_fn(
+            |_| match self.usb.bulk_out.packets.borrow_mut().pop_front() {
+                Some(packet) if packet.len() > buf.len() => {
+                    Poll::Ready(Err(EndpointError::BufferOverflow))
+                }
+                Some(packet) => {
+                    buf[..packet.len()].copy_from_slice(&packet);
+                    Poll::Ready(Ok(packet.len()))
+                }
+                None => Poll::Pending,
+            },
+        )
+        .await
+    }
+}
+
+pub struct MockEndpointIn {
+    usb: Rc<MockUsb>,
+    info: EndpointInfo,
+}
+
+impl Endpoint for MockEndpointIn {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl EndpointIn for MockEndpointIn {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        // as with real hardware, one write is one packet
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+        self.usb
+            .bulk_in
+            .packets
+            .borrow_mut()
+            .push_back(buf.to_vec());
+        Ok(())
+    }
+}
+
+pub struct MockControlPipe {
+    usb: Rc<MockUsb>,
+    max_packet_size: usize,
+}
+
+impl MockControlPipe {
+    fn respond(&self, response: ControlResponse) {
+        assert!(self.usb.control_response.replace(Some(response)).is_none());
+    }
+}
+
+impl ControlPipe for MockControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        poll_fn(|_| match self.usb.setup.take() {
+            Some(setup) => Poll::Ready(setup),
+            None => Poll::Pending,
+        })
+        .await
+    }
+
+    async fn data_out(
+        &mut self,
+        _buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        // the host never sends a data-out stage
+        Ok(0)
+    }
+
+    async fn data_in(&mut self, data: &[u8], first: bool, last: bool) -> Result<(), EndpointError> {
+        let mut control_in = self.usb.control_in.borrow_mut();
+        if first {
+            control_in.clear();
+        }
+        control_in.extend_from_slice(data);
+        if last {
+            self.respond(ControlResponse::Accepted(core::mem::take(&mut control_in)));
+        }
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.respond(ControlResponse::Accepted(Vec::new()));
+    }
+
+    async fn reject(&mut self) {
+        self.respond(ControlResponse::Rejected);
+    }
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
+
+pub struct MockBus;
+
+impl Bus for MockBus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        core::future::pending().await
+    }
+
+    fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+/// A RAM-backed block device
+pub struct RamBlockDevice<const BLOCK_BYTES: usize = 512> {
+    data: Vec<u8>,
+    pub saved_mode_parameters: Option<[u8; MODE_PARAMETERS_BYTES]>,
+    /// A block that can't be read, as if it had gone bad
+    pub unreadable: Option<u64>,
+}
+
+impl<const BLOCK_BYTES: usize> RamBlockDevice<BLOCK_BYTES> {
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            data: std::vec![0; blocks * BLOCK_BYTES],
+            saved_mode_parameters: None,
+            unreadable: None,
+        }
+    }
+
+    pub fn block(&self, lba: u64) -> &[u8] {
+        let start = lba as usize * BLOCK_BYTES;
+        &self.data[start..start + BLOCK_BYTES]
+    }
+
+    fn block_range(&self, lba: u64) -> Result<core::ops::Range<usize>, BlockDeviceError> {
+        let start = lba as usize * BLOCK_BYTES;
+        if start + BLOCK_BYTES > self.data.len() {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        Ok(start..start + BLOCK_BYTES)
+    }
+}
+
+impl<const N: usize> BlockDevice for RamBlockDevice<N> {
+    const BLOCK_BYTES: usize = N;
+
+    async fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let range = self.block_range(lba)?;
+        if self.unreadable == Some(lba) {
+            return Err(BlockDeviceError::ReadError);
+        }
+        block.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    async fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        let range = self.block_range(lba)?;
+        self.data[range].copy_from_slice(block);
+        Ok(())
+    }
+
+    const SAVES_MODE_PARAMETERS: bool = true;
+
+    async fn load_mode_parameters(
+        &mut self,
+        parameters: &mut [u8; MODE_PARAMETERS_BYTES],
+    ) -> Result<bool, BlockDeviceError> {
+        if let Some(saved) = &self.saved_mode_parameters {
+            parameters.copy_from_slice(saved);
+        }
+        Ok(self.saved_mode_parameters.is_some())
+    }
+
+    async fn save_mode_parameters(
+        &mut self,
+        parameters: &[u8; MODE_PARAMETERS_BYTES],
+    ) -> Result<(), BlockDeviceError> {
+        self.saved_mode_parameters = Some(*parameters);
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / N) as u64 - 1
+    }
+}
+
+/// An arbitrarily large block device that stores nothing: each block reads back as its own
+/// LBA, only the last block written is kept, and the ranges discarded are listed
+pub struct SparseBlockDevice {
+    blocks: u64,
+    pub written: Option<(u64, Vec<u8>)>,
+    pub discarded: Vec<(u64, u32)>,
+}
+
+impl SparseBlockDevice {
+    pub fn new(blocks: u64) -> Self {
+        Self {
+            blocks,
+            written: None,
+            discarded: Vec::new(),
+        }
+    }
+
+    pub fn block(lba: u64) -> [u8; 512] {
+        let mut block = [0; 512];
+        for chunk in block.chunks_mut(8) {
+            chunk.copy_from_slice(&lba.to_be_bytes());
+        }
+        block
+    }
+}
+
+impl BlockDevice for SparseBlockDevice {
+    const BLOCK_BYTES: usize = 512;
+
+    async fn read_block(&mut self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if lba >= self.blocks {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        block.copy_from_slice(&Self::block(lba));
+        Ok(())
+    }
+
+    async fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if lba >= self.blocks {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        self.written = Some((lba, block.to_vec()));
+        Ok(())
+    }
+
+    const DISCARDS: bool = true;
+
+    async fn discard(&mut self, lba: u64, count: u32) -> Result<(), BlockDeviceError> {
+        self.discarded.push((lba, count));
+        Ok(())
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks - 1
+    }
+}
+
+/// NOR flash in RAM, enforcing the rules of the real thing: erases and writes must be aligned,
+/// and programming can only clear bits
+pub struct SimulatedFlash<const ERASE_SIZE: usize = 4096, const WRITE_SIZE: usize = 256> {
+    data: Vec<u8>,
+    /// How many times each sector has been erased
+    pub erases: Vec<usize>,
+    /// How many `write`s have been made
+    pub writes: usize,
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> SimulatedFlash<ERASE_SIZE, WRITE_SIZE> {
+    /// `sectors` erase sectors, as shipped: erased
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: std::vec![0xFF; sectors * ERASE_SIZE],
+            erases: std::vec![0; sectors],
+            writes: 0,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<core::ops::Range<usize>, FlashError> {
+        let range = offset as usize..offset as usize + len;
+        if range.end > self.data.len() {
+            return Err(FlashError::OutOfBounds);
+        }
+        Ok(range)
+    }
+}
+
+impl<const ERASE_SIZE: usize, const WRITE_SIZE: usize> NorFlash
+    for SimulatedFlash<ERASE_SIZE, WRITE_SIZE>
+{
+    const ERASE_SIZE: usize = ERASE_SIZE;
+    const WRITE_SIZE: usize = WRITE_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        let range = self.range(offset, bytes.len())?;
+        bytes.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        if !(from as usize).is_multiple_of(ERASE_SIZE) || !(to as usize).is_multiple_of(ERASE_SIZE)
+        {
+            return Err(FlashError::NotAligned);
+        }
+        let range = self.range(from, (to - from) as usize)?;
+        for sector in range.clone().step_by(ERASE_SIZE) {
+            self.erases[sector / ERASE_SIZE] += 1;
+        }
+        self.data[range].fill(0xFF);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        if !(offset as usize).is_multiple_of(WRITE_SIZE) || !bytes.len().is_multiple_of(WRITE_SIZE)
+        {
+            return Err(FlashError::NotAligned);
+        }
+        let range = self.range(offset, bytes.len())?;
+        for (old, new) in self.data[range].iter_mut().zip(bytes) {
+            assert_eq!(
+                *old & new,
+                *new,
+                "programmed a bit from 0 to 1 without erasing"
+            );
+            *old = *new;
+        }
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+/// An SD card in SPI mode, answering byte by byte as a real card would
+pub struct SdCardEmulator {
+    data: Vec<u8>,
+    /// A version 1 card: SDSC, and doesn't know SEND_IF_COND
+    version_1: bool,
+    /// SD_SEND_OP_COND polls answered with "still initialising" before the card is ready
+    pub init_polls: usize,
+    /// Reply to every block written with a write error
+    pub reject_writes: bool,
+    /// Reply to reads of this block with an error token
+    pub unreadable: Option<usize>,
+    /// The commands received, in order
+    pub commands: Vec<u8>,
+    selected: bool,
+    idle: bool,
+    app_command: bool,
+    command: Vec<u8>,
+    state: SdState,
+    out: VecDeque<u8>,
+}
+
+enum SdState {
+    Ready,
+    ReadMultiple(usize),
+    /// Waiting for the next data token of a write
+    Write {
+        lba: usize,
+        multiple: bool,
+    },
+    Receiving {
+        lba: usize,
+        multiple: bool,
+        block: Vec<u8>,
+    },
+}
+
+const SD_BLOCK: usize = 512;
+
+impl SdCardEmulator {
+    /// An SDHC card. SDHC capacity is in units of 1024 blocks
+    pub fn sdhc(blocks: usize) -> Self {
+        assert!(blocks.is_multiple_of(1024));
+        Self::new(blocks, false)
+    }
+
+    /// A version 1 SDSC card, with capacity in units of 4 blocks
+    pub fn sdsc_v1(blocks: usize) -> Self {
+        assert!(blocks.is_multiple_of(4));
+        Self::new(blocks, true)
+    }
+
+    fn new(blocks: usize, version_1: bool) -> Self {
+        Self {
+            data: std::vec![0; blocks * SD_BLOCK],
+            version_1,
+            init_polls: 0,
+            reject_writes: false,
+            unreadable: None,
+            commands: Vec::new(),
+            selected: false,
+            idle: false,
+            app_command: false,
+            command: Vec::new(),
+            state: SdState::Ready,
+            out: VecDeque::new(),
+        }
+    }
+
+    pub fn block(&self, lba: usize) -> &[u8] {
+        &self.data[lba * SD_BLOCK..(lba + 1) * SD_BLOCK]
+    }
+
+    pub fn block_mut(&mut self, lba: usize) -> &mut [u8] {
+        &mut self.data[lba * SD_BLOCK..(lba + 1) * SD_BLOCK]
+    }
+
+    fn blocks(&self) -> usize {
+        self.data.len() / SD_BLOCK
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        let mut csd = [0; 16];
+        let mut set = |msb: usize, lsb: usize, value: usize| {
+            for bit in lsb..=msb {
+                if value >> (bit - lsb) & 1 != 0 {
+                    csd[15 - bit / 8] |= 1 << (bit % 8);
+                }
+            }
+        };
+        if self.version_1 {
+            set(83, 80, 9); // READ_BL_LEN, 512 bytes
+            set(49, 47, 0); // C_SIZE_MULT, 4 blocks
+            set(73, 62, self.blocks() / 4 - 1);
+        } else {
+            set(127, 126, 1);
+            set(69, 48, self.blocks() / 1024 - 1);
+        }
+        csd
+    }
+
+    fn r1(&mut self, r1: u8) {
+        // one byte of command response time
+        self.out.push_back(0xFF);
+        self.out.push_back(r1 | self.idle as u8);
+    }
+
+    /// Queue block `lba`, or an error token if it's unreadable
+    fn read_block(&mut self, lba: usize) {
+        if self.unreadable == Some(lba) {
+            self.out.push_back(0x01);
+        } else {
+            let data = self.block(lba).to_vec();
+            self.data(&data);
+        }
+    }
+
+    /// Queue a data block: start token, data and CRC
+    fn data(&mut self, data: &[u8]) {
+        self.out.push_back(0xFE);
+        self.out.extend(data);
+        self.out.extend([0, 0]);
+    }
+
+    fn lba(&self, arg: u32) -> usize {
+        if self.version_1 {
+            assert!((arg as usize).is_multiple_of(SD_BLOCK));
+            arg as usize / SD_BLOCK
+        } else {
+            arg as usize
+        }
+    }
+
+    fn process_command(&mut self, command: u8, arg: u32) {
+        self.commands.push(command);
+        let app_command = core::mem::take(&mut self.app_command);
+        match command {
+            41 if app_command => {
+                if self.init_polls > 0 {
+                    self.init_polls -= 1;
+                } else {
+                    self.idle = false;
+                }
+                self.r1(0);
+            }
+            0 => {
+                self.idle = true;
+                self.r1(0);
+            }
+            8 if self.version_1 => self.r1(0x04),
+            8 => {
+                self.r1(0);
+                self.out.extend([0, 0, 0x01, arg as u8]);
+            }
+            9 => {
+                self.r1(0);
+                let csd = self.csd();
+                self.data(&csd);
+            }
+            12 => {
+                // stuff byte, response, then busy
+                self.out.clear();
+                self.out.push_back(0xFF);
+                self.out.extend([0, 0, 0]);
+                self.state = SdState::Ready;
+            }
+            16 => self.r1(0),
+            17 | 18 | 24 | 25 if self.lba(arg) >= self.blocks() => self.r1(0x40),
+            17 => {
+                self.r1(0);
+                self.read_block(self.lba(arg));
+            }
+            18 => {
+                self.r1(0);
+                self.state = SdState::ReadMultiple(self.lba(arg));
+            }
+            24 | 25 => {
+                self.r1(0);
+                self.state = SdState::Write {
+                    lba: self.lba(arg),
+                    multiple: command == 25,
+                };
+            }
+            55 => {
+                self.app_command = true;
+                self.r1(0);
+            }
+            58 => {
+                self.r1(0);
+                let ccs = if self.version_1 { 0 } else { 0x40 };
+                self.out.extend([0x80 | ccs, 0xFF, 0x80, 0]);
+            }
+            _ => self.r1(0x04),
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match &mut self.state {
+            SdState::Receiving {
+                lba,
+                multiple,
+                block,
+            } => {
+                block.push(byte);
+                if block.len() < SD_BLOCK + 2 {
+                    return;
+                }
+                let (lba, multiple) = (*lba, *multiple);
+                if self.reject_writes {
+                    self.out.push_back(0x0D);
+                } else {
+                    let block = block[..SD_BLOCK].to_vec();
+                    self.block_mut(lba).copy_from_slice(&block);
+                    self.out.push_back(0x05);
+                }
+                self.out.extend([0, 0]); // busy programming
+                self.state = if multiple {
+                    SdState::Write {
+                        lba: lba + 1,
+                        multiple,
+                    }
+                } else {
+                    SdState::Ready
+                };
+                return;
+            }
+            SdState::Write { lba, multiple } if byte == 0xFE || byte == 0xFC => {
+                self.state = SdState::Receiving {
+                    lba: *lba,
+                    multiple: *multiple,
+                    block: Vec::new(),
+                };
+                return;
+            }
+            SdState::Write { multiple: true, .. } if byte == 0xFD => {
+                // an undefined byte, then busy
+                self.out.extend([0xFF, 0, 0]);
+                self.state = SdState::Ready;
+                return;
+            }
+            // commands aren't taken until the write is stopped
+            SdState::Write { multiple: true, .. } => return,
+            _ => {}
+        }
+
+        if !self.command.is_empty() || byte & 0xC0 == 0x40 {
+            self.command.push(byte);
+            if self.command.len() == 6 {
+                let command = self.command[0] & 0x3F;
+                let arg = u32::from_be_bytes(self.command[1..5].try_into().unwrap());
+                self.command.clear();
+                self.process_command(command, arg);
+            }
+        }
+    }
+}
+
+impl SdSpi for SdCardEmulator {
+    fn select(&mut self, selected: bool) {
+        self.selected = selected;
+        self.command.clear();
+    }
+
+    async fn transfer(&mut self, buf: &mut [u8]) -> Result<(), SpiError> {
+        for byte in buf {
+            if !self.selected {
+                *byte = 0xFF;
+                continue;
+            }
+            let out = self.out.pop_front().unwrap_or(0xFF);
+            self.receive(*byte);
+            if let SdState::ReadMultiple(lba) = self.state {
+                if self.out.is_empty() && lba < self.blocks() {
+                    self.read_block(lba);
+                    self.state = SdState::ReadMultiple(lba + 1);
+                }
+            }
+            *byte = out;
+        }
+        Ok(())
+    }
+}
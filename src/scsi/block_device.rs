@@ -1,13 +1,83 @@
+use core::convert::Infallible;
 use core::future::Future;
 
+use embedded_io_async::{ErrorType, Read, ReadExactError, Write};
+
+use super::mode_pages::ModePages;
+
+/// The largest blocks the default [`BlockDevice::read_to`] and [`BlockDevice::write_from`] can
+/// buffer. Devices with larger blocks that use either fail to build:
+///
+/// ```compile_fail
+/// # use pico_usb_mass_storage::{BlockDevice, BlockDeviceError};
+/// struct LargeBlocks;
+///
+/// impl BlockDevice for LargeBlocks {
+///     const BLOCK_BYTES: usize = 4096;
+///
+///     async fn read_block(&mut self, _: u64, _: &mut [u8]) -> Result<(), BlockDeviceError> {
+///         Ok(())
+///     }
+///
+///     async fn write_block(&mut self, _: u64, _: &[u8]) -> Result<(), BlockDeviceError> {
+///         Ok(())
+///     }
+///
+///     fn block_count(&self) -> u64 {
+///         0
+///     }
+/// }
+///
+/// let mut block = [0; 4096];
+/// let _ = embassy_futures::block_on(LargeBlocks.read_to(0, 1, &mut &mut block[..]));
+/// ```
+pub const DEFAULT_BUFFER_BYTES: usize = 2048;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum BlockDeviceError {
     /// Error during writing; most likely value read back after write was wrong
     WriteError,
 
+    /// Error during reading; the data couldn't be recovered
+    ReadError,
+
+    /// Error erasing the medium before writing
+    EraseError,
+
     /// Address is invalid or out of range
     InvalidAddress,
+
+    /// The device failed, or couldn't be communicated with
+    HardwareError,
+}
+
+/// The length of the mode parameters given to [`BlockDevice::save_mode_parameters`]
+pub const MODE_PARAMETERS_BYTES: usize = ModePages::BYTE_LEN;
+
+/// Why streaming blocks to or from a [`BlockDevice`] failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TransferError<E> {
+    BlockDevice(BlockDeviceError),
+    /// The data ran out before all the blocks were written
+    UnexpectedEof,
+    /// Reading or writing the data failed
+    Io(E),
+}
+
+impl<E> From<BlockDeviceError> for TransferError<E> {
+    fn from(e: BlockDeviceError) -> Self {
+        Self::BlockDevice(e)
+    }
+}
+
+impl<E> From<ReadExactError<E>> for TransferError<E> {
+    fn from(e: ReadExactError<E>) -> Self {
+        match e {
+            ReadExactError::UnexpectedEof => Self::UnexpectedEof,
+            ReadExactError::Other(e) => Self::Io(e),
+        }
+    }
 }
 
 pub trait BlockDevice {
@@ -18,17 +88,273 @@ pub trait BlockDevice {
     /// Read the block indicated by `lba` into the provided buffer
     fn read_block(
         &mut self,
-        lba: u32,
+        lba: u64,
         block: &mut [u8],
     ) -> impl Future<Output = Result<(), BlockDeviceError>>;
 
     /// Write the `block` buffer to the block indicated by `lba`
     fn write_block(
         &mut self,
-        lba: u32,
+        lba: u64,
         block: &[u8],
     ) -> impl Future<Output = Result<(), BlockDeviceError>>;
 
+    /// Read consecutive blocks from `lba` into `blocks`, which holds a whole number of blocks
+    ///
+    /// Devices that can transfer several blocks at once should override this; by default each
+    /// block is read in turn with [`read_block`](Self::read_block)
+    fn read_blocks(
+        &mut self,
+        lba: u64,
+        blocks: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            for (i, block) in blocks.chunks_mut(Self::BLOCK_BYTES).enumerate() {
+                self.read_block(lba + i as u64, block).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Write `blocks`, which holds a whole number of blocks, to consecutive blocks from `lba`
+    ///
+    /// Devices that can transfer several blocks at once should override this; by default each
+    /// block is written in turn with [`write_block`](Self::write_block)
+    fn write_blocks(
+        &mut self,
+        lba: u64,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            for (i, block) in blocks.chunks(Self::BLOCK_BYTES).enumerate() {
+                self.write_block(lba + i as u64, block).await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Read `count` blocks from `lba`, writing them to `to` as they're read
+    ///
+    /// This is how the host's reads are carried out, `to` being the USB data stage. Devices that
+    /// can stream should override this, and those whose blocks are larger than
+    /// [`DEFAULT_BUFFER_BYTES`] must, or they don't build; by default as many whole blocks as fit
+    /// in a [`DEFAULT_BUFFER_BYTES`] buffer are read at a time with
+    /// [`read_blocks`](Self::read_blocks)
+    fn read_to<W: Write>(
+        &mut self,
+        lba: u64,
+        count: u32,
+        to: &mut W,
+    ) -> impl Future<Output = Result<(), TransferError<W::Error>>> {
+        const {
+            assert!(
+                Self::BLOCK_BYTES <= DEFAULT_BUFFER_BYTES,
+                "blocks larger than DEFAULT_BUFFER_BYTES need read_to implemented"
+            )
+        };
+        async move {
+            let mut buf = [0u8; DEFAULT_BUFFER_BYTES];
+            let blocks_per_transfer = blocks_per_buffer::<Self>();
+
+            let end = lba + count as u64;
+            let mut lba = lba;
+            while lba < end {
+                let count = blocks_per_transfer.min(end - lba);
+                let buf = &mut buf[..count as usize * Self::BLOCK_BYTES];
+                self.read_blocks(lba, buf).await?;
+                to.write_all(buf).await.map_err(TransferError::Io)?;
+                lba += count;
+            }
+            Ok(())
+        }
+    }
+
+    /// Write `count` blocks to `lba`, reading them from `from` as they're written
+    ///
+    /// This is how the host's writes are carried out, `from` being the USB data stage. Devices
+    /// that can stream should override this, and those whose blocks are larger than
+    /// [`DEFAULT_BUFFER_BYTES`] must, or they don't build; by default as many whole blocks as fit
+    /// in a [`DEFAULT_BUFFER_BYTES`] buffer are written at a time with
+    /// [`write_blocks`](Self::write_blocks)
+    fn write_from<R: Read>(
+        &mut self,
+        lba: u64,
+        count: u32,
+        from: &mut R,
+    ) -> impl Future<Output = Result<(), TransferError<R::Error>>> {
+        const {
+            assert!(
+                Self::BLOCK_BYTES <= DEFAULT_BUFFER_BYTES,
+                "blocks larger than DEFAULT_BUFFER_BYTES need write_from implemented"
+            )
+        };
+        async move {
+            let mut buf = [0u8; DEFAULT_BUFFER_BYTES];
+            let blocks_per_transfer = blocks_per_buffer::<Self>();
+
+            let end = lba + count as u64;
+            let mut lba = lba;
+            while lba < end {
+                let count = blocks_per_transfer.min(end - lba);
+                let buf = &mut buf[..count as usize * Self::BLOCK_BYTES];
+                from.read_exact(buf).await?;
+                self.write_blocks(lba, buf).await?;
+                lba += count;
+            }
+            Ok(())
+        }
+    }
+
+    /// How many blocks [`format`](Self::format) is asked to erase at a time: by default as many
+    /// as fit in [`DEFAULT_BUFFER_BYTES`]. Devices that erase in larger units, such as flash
+    /// sectors, should make it one of those
+    const FORMAT_BLOCKS: u32 = if Self::BLOCK_BYTES < DEFAULT_BUFFER_BYTES {
+        (DEFAULT_BUFFER_BYTES / Self::BLOCK_BYTES) as u32
+    } else {
+        1
+    };
+
+    /// Erase `count` blocks from `lba`, for FORMAT UNIT
+    ///
+    /// The medium is formatted from the first block to the last,
+    /// [`FORMAT_BLOCKS`](Self::FORMAT_BLOCKS) at a time so the host can follow along. While formatting in the background a call is abandoned if a command
+    /// arrives, and made again once it's been handled.
+    /// Devices with something to lay down on a freshly formatted medium, such as a partition table
+    /// and filesystem, should do so once the last block has been erased. By default the blocks are
+    /// zeroed with [`write_from`](Self::write_from)
+    fn format(
+        &mut self,
+        lba: u64,
+        count: u32,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            match self.write_from(lba, count, &mut Fill(0)).await {
+                Ok(()) => Ok(()),
+                Err(TransferError::BlockDevice(e)) => Err(e),
+                Err(TransferError::UnexpectedEof | TransferError::Io(_)) => {
+                    unreachable!("the zeros don't run out")
+                }
+            }
+        }
+    }
+
+    /// Check the device is healthy, for the host's SEND DIAGNOSTIC self-test
+    ///
+    /// Devices able to check themselves more thoroughly should override this, by checksumming
+    /// their flash, say. By default a sample of blocks is read with [`read_to`](Self::read_to):
+    /// the first, the middle and the last
+    fn self_test(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            let last = self.block_count();
+            for lba in [0, last / 2, last] {
+                match self.read_to(lba, 1, &mut Sink).await {
+                    Ok(()) => {}
+                    Err(TransferError::BlockDevice(e)) => return Err(e),
+                    Err(TransferError::UnexpectedEof | TransferError::Io(_)) => {
+                        unreachable!("the sink takes everything")
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Whether writes may be held in a volatile cache until [`flush`](Self::flush)ed. The host is
+    /// then told the write cache is enabled, so it synchronizes the cache when it needs writes to
+    /// be durable
+    const CACHES_WRITES: bool = false;
+
+    /// Make every block written so far durable, however it's been cached or batched up
+    ///
+    /// Called when the host synchronizes the cache, forces unit access or disables the write
+    /// cache, and before the medium is ejected.
+    fn flush(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Ok(()) }
+    }
+
+    /// Whether [`discard`](Self::discard) does anything, so the host is told it may unmap blocks
+    /// it's no longer using
+    const DISCARDS: bool = false;
+
+    /// Forget `count` blocks from `lba`, which the host is no longer using, until they're written
+    /// again. They may read back as anything meanwhile
+    ///
+    /// Called when the host unmaps blocks, as a filesystem does for deleted files. Flash can then
+    /// erase them ahead of time, making their next write quicker and sparing it copying them
+    /// about. Only called if [`DISCARDS`](Self::DISCARDS) is set.
+    fn discard(
+        &mut self,
+        lba: u64,
+        count: u32,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        let _ = (lba, count);
+        async { Ok(()) }
+    }
+
+    /// Whether the device has somewhere to keep mode parameters the host saves with MODE SELECT,
+    /// see [`save_mode_parameters`](Self::save_mode_parameters)
+    const SAVES_MODE_PARAMETERS: bool = false;
+
+    /// Read back the mode parameters last passed to
+    /// [`save_mode_parameters`](Self::save_mode_parameters) into `parameters`, returning `false`
+    /// if none have been saved
+    ///
+    /// This is called once, before the first command, to restore the parameters the host saved.
+    fn load_mode_parameters(
+        &mut self,
+        parameters: &mut [u8; MODE_PARAMETERS_BYTES],
+    ) -> impl Future<Output = Result<bool, BlockDeviceError>> {
+        let _ = parameters;
+        async { Ok(false) }
+    }
+
+    /// Keep `parameters` across power cycles, somewhere other than the blocks the host can see.
+    /// Only called if [`SAVES_MODE_PARAMETERS`](Self::SAVES_MODE_PARAMETERS) is set
+    ///
+    /// The bytes are opaque to the device; they're the mode pages the host asked to be saved.
+    fn save_mode_parameters(
+        &mut self,
+        parameters: &[u8; MODE_PARAMETERS_BYTES],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        let _ = parameters;
+        async { Ok(()) }
+    }
+
     /// Get the maxium valid lba (logical block address)
-    fn block_count(&self) -> u32;
+    fn block_count(&self) -> u64;
+}
+
+/// How many blocks fit in the default [`BlockDevice::read_to`] and [`BlockDevice::write_from`]'s
+/// buffer, which each check at build time that it's at least one
+fn blocks_per_buffer<BD: BlockDevice + ?Sized>() -> u64 {
+    (DEFAULT_BUFFER_BYTES / BD::BLOCK_BYTES) as u64
+}
+
+/// Reads as `.0` over and over, without end: blocks of a single byte for
+/// [`BlockDevice::write_from`]
+pub(crate) struct Fill(pub u8);
+
+impl ErrorType for Fill {
+    type Error = Infallible;
+}
+
+impl Read for Fill {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        buf.fill(self.0);
+        Ok(buf.len())
+    }
+}
+
+/// Takes whatever's written and drops it, to read blocks with [`BlockDevice::read_to`] only to
+/// see that they can be
+struct Sink;
+
+impl ErrorType for Sink {
+    type Error = Infallible;
+}
+
+impl Write for Sink {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(buf.len())
+    }
 }
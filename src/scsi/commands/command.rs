@@ -6,7 +6,8 @@ use crate::scsi::{commands::*, enums::*, Error};
 pub enum Command {
     Inquiry(#[defmt(Debug2Format)] InquiryCommand),
     TestUnitReady(#[defmt(Debug2Format)] TestUnitReadyCommand),
-    ReadCapacity(#[defmt(Debug2Format)] ReadCapacity10Command), // FIXME: ReadCapacity16
+    ReadCapacity(#[defmt(Debug2Format)] ReadCapacity10Command),
+    ReadCapacity16(#[defmt(Debug2Format)] ReadCapacity16Command),
     ModeSense(#[defmt(Debug2Format)] ModeSenseXCommand),
     PreventAllowMediumRemoval(#[defmt(Debug2Format)] PreventAllowMediumRemovalCommand),
     RequestSense(#[defmt(Debug2Format)] RequestSenseCommand),
@@ -14,12 +15,15 @@ pub enum Command {
     Write(#[defmt(Debug2Format)] WriteXCommand),
     Format(#[defmt(Debug2Format)] FormatCommand),
     SendDiagnostic(#[defmt(Debug2Format)] SendDiagnosticCommand),
+    ReceiveDiagnosticResults(#[defmt(Debug2Format)] ReceiveDiagnosticResultsCommand),
     ReportLuns(#[defmt(Debug2Format)] ReportLunsCommand),
     ModeSelect(#[defmt(Debug2Format)] ModeSelectXCommand),
     StartStopUnit(#[defmt(Debug2Format)] StartStopUnitCommand),
     ReadFormatCapacities(#[defmt(Debug2Format)] ReadFormatCapacitiesCommand),
-    Verify(#[defmt(Debug2Format)] Verify10Command), // FIXME: Verify16?
-    SynchronizeCache(#[defmt(Debug2Format)] SynchronizeCache10Command), // FIXME: SynchronizeCache16?
+    Verify(#[defmt(Debug2Format)] VerifyXCommand),
+    SynchronizeCache(#[defmt(Debug2Format)] SynchronizeCacheXCommand),
+    Unmap(#[defmt(Debug2Format)] UnmapCommand),
+    WriteSame(#[defmt(Debug2Format)] WriteSameXCommand),
 }
 
 impl Command {
@@ -33,7 +37,15 @@ impl Command {
             OpCode::Read6 => Ok(Command::Read((overlay::<Read6Command>(cbw)?).into())),
             OpCode::Read10 => Ok(Command::Read((overlay::<Read10Command>(cbw)?).into())),
             OpCode::Read12 => Ok(Command::Read((overlay::<Read12Command>(cbw)?).into())),
+            OpCode::Read16 => Ok(Command::Read((overlay::<Read16Command>(cbw)?).into())),
             OpCode::ReadCapacity10 => Ok(Command::ReadCapacity(overlay(cbw)?)),
+            OpCode::ServiceActionIn16 => {
+                let command: ReadCapacity16Command = overlay(cbw)?;
+                if command.service_action() != ReadCapacity16Command::SERVICE_ACTION {
+                    return Err(Error::UnhandledOpCode);
+                }
+                Ok(Command::ReadCapacity16(command))
+            }
             OpCode::ReadFormatCapacities => Ok(Command::ReadFormatCapacities(overlay(cbw)?)),
             OpCode::Inquiry => Ok(Command::Inquiry(overlay(cbw)?)),
             OpCode::TestUnitReady => Ok(Command::TestUnitReady(overlay(cbw)?)),
@@ -43,8 +55,12 @@ impl Command {
             OpCode::ModeSense10 => Ok(Command::ModeSense(
                 (overlay::<ModeSense10Command>(cbw)?).into(),
             )),
-            OpCode::ModeSelect6 => Ok(Command::ModeSelect(overlay(cbw)?)),
-            OpCode::ModeSelect10 => Ok(Command::ModeSelect(overlay(cbw)?)),
+            OpCode::ModeSelect6 => Ok(Command::ModeSelect(
+                (overlay::<ModeSelect6Command>(cbw)?).into(),
+            )),
+            OpCode::ModeSelect10 => Ok(Command::ModeSelect(
+                (overlay::<ModeSelect10Command>(cbw)?).into(),
+            )),
             OpCode::PreventAllowMediumRemoval => {
                 Ok(Command::PreventAllowMediumRemoval(overlay(cbw)?))
             }
@@ -52,12 +68,29 @@ impl Command {
             OpCode::Write6 => Ok(Command::Write((overlay::<Write6Command>(cbw)?).into())),
             OpCode::Write10 => Ok(Command::Write((overlay::<Write10Command>(cbw)?).into())),
             OpCode::Write12 => Ok(Command::Write((overlay::<Write12Command>(cbw)?).into())),
+            OpCode::Write16 => Ok(Command::Write((overlay::<Write16Command>(cbw)?).into())),
             OpCode::Format => Ok(Command::Format(overlay(cbw)?)),
             OpCode::SendDiagnostic => Ok(Command::SendDiagnostic(overlay(cbw)?)),
+            OpCode::ReceiveDiagnosticResults => {
+                Ok(Command::ReceiveDiagnosticResults(overlay(cbw)?))
+            }
             OpCode::ReportLuns => Ok(Command::ReportLuns(overlay(cbw)?)),
             OpCode::StartStopUnit => Ok(Command::StartStopUnit(overlay(cbw)?)),
-            OpCode::Verify10 => Ok(Command::Verify(overlay(cbw)?)),
-            OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(overlay(cbw)?)),
+            OpCode::Verify10 => Ok(Command::Verify((overlay::<Verify10Command>(cbw)?).into())),
+            OpCode::Verify16 => Ok(Command::Verify((overlay::<Verify16Command>(cbw)?).into())),
+            OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(
+                (overlay::<SynchronizeCache10Command>(cbw)?).into(),
+            )),
+            OpCode::SynchronizeCache16 => Ok(Command::SynchronizeCache(
+                (overlay::<SynchronizeCache16Command>(cbw)?).into(),
+            )),
+            OpCode::Unmap => Ok(Command::Unmap(overlay(cbw)?)),
+            OpCode::WriteSame10 => Ok(Command::WriteSame(
+                (overlay::<WriteSame10Command>(cbw)?).into(),
+            )),
+            OpCode::WriteSame16 => Ok(Command::WriteSame(
+                (overlay::<WriteSame16Command>(cbw)?).into(),
+            )),
             _ => Err(Error::UnhandledOpCode),
         }
     }
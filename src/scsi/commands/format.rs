@@ -26,3 +26,69 @@ pub struct FormatCommand {
     #[overlay(bytes=5..=5, nested)]
     pub control: Control,
 }
+
+/// The short parameter list header (SBC-3 5.3.2.2), sent with FORMAT UNIT when FMTDATA is set
+/// and LONGLIST isn't
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ShortFormatParameterListHeader {
+    #[overlay(bytes=0..=0, bits=0..=2)]
+    pub protection_field_usage: u8,
+
+    /// The bits that follow are valid
+    #[overlay(bytes=1..=1, bits=7..=7)]
+    pub format_options_valid: bool,
+
+    #[overlay(bytes=1..=1, bits=6..=6)]
+    pub disable_primary: bool,
+
+    #[overlay(bytes=1..=1, bits=5..=5)]
+    pub disable_certification: bool,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub stop_format: bool,
+
+    /// An initialization pattern descriptor follows the header
+    #[overlay(bytes=1..=1, bits=3..=3)]
+    pub initialization_pattern: bool,
+
+    /// Return status before formatting, which carries on in the background
+    #[overlay(bytes=1..=1, bits=1..=1)]
+    pub immediate: bool,
+
+    #[overlay(bytes=2..=3)]
+    pub defect_list_length: u16,
+}
+
+/// The long parameter list header (SBC-3 5.3.2.2), sent with FORMAT UNIT when FMTDATA and
+/// LONGLIST are set
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct LongFormatParameterListHeader {
+    #[overlay(bytes=0..=0, bits=0..=2)]
+    pub protection_field_usage: u8,
+
+    /// The bits that follow are valid
+    #[overlay(bytes=1..=1, bits=7..=7)]
+    pub format_options_valid: bool,
+
+    #[overlay(bytes=1..=1, bits=6..=6)]
+    pub disable_primary: bool,
+
+    #[overlay(bytes=1..=1, bits=5..=5)]
+    pub disable_certification: bool,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub stop_format: bool,
+
+    /// An initialization pattern descriptor follows the header
+    #[overlay(bytes=1..=1, bits=3..=3)]
+    pub initialization_pattern: bool,
+
+    /// Return status before formatting, which carries on in the background
+    #[overlay(bytes=1..=1, bits=1..=1)]
+    pub immediate: bool,
+
+    #[overlay(bytes=4..=7)]
+    pub defect_list_length: u32,
+}
@@ -34,6 +34,9 @@ pub use read_format_capacities::*;
 mod read;
 pub use read::*;
 
+mod receive_diagnostic_results;
+pub use receive_diagnostic_results::*;
+
 mod report_luns;
 pub use report_luns::*;
 
@@ -52,11 +55,17 @@ pub use synchronize_cache::*;
 mod test_unit_ready;
 pub use test_unit_ready::*;
 
+mod unmap;
+pub use unmap::*;
+
 mod verify;
 pub use verify::*;
 
 mod write;
 pub use write::*;
 
+mod write_same;
+pub use write_same::*;
+
 mod mode_parameter;
 pub use mode_parameter::*;
@@ -28,19 +28,6 @@ impl Default for ModeParameterHeader6 {
         header
     }
 }
-impl ModeParameterHeader6 {
-    /// Increase the relevant length fields to indicate the provided page follows this header
-    /// can be called multiple times but be aware of the max length allocated by CBW
-    pub fn increase_length_for_page(&mut self, page_code: PageCode) {
-        self.set_mode_data_length(
-            self.mode_data_length()
-                + match page_code {
-                    PageCode::CachingModePage => CachingModePage::BYTE_LEN as u8,
-                },
-        );
-    }
-}
-
 #[overlay]
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ModeParameterHeader10 {
@@ -70,20 +57,6 @@ impl Default for ModeParameterHeader10 {
         header
     }
 }
-impl ModeParameterHeader10 {
-    /// Increase the relevant length fields to indicate the provided page follows this header
-    /// can be called multiple times but be aware of the max length allocated by CBW
-    #[allow(dead_code)]
-    pub fn increase_length_for_page(&mut self, page_code: PageCode) {
-        self.set_mode_data_length(
-            self.mode_data_length()
-                + match page_code {
-                    PageCode::CachingModePage => CachingModePage::BYTE_LEN as u16,
-                },
-        );
-    }
-}
-
 #[overlay]
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
 pub struct SbcDeviceSpecificParameter {
@@ -94,18 +67,49 @@ pub struct SbcDeviceSpecificParameter {
     pub disable_page_out_and_force_unit_access_available: bool,
 }
 
+/// Describes the medium's blocks, following the mode parameter header (SPC-4 7.5.5.1)
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ShortLbaBlockDescriptor {
+    /// `0xFFFFFFFF` if there are too many blocks to fit
+    #[overlay(bytes=0..=3)]
+    pub number_of_blocks: u32,
+
+    #[overlay(bytes=5..=7)]
+    pub block_length: u32,
+}
+
+/// The long form of [`ShortLbaBlockDescriptor`], for MODE SENSE(10) with `LLBAA` set
+/// (SPC-4 7.5.5.2)
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct LongLbaBlockDescriptor {
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=0..=7)]
+    pub number_of_blocks: [u8; 8],
+
+    #[overlay(bytes=12..=15)]
+    pub block_length: u32,
+}
+
 #[repr(u8)]
 #[derive(TryFromPrimitive, Clone, Copy, Eq, PartialEq, Debug)]
 pub enum PageCode {
     CachingModePage = 0x08,
+    ControlModePage = 0x0A,
+    InformationalExceptionsControlModePage = 0x1C,
+    /// Not a page, requests every page the device has
+    AllPages = 0x3F,
 }
 
-/// This is only a partial implementation, there are a whole load of extra
-/// fields defined in SBC-3 6.4.5
+/// SBC-3 6.4.5
 /// Default config is no read or write cache
 #[overlay]
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct CachingModePage {
+    #[overlay(bytes=0..=0, bits=7..=7)]
+    pub parameters_saveable: bool,
+
     #[overlay(bytes=0..=0, bits=0..=5)]
     pub page_code: PageCode,
 
@@ -117,14 +121,88 @@ pub struct CachingModePage {
 
     #[overlay(bytes=2..=2, bits=0..=0)]
     pub read_cache_disable: bool,
+
+    /// Prefetch and cache segment parameters, which don't apply to a block device like ours
+    #[overlay(bytes=3..=19)]
+    _parameters: [u8; 17],
 }
 impl Default for CachingModePage {
     fn default() -> Self {
         let mut mode = Self::new();
         mode.set_page_code(PageCode::CachingModePage);
-        mode.set_page_length(Self::BYTE_LEN as u8);
+        mode.set_page_length(Self::BYTE_LEN as u8 - 2);
         mode.set_write_cache_enabled(false);
         mode.set_read_cache_disable(true);
         mode
     }
 }
+
+/// SPC-4 7.5.8
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ControlModePage {
+    #[overlay(bytes=0..=0, bits=7..=7)]
+    pub parameters_saveable: bool,
+
+    #[overlay(bytes=0..=0, bits=0..=5)]
+    pub page_code: PageCode,
+
+    #[overlay(bytes=1..=1, bits=0..=7)]
+    pub page_length: u8,
+
+    /// Report sense data in descriptor format rather than fixed
+    #[overlay(bytes=2..=2, bits=2..=2)]
+    pub descriptor_sense: bool,
+
+    #[overlay(bytes=4..=4, bits=3..=3)]
+    pub software_write_protect: bool,
+
+    /// Seconds taken by an extended self-test
+    #[overlay(bytes=10..=11)]
+    pub extended_self_test_completion_time: u16,
+}
+impl Default for ControlModePage {
+    fn default() -> Self {
+        let mut mode = Self::new();
+        mode.set_page_code(PageCode::ControlModePage);
+        mode.set_page_length(Self::BYTE_LEN as u8 - 2);
+        mode
+    }
+}
+
+/// SPC-4 7.5.10
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct InformationalExceptionsControlModePage {
+    #[overlay(bytes=0..=0, bits=7..=7)]
+    pub parameters_saveable: bool,
+
+    #[overlay(bytes=0..=0, bits=0..=5)]
+    pub page_code: PageCode,
+
+    #[overlay(bytes=1..=1, bits=0..=7)]
+    pub page_length: u8,
+
+    /// Disable exception control, we have no failure prediction to report
+    #[overlay(bytes=2..=2, bits=3..=3)]
+    pub disable_exception_control: bool,
+
+    /// Method of reporting informational exceptions
+    #[overlay(bytes=3..=3, bits=0..=3)]
+    pub mrie: u8,
+
+    #[overlay(bytes=4..=7)]
+    pub interval_timer: u32,
+
+    #[overlay(bytes=8..=11)]
+    pub report_count: u32,
+}
+impl Default for InformationalExceptionsControlModePage {
+    fn default() -> Self {
+        let mut mode = Self::new();
+        mode.set_page_code(PageCode::InformationalExceptionsControlModePage);
+        mode.set_page_length(Self::BYTE_LEN as u8 - 2);
+        mode.set_disable_exception_control(true);
+        mode
+    }
+}
@@ -22,6 +22,12 @@ B) the default values.
 pub struct ModeSenseXCommand {
     pub command_length: CommandLength,
     pub page_control: PageControl,
+    pub page_code: u8,
+    pub subpage_code: u8,
+    pub allocation_length: u16,
+    pub disable_block_descriptors: bool,
+    /// Only MODE SENSE(10) can return long LBA block descriptors
+    pub long_lba_accepted: bool,
 }
 
 #[overlay]
@@ -52,7 +58,13 @@ impl From<ModeSense6Command> for ModeSenseXCommand {
     fn from(m: ModeSense6Command) -> Self {
         Self {
             command_length: CommandLength::C6,
-            page_control: m.page_control().unwrap(), // FIXME: error handling here and below
+            // two bits, every value is a valid PageControl
+            page_control: m.page_control().unwrap(),
+            page_code: m.page_code(),
+            subpage_code: m.subpage_code(),
+            allocation_length: m.allocation_length() as u16,
+            disable_block_descriptors: m.disable_block_descriptors(),
+            long_lba_accepted: false,
         }
     }
 }
@@ -78,10 +90,10 @@ pub struct ModeSense10Command {
     #[overlay(bytes=3..=3, bits=0..=7)]
     pub subpage_code: u8,
 
-    #[overlay(bytes=8..=9)]
+    #[overlay(bytes=7..=8)]
     pub allocation_length: u16,
 
-    #[overlay(bytes=10..=10, nested)]
+    #[overlay(bytes=9..=9, nested)]
     pub control: Control,
 }
 impl From<ModeSense10Command> for ModeSenseXCommand {
@@ -89,6 +101,31 @@ impl From<ModeSense10Command> for ModeSenseXCommand {
         Self {
             command_length: CommandLength::C10,
             page_control: m.page_control().unwrap(),
+            page_code: m.page_code(),
+            subpage_code: m.subpage_code(),
+            allocation_length: m.allocation_length(),
+            disable_block_descriptors: m.disable_block_descriptors(),
+            long_lba_accepted: m.long_lba_accepted(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use overlay::Overlay;
+
+    use super::*;
+
+    #[test]
+    fn mode_sense10_parse() {
+        let data = [0x5A, 0b1_1000, 0x9C, 0xFF, 0, 0, 0, 0x01, 0x02, 0];
+        let cmd: ModeSenseXCommand = (*ModeSense10Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.command_length, CommandLength::C10);
+        assert_eq!(cmd.page_control, PageControl::DefaultValues);
+        assert_eq!(cmd.page_code, 0x1C);
+        assert_eq!(cmd.subpage_code, 0xFF);
+        assert_eq!(cmd.allocation_length, 0x0102);
+        assert!(cmd.disable_block_descriptors);
+        assert!(cmd.long_lba_accepted);
+    }
+}
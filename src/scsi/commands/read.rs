@@ -3,7 +3,7 @@ use overlay_macro::overlay;
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ReadXCommand {
-    pub lba: u32,
+    pub lba: u64,
     pub transfer_length: u32,
 }
 
@@ -13,7 +13,7 @@ pub struct Read6Command {
     #[overlay(bytes=0..=0, bits=0..=7)]
     pub op_code: u8,
 
-    #[overlay(bytes=1..=3, bits=4..24)]
+    #[overlay(bytes=1..=3, bits=0..=20)]
     pub lba: u32,
 
     #[overlay(bytes=4..=4, bits=0..=7)]
@@ -26,7 +26,7 @@ pub struct Read6Command {
 impl From<Read6Command> for ReadXCommand {
     fn from(r: Read6Command) -> Self {
         Self {
-            lba: r.lba(),
+            lba: r.lba().into(),
             transfer_length: r.transfer_length().into(),
         }
     }
@@ -66,7 +66,7 @@ pub struct Read10Command {
 impl From<Read10Command> for ReadXCommand {
     fn from(r: Read10Command) -> Self {
         Self {
-            lba: r.lba(),
+            lba: r.lba().into(),
             transfer_length: r.transfer_length().into(),
         }
     }
@@ -106,18 +106,81 @@ pub struct Read12Command {
 impl From<Read12Command> for ReadXCommand {
     fn from(r: Read12Command) -> Self {
         Self {
-            lba: r.lba(),
+            lba: r.lba().into(),
+            transfer_length: r.transfer_length(),
+        }
+    }
+}
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Read16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=5..=7)]
+    pub rd_protect: u8,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub dpo: bool,
+
+    #[overlay(bytes=1..=1, bits=3..=3)]
+    pub fua: bool,
+
+    #[overlay(bytes=1..=1, bits=1..=1)]
+    pub fua_nv: bool,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=2..=9)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=10..=13)]
+    pub transfer_length: u32,
+
+    #[overlay(bytes=14..=14, bits=0..=5)]
+    pub group_number: u8,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+impl From<Read16Command> for ReadXCommand {
+    fn from(r: Read16Command) -> Self {
+        Self {
+            lba: u64::from_be_bytes(*r.lba()),
             transfer_length: r.transfer_length(),
         }
     }
 }
 
-/*
-TODO: tests
-#[test]
-fn test_read10_parse() {
-    let data = [0, 0, 0, 0x1E, 0x80, 0, 0, 0x8, 0, 0, 0, 0, 0, 0, 0];
-    let cmd = Read10Command::parse(&data).unwrap();
-    assert_eq!(cmd.lba, 0x1E80);
+#[cfg(test)]
+mod tests {
+    use overlay::Overlay;
+
+    use super::*;
+
+    #[test]
+    fn read6_parse() {
+        let data = [0x08, 0xE1, 0x2, 0x3, 0x4, 0];
+        let cmd: ReadXCommand = (*Read6Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.lba, 0x10203);
+        assert_eq!(cmd.transfer_length, 4);
+    }
+
+    #[test]
+    fn read10_parse() {
+        let data = [0x28, 0, 0, 0, 0x1E, 0x80, 0, 0, 0x8, 0];
+        let cmd = Read10Command::overlay(&data).unwrap();
+        assert_eq!(cmd.lba(), 0x1E80);
+        assert_eq!(cmd.transfer_length(), 8);
+    }
+
+    #[test]
+    fn read16_parse() {
+        let data = [
+            0x88, 0, 0, 0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xAB, 0, 1, 0, 0, 0, 0,
+        ];
+        let cmd: ReadXCommand = (*Read16Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.lba, 0x0123_4567_89AB);
+        assert_eq!(cmd.transfer_length, 0x10000);
+    }
 }
-*/
@@ -14,3 +14,28 @@ pub struct ReadCapacity10Command {
     #[overlay(bytes=9..=9, nested)]
     pub control: Control,
 }
+
+/// SERVICE ACTION IN(16) with the READ CAPACITY(16) service action
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReadCapacity16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=0..=4)]
+    pub service_action: u8,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=2..=9)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=10..=13)]
+    pub allocation_length: u32,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+
+impl ReadCapacity16Command {
+    pub const SERVICE_ACTION: u8 = 0x10;
+}
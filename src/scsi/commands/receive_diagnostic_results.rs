@@ -0,0 +1,23 @@
+use overlay_macro::overlay;
+
+use crate::scsi::commands::Control;
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReceiveDiagnosticResultsCommand {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    /// Return `page_code` rather than the page last sent with SEND DIAGNOSTIC
+    #[overlay(bytes=1..=1, bits=0..=0)]
+    pub page_code_valid: bool,
+
+    #[overlay(bytes=2..=2, bits=0..=7)]
+    pub page_code: u8,
+
+    #[overlay(bytes=3..=4)]
+    pub allocation_length: u16,
+
+    #[overlay(bytes=5..=5, nested)]
+    pub control: Control,
+}
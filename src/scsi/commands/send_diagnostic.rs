@@ -29,3 +29,9 @@ pub struct SendDiagnosticCommand {
     #[overlay(bytes=5..=5, nested)]
     pub control: Control,
 }
+
+impl SendDiagnosticCommand {
+    /// Self-test codes for the short and extended self-tests, run before status is returned
+    pub const SELF_TEST_CODE_FOREGROUND_SHORT: u8 = 0b101;
+    pub const SELF_TEST_CODE_FOREGROUND_EXTENDED: u8 = 0b110;
+}
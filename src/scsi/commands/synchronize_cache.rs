@@ -2,6 +2,14 @@ use overlay_macro::overlay;
 
 use crate::scsi::commands::Control;
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SynchronizeCacheXCommand {
+    pub lba: u64,
+    /// Zero for every block from `lba` to the end of the medium
+    pub number_of_blocks: u32,
+    pub immediate: bool,
+}
+
 #[overlay]
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct SynchronizeCache10Command {
@@ -23,3 +31,61 @@ pub struct SynchronizeCache10Command {
     #[overlay(bytes=9..=9, nested)]
     pub control: Control,
 }
+impl From<SynchronizeCache10Command> for SynchronizeCacheXCommand {
+    fn from(s: SynchronizeCache10Command) -> Self {
+        Self {
+            lba: s.lba().into(),
+            number_of_blocks: s.number_of_blocks().into(),
+            immediate: s.immediate(),
+        }
+    }
+}
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SynchronizeCache16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=1..=1)]
+    pub immediate: bool,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=2..=9)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=10..=13)]
+    pub number_of_blocks: u32,
+
+    #[overlay(bytes=14..=14, bits=0..=5)]
+    pub group_number: u8,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+impl From<SynchronizeCache16Command> for SynchronizeCacheXCommand {
+    fn from(s: SynchronizeCache16Command) -> Self {
+        Self {
+            lba: u64::from_be_bytes(*s.lba()),
+            number_of_blocks: s.number_of_blocks(),
+            immediate: s.immediate(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use overlay::Overlay;
+
+    use super::*;
+
+    #[test]
+    fn synchronize_cache16_parse() {
+        let data = [0x91, 0x02, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0];
+        let cmd: SynchronizeCacheXCommand =
+            (*SynchronizeCache16Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.lba, 0x1_0000_0002);
+        assert_eq!(cmd.number_of_blocks, 3);
+        assert!(cmd.immediate);
+    }
+}
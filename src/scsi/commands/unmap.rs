@@ -0,0 +1,53 @@
+use overlay_macro::overlay;
+
+use crate::scsi::commands::Control;
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct UnmapCommand {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=0..=0)]
+    pub anchor: bool,
+
+    #[overlay(bytes=6..=6, bits=0..=4)]
+    pub group_number: u8,
+
+    #[overlay(bytes=7..=8)]
+    pub parameter_list_length: u16,
+
+    #[overlay(bytes=9..=9, nested)]
+    pub control: Control,
+}
+
+/// Starts the UNMAP parameter list (SBC-3 5.28.2), followed by the block descriptors
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct UnmapParameterListHeader {
+    /// n-1
+    #[overlay(bytes=0..=1)]
+    pub unmap_data_length: u16,
+
+    /// Length in bytes of the block descriptors that follow
+    #[overlay(bytes=2..=3)]
+    pub block_descriptor_data_length: u16,
+
+    #[overlay(bytes=4..=7)]
+    _reserved: [u8; 4],
+}
+
+/// A range of blocks to unmap (SBC-3 5.28.3)
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct UnmapBlockDescriptor {
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=0..=7)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=8..=11)]
+    pub number_of_blocks: u32,
+
+    #[overlay(bytes=12..=15)]
+    _reserved: [u8; 4],
+}
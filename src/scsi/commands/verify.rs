@@ -2,6 +2,23 @@ use overlay_macro::overlay;
 
 use crate::scsi::commands::Control;
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct VerifyXCommand {
+    pub lba: u64,
+    pub verification_length: u32,
+    /// BYTCHK: whether the blocks are compared with the data-out
+    pub byte_check: u8,
+}
+
+impl VerifyXCommand {
+    /// The blocks are only read back from the medium
+    pub const BYTE_CHECK_MEDIUM: u8 = 0b00;
+    /// Each block is compared with the corresponding block of the data-out
+    pub const BYTE_CHECK_EACH_BLOCK: u8 = 0b01;
+    /// Each block is compared with the single block of the data-out
+    pub const BYTE_CHECK_SINGLE_BLOCK: u8 = 0b11;
+}
+
 #[overlay]
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct Verify10Command {
@@ -14,7 +31,7 @@ pub struct Verify10Command {
     #[overlay(bytes=1..=1, bits=4..=4)]
     pub dpo: bool,
 
-    #[overlay(bytes=1..=1, bits=1..=1)]
+    #[overlay(bytes=1..=1, bits=1..=2)]
     pub byte_check: u8,
 
     #[overlay(bytes=2..=5)]
@@ -29,3 +46,75 @@ pub struct Verify10Command {
     #[overlay(bytes=9..=9, nested)]
     pub control: Control,
 }
+impl From<Verify10Command> for VerifyXCommand {
+    fn from(v: Verify10Command) -> Self {
+        Self {
+            lba: v.lba().into(),
+            verification_length: v.verification_length().into(),
+            byte_check: v.byte_check(),
+        }
+    }
+}
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Verify16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=5..=7)]
+    pub vr_protect: u8,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub dpo: bool,
+
+    #[overlay(bytes=1..=1, bits=1..=2)]
+    pub byte_check: u8,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=2..=9)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=10..=13)]
+    pub verification_length: u32,
+
+    #[overlay(bytes=14..=14, bits=0..=5)]
+    pub group_number: u8,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+impl From<Verify16Command> for VerifyXCommand {
+    fn from(v: Verify16Command) -> Self {
+        Self {
+            lba: u64::from_be_bytes(*v.lba()),
+            verification_length: v.verification_length(),
+            byte_check: v.byte_check(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use overlay::Overlay;
+
+    use super::*;
+
+    #[test]
+    fn verify10_parse() {
+        let data = [0x2F, 0b110, 0, 0, 0x01, 0x02, 0, 0, 0x03, 0];
+        let cmd: VerifyXCommand = (*Verify10Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.lba, 0x102);
+        assert_eq!(cmd.verification_length, 3);
+        assert_eq!(cmd.byte_check, VerifyXCommand::BYTE_CHECK_SINGLE_BLOCK);
+    }
+
+    #[test]
+    fn verify16_parse() {
+        let data = [0x8F, 0b010, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0];
+        let cmd: VerifyXCommand = (*Verify16Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.lba, 0x1_0000_0002);
+        assert_eq!(cmd.verification_length, 3);
+        assert_eq!(cmd.byte_check, VerifyXCommand::BYTE_CHECK_EACH_BLOCK);
+    }
+}
@@ -4,8 +4,11 @@ use crate::scsi::commands::Control;
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct WriteXCommand {
-    pub lba: u32,
+    pub lba: u64,
     pub transfer_length: u32,
+    /// Force unit access: the blocks must be on the medium, not just cached, before the command
+    /// completes
+    pub fua: bool,
 }
 
 #[overlay]
@@ -14,7 +17,7 @@ pub struct Write6Command {
     #[overlay(bytes=0..=0, bits=0..=7)]
     pub op_code: u8,
 
-    #[overlay(bytes=1..=3, bits=4..24)]
+    #[overlay(bytes=1..=3, bits=0..=20)]
     pub lba: u32,
 
     #[overlay(bytes=4..=4, bits=0..=7)]
@@ -26,8 +29,9 @@ pub struct Write6Command {
 impl From<Write6Command> for WriteXCommand {
     fn from(w: Write6Command) -> Self {
         Self {
-            lba: w.lba(),
+            lba: w.lba().into(),
             transfer_length: w.transfer_length().into(),
+            fua: false,
         }
     }
 }
@@ -65,8 +69,9 @@ pub struct Write10Command {
 impl From<Write10Command> for WriteXCommand {
     fn from(w: Write10Command) -> Self {
         Self {
-            lba: w.lba(),
+            lba: w.lba().into(),
             transfer_length: w.transfer_length().into(),
+            fua: w.fua(),
         }
     }
 }
@@ -104,8 +109,50 @@ pub struct Write12Command {
 impl From<Write12Command> for WriteXCommand {
     fn from(w: Write12Command) -> Self {
         Self {
-            lba: w.lba(),
+            lba: w.lba().into(),
+            transfer_length: w.transfer_length(),
+            fua: w.fua(),
+        }
+    }
+}
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Write16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=5..=7)]
+    pub wr_protect: u8,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub dpo: bool,
+
+    #[overlay(bytes=1..=1, bits=3..=3)]
+    pub fua: bool,
+
+    #[overlay(bytes=1..=1, bits=1..=1)]
+    pub fua_nv: bool,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=2..=9)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=10..=13)]
+    pub transfer_length: u32,
+
+    #[overlay(bytes=14..=14, bits=0..=5)]
+    pub group_number: u8,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+impl From<Write16Command> for WriteXCommand {
+    fn from(w: Write16Command) -> Self {
+        Self {
+            lba: u64::from_be_bytes(*w.lba()),
             transfer_length: w.transfer_length(),
+            fua: w.fua(),
         }
     }
 }
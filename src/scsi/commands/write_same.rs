@@ -0,0 +1,115 @@
+use overlay_macro::overlay;
+
+use crate::scsi::commands::Control;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct WriteSameXCommand {
+    pub lba: u64,
+    /// Zero for every block from `lba` to the end of the medium
+    pub number_of_blocks: u32,
+    /// The blocks may be discarded rather than written
+    pub unmap: bool,
+    pub anchor: bool,
+    /// No data-out: the blocks are written with zeros
+    pub no_data_out_buffer: bool,
+}
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct WriteSame10Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=5..=7)]
+    pub wr_protect: u8,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub anchor: bool,
+
+    #[overlay(bytes=1..=1, bits=3..=3)]
+    pub unmap: bool,
+
+    #[overlay(bytes=2..=5)]
+    pub lba: u32,
+
+    #[overlay(bytes=6..=6, bits=0..=4)]
+    pub group_number: u8,
+
+    #[overlay(bytes=7..=8)]
+    pub number_of_blocks: u16,
+
+    #[overlay(bytes=9..=9, nested)]
+    pub control: Control,
+}
+impl From<WriteSame10Command> for WriteSameXCommand {
+    fn from(w: WriteSame10Command) -> Self {
+        Self {
+            lba: w.lba().into(),
+            number_of_blocks: w.number_of_blocks().into(),
+            unmap: w.unmap(),
+            anchor: w.anchor(),
+            no_data_out_buffer: false,
+        }
+    }
+}
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct WriteSame16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=5..=7)]
+    pub wr_protect: u8,
+
+    #[overlay(bytes=1..=1, bits=4..=4)]
+    pub anchor: bool,
+
+    #[overlay(bytes=1..=1, bits=3..=3)]
+    pub unmap: bool,
+
+    #[overlay(bytes=1..=1, bits=0..=0)]
+    pub no_data_out_buffer: bool,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=2..=9)]
+    pub lba: [u8; 8],
+
+    #[overlay(bytes=10..=13)]
+    pub number_of_blocks: u32,
+
+    #[overlay(bytes=14..=14, bits=0..=5)]
+    pub group_number: u8,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+impl From<WriteSame16Command> for WriteSameXCommand {
+    fn from(w: WriteSame16Command) -> Self {
+        Self {
+            lba: u64::from_be_bytes(*w.lba()),
+            number_of_blocks: w.number_of_blocks(),
+            unmap: w.unmap(),
+            anchor: w.anchor(),
+            no_data_out_buffer: w.no_data_out_buffer(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use overlay::Overlay;
+
+    use super::*;
+
+    #[test]
+    fn write_same16_parse() {
+        let data = [0x93, 0x09, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0];
+        let cmd: WriteSameXCommand = (*WriteSame16Command::overlay(&data).unwrap()).into();
+        assert_eq!(cmd.lba, 0x1_0000_0002);
+        assert_eq!(cmd.number_of_blocks, 3);
+        assert!(cmd.unmap);
+        assert!(!cmd.anchor);
+        assert!(cmd.no_data_out_buffer);
+    }
+}
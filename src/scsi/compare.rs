@@ -0,0 +1,123 @@
+use core::ops::Range;
+
+use embedded_io_async::{ErrorType, Read, ReadExactError, Write};
+
+use crate::usb_mass_storage::TransportError;
+
+/// Bytes of data-out read at a time to compare
+const CHUNK_BYTES: usize = 64;
+
+/// What a block read back by VERIFY is compared with
+pub(crate) enum Expected<'a, R> {
+    /// Nothing, the block only being read
+    Nothing,
+    /// The data-out, read from the host as it's needed
+    DataOut(&'a mut R),
+    /// Bytes `start..start + bytes.len()` of the block, the rest not being compared
+    Window { bytes: &'a [u8], start: usize },
+}
+
+/// A [`Write`] for [`BlockDevice::read_to`], comparing a block as it's read back with what's
+/// expected of it, so it needn't be held in RAM
+///
+/// [`BlockDevice::read_to`]: super::BlockDevice::read_to
+pub(crate) struct Compare<'a, R> {
+    expected: Expected<'a, R>,
+    /// How far through the block
+    offset: usize,
+    pub miscompared: bool,
+    /// The data-out ran out before the block did
+    pub short: bool,
+}
+
+impl<'a, R> Compare<'a, R> {
+    pub fn new(expected: Expected<'a, R>) -> Self {
+        Self {
+            expected,
+            offset: 0,
+            miscompared: false,
+            short: false,
+        }
+    }
+}
+
+impl<R> ErrorType for Compare<'_, R> {
+    type Error = TransportError;
+}
+
+impl<R: Read<Error = TransportError>> Write for Compare<'_, R> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        match &mut self.expected {
+            Expected::Nothing => {}
+            Expected::DataOut(data) => {
+                let mut expected = [0u8; CHUNK_BYTES];
+                for chunk in buf.chunks(CHUNK_BYTES) {
+                    if self.short {
+                        break;
+                    }
+                    let expected = &mut expected[..chunk.len()];
+                    match data.read_exact(expected).await {
+                        Ok(()) => self.miscompared |= chunk != expected,
+                        Err(ReadExactError::UnexpectedEof) => self.short = true,
+                        Err(ReadExactError::Other(e)) => return Err(e),
+                    }
+                }
+            }
+            Expected::Window { bytes, start } => {
+                if let Some((in_buf, in_window)) = overlap(self.offset, buf.len(), *start, bytes) {
+                    self.miscompared |= buf[in_buf] != bytes[in_window];
+                }
+            }
+        }
+        self.offset += buf.len();
+        Ok(buf.len())
+    }
+}
+
+/// A [`Write`] for [`BlockDevice::read_to`], keeping bytes `start..start + bytes.len()` of the
+/// block read back, to compare other blocks with a [`Expected::Window`]
+///
+/// [`BlockDevice::read_to`]: super::BlockDevice::read_to
+pub(crate) struct Capture<'a> {
+    bytes: &'a mut [u8],
+    start: usize,
+    /// How far through the block
+    offset: usize,
+}
+
+impl<'a> Capture<'a> {
+    pub fn new(bytes: &'a mut [u8], start: usize) -> Self {
+        Self {
+            bytes,
+            start,
+            offset: 0,
+        }
+    }
+}
+
+impl ErrorType for Capture<'_> {
+    type Error = TransportError;
+}
+
+impl Write for Capture<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if let Some((in_buf, in_window)) = overlap(self.offset, buf.len(), self.start, self.bytes) {
+            self.bytes[in_window].copy_from_slice(&buf[in_buf]);
+        }
+        self.offset += buf.len();
+        Ok(buf.len())
+    }
+}
+
+/// Where `len` bytes at `offset` of a block overlap the window of `window` at `start`: the
+/// ranges of each that do, if any
+fn overlap(
+    offset: usize,
+    len: usize,
+    start: usize,
+    window: &[u8],
+) -> Option<(Range<usize>, Range<usize>)> {
+    let from = offset.max(start);
+    let to = (offset + len).min(start + window.len());
+    (from < to).then(|| (from - offset..to - offset, from - start..to - start))
+}
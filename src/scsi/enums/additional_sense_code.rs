@@ -1,78 +1,149 @@
-// There are many more variants (see asc-num.txt) but these are the ones the scsi code
-// currently uses
+use num_enum::TryFromPrimitive;
+
+/// The additional sense code (ASC) and qualifier (ASCQ) of sense data, which narrow down the
+/// [`SenseKey`](super::SenseKey)
+///
+/// Each discriminant is the ASC and ASCQ together, big-endian, as they appear in the sense data.
+/// There are many more codes (see SPC-4 annex D), these are the ones a block device is likely to
+/// need.
 #[repr(u16)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Default)]
+#[derive(TryFromPrimitive, Clone, Copy, Debug, PartialEq, Eq, defmt::Format, Default)]
 pub enum AdditionalSenseCode {
-    /// ASC 0x20, ASCQ: 0x0 - INVALID COMMAND OPERATION CODE
-    InvalidCommandOperationCode,
-    /// ASC 0x64, ASCQ: 0x1 - INVALID PACKET SIZE
-    InvalidPacketSize,
-    /// ASC 0x24, ASCQ: 0x0 - INVALID FIELD IN CDB
-    InvalidFieldInCdb,
     /// ASC 0x0, ASCQ: 0x0 - NO ADDITIONAL SENSE INFORMATION
     #[default]
-    NoAdditionalSenseInformation,
+    NoAdditionalSenseInformation = 0x0000,
+    /// ASC 0x4, ASCQ: 0x0 - LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE
+    LogicalUnitNotReadyCauseNotReportable = 0x0400,
+    /// ASC 0x4, ASCQ: 0x1 - LOGICAL UNIT IS IN PROCESS OF BECOMING READY
+    LogicalUnitIsInProcessOfBecomingReady = 0x0401,
+    /// ASC 0x4, ASCQ: 0x2 - LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED
+    LogicalUnitNotReadyInitializingCommandRequired = 0x0402,
+    /// ASC 0x4, ASCQ: 0x3 - LOGICAL UNIT NOT READY, MANUAL INTERVENTION REQUIRED
+    LogicalUnitNotReadyManualInterventionRequired = 0x0403,
+    /// ASC 0x4, ASCQ: 0x4 - LOGICAL UNIT NOT READY, FORMAT IN PROGRESS
+    LogicalUnitNotReadyFormatInProgress = 0x0404,
+    /// ASC 0x4, ASCQ: 0x9 - LOGICAL UNIT NOT READY, SELF-TEST IN PROGRESS
+    LogicalUnitNotReadySelfTestInProgress = 0x0409,
+    /// ASC 0x8, ASCQ: 0x0 - LOGICAL UNIT COMMUNICATION FAILURE
+    LogicalUnitCommunicationFailure = 0x0800,
+    /// ASC 0x8, ASCQ: 0x1 - LOGICAL UNIT COMMUNICATION TIME-OUT
+    LogicalUnitCommunicationTimeOut = 0x0801,
     /// ASC 0xC, ASCQ: 0x0 - WRITE ERROR
-    WriteError,
-    /// ASC 0x51, ASCQ: 0x0 - ERASE FAILURE
-    EraseFailure,
+    WriteError = 0x0C00,
+    /// ASC 0xC, ASCQ: 0x2 - WRITE ERROR - AUTO REALLOCATION FAILED
+    WriteErrorAutoReallocationFailed = 0x0C02,
+    /// ASC 0x10, ASCQ: 0x0 - ID CRC OR ECC ERROR
+    IdCrcOrEccError = 0x1000,
+    /// ASC 0x11, ASCQ: 0x0 - UNRECOVERED READ ERROR
+    UnrecoveredReadError = 0x1100,
+    /// ASC 0x14, ASCQ: 0x1 - RECORD NOT FOUND
+    RecordNotFound = 0x1401,
+    /// ASC 0x1A, ASCQ: 0x0 - PARAMETER LIST LENGTH ERROR
+    ParameterListLengthError = 0x1A00,
+    /// ASC 0x1D, ASCQ: 0x0 - MISCOMPARE DURING VERIFY OPERATION
+    MiscompareDuringVerifyOperation = 0x1D00,
+    /// ASC 0x20, ASCQ: 0x0 - INVALID COMMAND OPERATION CODE
+    InvalidCommandOperationCode = 0x2000,
     /// ASC 0x21, ASCQ: 0x0 - LOGICAL BLOCK ADDRESS OUT OF RANGE
-    LogicalBlockAddressOutOfRange,
+    LogicalBlockAddressOutOfRange = 0x2100,
+    /// ASC 0x24, ASCQ: 0x0 - INVALID FIELD IN CDB
+    InvalidFieldInCdb = 0x2400,
+    /// ASC 0x25, ASCQ: 0x0 - LOGICAL UNIT NOT SUPPORTED
+    LogicalUnitNotSupported = 0x2500,
+    /// ASC 0x26, ASCQ: 0x0 - INVALID FIELD IN PARAMETER LIST
+    InvalidFieldInParameterList = 0x2600,
+    /// ASC 0x26, ASCQ: 0x1 - PARAMETER NOT SUPPORTED
+    ParameterNotSupported = 0x2601,
+    /// ASC 0x26, ASCQ: 0x2 - PARAMETER VALUE INVALID
+    ParameterValueInvalid = 0x2602,
+    /// ASC 0x27, ASCQ: 0x0 - WRITE PROTECTED
+    WriteProtected = 0x2700,
+    /// ASC 0x27, ASCQ: 0x1 - HARDWARE WRITE PROTECTED
+    HardwareWriteProtected = 0x2701,
+    /// ASC 0x27, ASCQ: 0x2 - LOGICAL UNIT SOFTWARE WRITE PROTECTED
+    LogicalUnitSoftwareWriteProtected = 0x2702,
+    /// ASC 0x28, ASCQ: 0x0 - NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    NotReadyToReadyChange = 0x2800,
+    /// ASC 0x29, ASCQ: 0x0 - POWER ON, RESET, OR BUS DEVICE RESET OCCURRED
+    PowerOnResetOrBusDeviceResetOccurred = 0x2900,
+    /// ASC 0x29, ASCQ: 0x1 - POWER ON OCCURRED
+    PowerOnOccurred = 0x2901,
+    /// ASC 0x29, ASCQ: 0x3 - BUS DEVICE RESET FUNCTION OCCURRED
+    BusDeviceResetFunctionOccurred = 0x2903,
+    /// ASC 0x2A, ASCQ: 0x0 - PARAMETERS CHANGED
+    ParametersChanged = 0x2A00,
+    /// ASC 0x2A, ASCQ: 0x1 - MODE PARAMETERS CHANGED
+    ModeParametersChanged = 0x2A01,
+    /// ASC 0x2A, ASCQ: 0x9 - CAPACITY DATA HAS CHANGED
+    CapacityDataHasChanged = 0x2A09,
+    /// ASC 0x2C, ASCQ: 0x0 - COMMAND SEQUENCE ERROR
+    CommandSequenceError = 0x2C00,
+    /// ASC 0x2F, ASCQ: 0x0 - COMMANDS CLEARED BY ANOTHER INITIATOR
+    CommandsClearedByAnotherInitiator = 0x2F00,
+    /// ASC 0x30, ASCQ: 0x0 - INCOMPATIBLE MEDIUM INSTALLED
+    IncompatibleMediumInstalled = 0x3000,
+    /// ASC 0x31, ASCQ: 0x0 - MEDIUM FORMAT CORRUPTED
+    MediumFormatCorrupted = 0x3100,
+    /// ASC 0x31, ASCQ: 0x1 - FORMAT COMMAND FAILED
+    FormatCommandFailed = 0x3101,
+    /// ASC 0x39, ASCQ: 0x0 - SAVING PARAMETERS NOT SUPPORTED
+    SavingParametersNotSupported = 0x3900,
+    /// ASC 0x3A, ASCQ: 0x0 - MEDIUM NOT PRESENT
+    MediumNotPresent = 0x3A00,
+    /// ASC 0x3A, ASCQ: 0x1 - MEDIUM NOT PRESENT - TRAY CLOSED
+    MediumNotPresentTrayClosed = 0x3A01,
+    /// ASC 0x3A, ASCQ: 0x2 - MEDIUM NOT PRESENT - TRAY OPEN
+    MediumNotPresentTrayOpen = 0x3A02,
+    /// ASC 0x3E, ASCQ: 0x3 - LOGICAL UNIT FAILED SELF-TEST
+    LogicalUnitFailedSelfTest = 0x3E03,
+    /// ASC 0x44, ASCQ: 0x0 - INTERNAL TARGET FAILURE
+    InternalTargetFailure = 0x4400,
+    /// ASC 0x4B, ASCQ: 0x0 - DATA PHASE ERROR
+    DataPhaseError = 0x4B00,
+    /// ASC 0x51, ASCQ: 0x0 - ERASE FAILURE
+    EraseFailure = 0x5100,
+    /// ASC 0x53, ASCQ: 0x0 - MEDIA LOAD OR EJECT FAILED
+    MediaLoadOrEjectFailed = 0x5300,
+    /// ASC 0x53, ASCQ: 0x2 - MEDIUM REMOVAL PREVENTED
+    MediumRemovalPrevented = 0x5302,
+    /// ASC 0x5A, ASCQ: 0x1 - OPERATOR MEDIUM REMOVAL REQUEST
+    OperatorMediumRemovalRequest = 0x5A01,
+    /// ASC 0x5D, ASCQ: 0x0 - FAILURE PREDICTION THRESHOLD EXCEEDED
+    FailurePredictionThresholdExceeded = 0x5D00,
+    /// ASC 0x64, ASCQ: 0x1 - INVALID PACKET SIZE
+    InvalidPacketSize = 0x6401,
 }
 
 #[allow(dead_code)]
 impl AdditionalSenseCode {
     /// Returns the ASC code for this variant
     pub fn asc(&self) -> u8 {
-        match self {
-            AdditionalSenseCode::InvalidCommandOperationCode => 32,
-            AdditionalSenseCode::InvalidPacketSize => 100,
-            AdditionalSenseCode::InvalidFieldInCdb => 36,
-            AdditionalSenseCode::NoAdditionalSenseInformation => 0,
-            AdditionalSenseCode::WriteError => 12,
-            AdditionalSenseCode::EraseFailure => 81,
-            AdditionalSenseCode::LogicalBlockAddressOutOfRange => 33,
-        }
+        (*self as u16 >> 8) as u8
     }
+
     /// Returns the ASCQ code for this variant
     pub fn ascq(&self) -> u8 {
-        match self {
-            AdditionalSenseCode::InvalidCommandOperationCode => 0,
-            AdditionalSenseCode::InvalidPacketSize => 1,
-            AdditionalSenseCode::InvalidFieldInCdb => 0,
-            AdditionalSenseCode::NoAdditionalSenseInformation => 0,
-            AdditionalSenseCode::WriteError => 0,
-            AdditionalSenseCode::EraseFailure => 0,
-            AdditionalSenseCode::LogicalBlockAddressOutOfRange => 0,
-        }
-    }
-    /// Returns the ASCQ code for this variant
-    pub fn from(asc: u8, ascq: u8) -> core::option::Option<Self> {
-        match (asc, ascq) {
-            (32, 0) => Some(AdditionalSenseCode::InvalidCommandOperationCode),
-            (100, 1) => Some(AdditionalSenseCode::InvalidPacketSize),
-            (36, 0) => Some(AdditionalSenseCode::InvalidFieldInCdb),
-            (0, 0) => Some(AdditionalSenseCode::NoAdditionalSenseInformation),
-            (12, 0) => Some(AdditionalSenseCode::WriteError),
-            (81, 0) => Some(AdditionalSenseCode::EraseFailure),
-            (33, 0) => Some(AdditionalSenseCode::LogicalBlockAddressOutOfRange),
-            _ => None,
-        }
+        *self as u16 as u8
     }
-}
 
-#[derive(Debug)]
-pub enum AdditionalSenseCodeError {
-    InvalidEnumDiscriminant,
+    /// Returns the variant for an ASC and ASCQ, if it's one we know
+    pub fn from(asc: u8, ascq: u8) -> Option<Self> {
+        Self::try_from(u16::from_be_bytes([asc, ascq])).ok()
+    }
 }
 
-impl TryFrom<u16> for AdditionalSenseCode {
-    type Error = AdditionalSenseCodeError;
-
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
-        let asc = (value >> 8) as u8;
-        let ascq = value as u8;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Self::from(asc, ascq).ok_or(AdditionalSenseCodeError::InvalidEnumDiscriminant)
+    #[test]
+    fn asc_and_ascq() {
+        let code = AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress;
+        assert_eq!((code.asc(), code.ascq()), (0x04, 0x04));
+        assert_eq!(
+            AdditionalSenseCode::from(0x3A, 0x00),
+            Some(AdditionalSenseCode::MediumNotPresent)
+        );
+        assert_eq!(AdditionalSenseCode::from(0x3A, 0x7F), None);
     }
 }
@@ -0,0 +1,20 @@
+use num_enum::TryFromPrimitive;
+
+/// Diagnostic pages sent with SEND DIAGNOSTIC and returned by RECEIVE DIAGNOSTIC RESULTS
+/// (SPC-4 7.2)
+#[repr(u8)]
+#[derive(TryFromPrimitive, Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum DiagnosticPage {
+    #[default]
+    SupportedDiagnosticPages = 0x00,
+    /// Vendor specific: sending it runs the self-test, whose outcome is then received
+    SelfTestResults = 0x80,
+}
+
+impl DiagnosticPage {
+    /// Every page we support, in ascending order as the supported pages page lists them
+    pub const ALL: [DiagnosticPage; 2] = [
+        DiagnosticPage::SupportedDiagnosticPages,
+        DiagnosticPage::SelfTestResults,
+    ];
+}
@@ -33,3 +33,9 @@ pub use spc_version::*;
 
 mod response_data_format;
 pub use response_data_format::*;
+
+mod vpd_page;
+pub use vpd_page::*;
+
+mod diagnostic_page;
+pub use diagnostic_page::*;
@@ -12,6 +12,7 @@ pub enum OpCode {
     Inquiry = 0x12,
     ReadCapacity10 = 0x25,
     Read10 = 0x28,
+    ReceiveDiagnosticResults = 0x1C,
     SendDiagnostic = 0x1D,
     ReportLuns = 0xA0,
 
@@ -25,8 +26,16 @@ pub enum OpCode {
     Write10 = 0x2A,
     Verify10 = 0x2F,
     SynchronizeCache10 = 0x35,
+    WriteSame10 = 0x41,
+    Unmap = 0x42,
     ReadTocPmaAtip = 0x43,
     ModeSelect10 = 0x55,
     Read12 = 0xA8,
     Write12 = 0xAA,
+    Read16 = 0x88,
+    Write16 = 0x8A,
+    Verify16 = 0x8F,
+    SynchronizeCache16 = 0x91,
+    WriteSame16 = 0x93,
+    ServiceActionIn16 = 0x9E,
 }
@@ -0,0 +1,25 @@
+use num_enum::TryFromPrimitive;
+
+/// Vital product data pages returned by INQUIRY with EVPD set (SPC-4 7.8, SBC-3 6.5)
+#[repr(u8)]
+#[derive(TryFromPrimitive, Clone, Copy, Eq, PartialEq, Debug)]
+pub enum VpdPage {
+    SupportedVpdPages = 0x00,
+    UnitSerialNumber = 0x80,
+    DeviceIdentification = 0x83,
+    BlockLimits = 0xB0,
+    BlockDeviceCharacteristics = 0xB1,
+    LogicalBlockProvisioning = 0xB2,
+}
+
+impl VpdPage {
+    /// Every page we support, in ascending order as the supported pages page lists them
+    pub const ALL: [VpdPage; 6] = [
+        VpdPage::SupportedVpdPages,
+        VpdPage::UnitSerialNumber,
+        VpdPage::DeviceIdentification,
+        VpdPage::BlockLimits,
+        VpdPage::BlockDeviceCharacteristics,
+        VpdPage::LogicalBlockProvisioning,
+    ];
+}
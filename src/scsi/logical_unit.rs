@@ -0,0 +1,1545 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{error, info};
+use embedded_io_async::{Read, Write};
+use overlay::Overlay;
+
+use crate::{
+    bulk_only_transport::{CommandError, DataPhase},
+    usb_mass_storage::TransportError,
+};
+
+use super::{
+    commands::*,
+    compare::{Capture, Compare, Expected},
+    enums::{AdditionalSenseCode, DiagnosticPage, PageControl, SenseKey, SpcVersion, VpdPage},
+    mode_pages::ModePages,
+    removable_medium::MediumRequest,
+    responses::*,
+    sense::Sense,
+    write_response, BlockDevice, BlockDeviceError, Error, Fill, TransferError,
+    DEFAULT_BUFFER_BYTES, MODE_PARAMETERS_BYTES,
+};
+
+/// A logical unit presented to the host: a [`BlockDevice`] along with how it identifies itself
+/// and its sense data
+///
+/// Every logical unit of a target has the same `BlockDevice` type. To mix backends, implement
+/// `BlockDevice` for an enum of them.
+///
+/// The `BlockDevice` is the logical unit's medium, which may be ejected and another inserted.
+/// While there's none, commands that access the medium fail with NOT READY, MEDIUM NOT PRESENT.
+pub struct LogicalUnit<'bd, BD: BlockDevice> {
+    block_device: Option<&'bd mut BD>,
+    inquiry_response: InquiryResponse,
+    serial_number: Option<&'bd [u8]>,
+    sense: Sense,
+    read_only: bool,
+    write_protect: Option<&'bd WriteProtect>,
+    mode_pages: ModePages,
+    /// `None` if the host hasn't saved any
+    saved_mode_pages: Option<ModePages>,
+    mode_pages_loaded: bool,
+    /// Reported in place of the next command other than INQUIRY or REQUEST SENSE, so the host
+    /// knows to forget what it read from the medium
+    unit_attention: Option<AdditionalSenseCode>,
+    /// The host has the medium mounted, and asked it not be ejected
+    removal_prevented: bool,
+    /// Not yet passed on to the [`RemovableMedium`](super::RemovableMedium)
+    medium_request: Option<MediumRequest>,
+    /// Since the medium was last formatted, if it wasn't done in one go
+    format: Option<Format>,
+    /// How the medium's last self-test went, if it's had one
+    self_test: Option<Result<(), BlockDeviceError>>,
+    /// Returned by RECEIVE DIAGNOSTIC RESULTS, unless it asks for another
+    diagnostic_page: DiagnosticPage,
+}
+
+/// How a FORMAT UNIT that returned before it finished is getting on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Format {
+    /// Formatting in the background, from this block
+    InProgress(u64),
+    /// The medium is unusable until it's formatted again
+    Failed,
+}
+
+/// Longest serial number, so the device identification page fits in [`VPD_PAGE_MAX_BYTES`]
+const SERIAL_NUMBER_MAX_LEN: usize = 32;
+
+/// Longest VPD page, the block limits and block device characteristics pages
+const VPD_PAGE_MAX_BYTES: usize = 64;
+
+/// Longest mode parameter list: the longer header, a long LBA block descriptor and every page
+const MODE_PARAMETER_LIST_MAX_BYTES: usize =
+    ModeParameterHeader10::BYTE_LEN + LongLbaBlockDescriptor::BYTE_LEN + ModePages::BYTE_LEN;
+
+impl<'bd, BD: BlockDevice> LogicalUnit<'bd, BD> {
+    /// Creates a new logical unit, removable and writable
+    ///
+    /// `block_device` provides reading and writing of blocks to the underlying filesystem
+    ///
+    /// `vendor_identification` is an ASCII string that forms part of the SCSI inquiry response.
+    ///      Should come from [t10](https://www.t10.org/lists/2vid.htm). Any semi-unique non-blank
+    ///      string should work fine for local development. Panics if > 8 characters are supplied.
+    ///
+    /// `product_identification` is an ASCII string that forms part of the SCSI inquiry response.
+    ///      Vendor (probably you...) defined so pick whatever you want. Panics if > 16 characters
+    ///      are supplied.
+    ///
+    /// `product_revision_level` is an ASCII string that forms part of the SCSI inquiry response.
+    ///      Vendor (probably you...) defined so pick whatever you want. Typically a version number.
+    ///      Panics if > 4 characters are supplied.
+    pub fn new(
+        block_device: &'bd mut BD,
+        vendor_identification: &[u8; 8],
+        product_identification: &[u8; 16],
+        product_revision_level: &[u8; 4],
+    ) -> Self {
+        let mut inquiry_response = InquiryResponse::default();
+        inquiry_response.set_vendor_identification(vendor_identification);
+        inquiry_response.set_product_identification(product_identification);
+        inquiry_response.set_product_revision_level(product_revision_level);
+
+        inquiry_response.set_version(SpcVersion::Spc2); // we are compliant (???)
+
+        Self {
+            block_device: Some(block_device),
+            inquiry_response,
+            serial_number: None,
+            sense: Default::default(),
+            read_only: false,
+            write_protect: None,
+            mode_pages: ModePages::defaults(BD::CACHES_WRITES),
+            saved_mode_pages: None,
+            mode_pages_loaded: false,
+            unit_attention: Some(AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred),
+            removal_prevented: false,
+            medium_request: None,
+            format: None,
+            self_test: None,
+            diagnostic_page: DiagnosticPage::default(),
+        }
+    }
+
+    /// Replace the medium with `block_device`, returning the one it replaces, if any. The host is
+    /// told the medium may have changed by a unit attention
+    ///
+    /// The mode pages return to their defaults, or whatever was saved to the new medium.
+    pub fn insert_medium(&mut self, block_device: &'bd mut BD) -> Option<&'bd mut BD> {
+        let ejected = self.block_device.replace(block_device);
+        self.mode_pages = ModePages::defaults(BD::CACHES_WRITES);
+        self.saved_mode_pages = None;
+        self.mode_pages_loaded = false;
+        self.format = None;
+        self.self_test = None;
+        self.raise_unit_attention(AdditionalSenseCode::NotReadyToReadyChange);
+        ejected
+    }
+
+    /// Remove the medium, returning it. Until another is inserted, the logical unit is not ready
+    pub fn eject_medium(&mut self) -> Option<&'bd mut BD> {
+        // a format in progress is abandoned along with the medium
+        self.format = None;
+        self.block_device.take()
+    }
+
+    /// Whether there's a medium to read and write
+    pub fn has_medium(&self) -> bool {
+        self.block_device.is_some()
+    }
+
+    /// Whether the host has asked that the medium isn't ejected, while it's mounted
+    pub fn removal_prevented(&self) -> bool {
+        self.removal_prevented
+    }
+
+    /// What the host last asked of the medium, since this was last called
+    pub(crate) fn take_medium_request(&mut self) -> Option<MediumRequest> {
+        self.medium_request.take()
+    }
+
+    /// The host reset the target: the next command is failed with a unit attention, and the
+    /// medium may be removed
+    pub(crate) fn reset(&mut self) {
+        self.sense = Sense::default();
+        self.raise_unit_attention(AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred);
+        if self.removal_prevented {
+            self.removal_prevented = false;
+            self.medium_request = Some(MediumRequest::AllowRemoval);
+        }
+    }
+
+    /// Only one unit attention is kept. A reset outranks a medium change, as the host starts
+    /// afresh after either
+    fn raise_unit_attention(&mut self, code: AdditionalSenseCode) {
+        if self.unit_attention != Some(AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred) {
+            self.unit_attention = Some(code);
+        }
+    }
+
+    /// Whether the host should treat the medium as removable
+    pub fn set_removable(&mut self, removable: bool) {
+        self.inquiry_response.set_removable_medium(removable);
+    }
+
+    /// `serial_number` is an ASCII string identifying this logical unit, reported in the unit
+    ///      serial number and device identification VPD pages. Hosts use it to recognise the
+    ///      device, so it should be unique. Panics if > 32 characters are supplied.
+    pub fn set_serial_number(&mut self, serial_number: &'bd [u8]) {
+        assert!(serial_number.len() <= SERIAL_NUMBER_MAX_LEN);
+        self.serial_number = Some(serial_number);
+    }
+
+    /// Whether writes from the host are rejected
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Also reject writes while `write_protect` is set, for a switch that can be flipped while the
+    /// target is running
+    pub fn set_write_protect(&mut self, write_protect: &'bd WriteProtect) {
+        self.write_protect = Some(write_protect);
+    }
+
+    /// Whether writes from the host are currently rejected, either by
+    /// [`set_read_only`](Self::set_read_only) or the [`WriteProtect`] switch
+    pub fn read_only(&self) -> bool {
+        self.read_only || self.write_protect.is_some_and(WriteProtect::is_protected)
+    }
+
+    /// Process `command`, addressed to this logical unit
+    pub(crate) async fn handle(
+        &mut self,
+        command: Command,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        if !self.mode_pages_loaded {
+            self.load_mode_pages().await;
+        }
+        // sense describes the command before, so is lost if the host doesn't ask for it straight away
+        if !matches!(command, Command::RequestSense(_)) {
+            self.sense = Sense::default();
+        }
+
+        // a unit attention is reported once, either by REQUEST SENSE or by failing the command
+        // (SPC-4 5.14). INQUIRY goes ahead regardless, so the host can still identify us
+        let mut reporting_unit_attention = false;
+        if let Some(code) = self.unit_attention {
+            match command {
+                Command::Inquiry(_) => {}
+                Command::RequestSense(_) => {
+                    self.unit_attention = None;
+                    self.sense = Sense::new(SenseKey::UnitAttention, code);
+                    reporting_unit_attention = true;
+                }
+                _ => {
+                    error!("unit attention: {}", code);
+                    self.unit_attention = None;
+                    self.set_sense(SenseKey::UnitAttention, code);
+                    return Err(CommandError::Failed);
+                }
+            }
+        }
+
+        // while formatting only INQUIRY goes ahead, REQUEST SENSE reporting the progress once
+        // any unit attention has been reported
+        if let Some(progress) = self.format_progress().filter(|_| !reporting_unit_attention) {
+            let sense = Sense {
+                progress: Some(progress),
+                ..Sense::new(
+                    SenseKey::NotReady,
+                    AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress,
+                )
+            };
+            match command {
+                Command::Inquiry(_) => {}
+                Command::RequestSense(_) => self.sense = sense,
+                _ => {
+                    error!("format in progress");
+                    self.sense = sense;
+                    return Err(CommandError::Failed);
+                }
+            }
+        }
+
+        match command {
+            Command::Write(WriteXCommand {
+                lba: lba_start,
+                transfer_length,
+                fua,
+            }) => {
+                self.medium()?;
+                self.check_writable()?;
+                self.check_lba_range(lba_start, transfer_length)?;
+
+                self.medium()?
+                    .write_from(lba_start, transfer_length, data)
+                    .await
+                    .map_err(|e| self.transfer_error(e))?;
+
+                // with the write cache disabled, every write is forced to the medium
+                if fua || !self.mode_pages.caching.write_cache_enabled() {
+                    self.flush().await?;
+                }
+                Ok(())
+            }
+            Command::ReadCapacity(_read_capacity10) => {
+                // too large to report, the host should use READ CAPACITY(16) instead
+                let max_lba = u32::try_from(self.medium()?.block_count()).unwrap_or(u32::MAX);
+                let block_size = BD::BLOCK_BYTES as u32;
+                let mut cap = ReadCapacity10Response::new();
+
+                cap.set_max_lba(max_lba);
+                cap.set_block_size(block_size);
+
+                write_response(data, cap.as_bytes(), ReadCapacity10Response::BYTE_LEN).await
+            }
+            Command::ReadCapacity16(read_capacity16) => {
+                let mut cap = ReadCapacity16Response::new();
+
+                cap.set_max_lba(&self.medium()?.block_count().to_be_bytes());
+                cap.set_block_size(BD::BLOCK_BYTES as u32);
+                cap.set_logical_block_provisioning_management_enabled(BD::DISCARDS);
+
+                let allocation_length = read_capacity16.allocation_length() as usize;
+                write_response(data, cap.as_bytes(), allocation_length).await
+            }
+
+            Command::Read(ReadXCommand {
+                lba: lba_start,
+                transfer_length,
+            }) => {
+                // transfer_length == number of blocks to read
+                self.check_lba_range(lba_start, transfer_length)?;
+
+                self.medium()?
+                    .read_to(lba_start, transfer_length, data)
+                    .await
+                    .map_err(|e| self.transfer_error(e))
+            }
+            Command::Inquiry(inquiry) if inquiry.enable_vital_product_data() => {
+                self.vital_product_data(inquiry, data).await
+            }
+            Command::Inquiry(inquiry) => {
+                if inquiry.page_code() != 0 {
+                    error!("INQUIRY page code {} without EVPD", inquiry.page_code());
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
+                }
+
+                let response = &self.inquiry_response.as_bytes()[..InquiryResponse::MINIMUM_SIZE];
+                write_response(data, response, inquiry.allocation_length() as usize).await
+            }
+            Command::RequestSense(request_sense) => {
+                // reported once, after which there's nothing to report
+                let sense = core::mem::take(&mut self.sense);
+                sense.respond(request_sense, data).await
+            }
+            Command::ModeSense(mode_sense) => self.mode_sense(mode_sense, data).await,
+            Command::ModeSelect(mode_select) => self.mode_select(mode_select, data).await,
+            Command::ReadFormatCapacities(read_format_capacities) => {
+                self.read_format_capacities(read_format_capacities, data)
+                    .await
+            }
+            Command::PreventAllowMediumRemoval(prevent_allow) => {
+                // the other values are obsolete, for medium changers
+                let (prevent, request) = match prevent_allow.prevent() {
+                    0b00 => (false, MediumRequest::AllowRemoval),
+                    0b01 => (true, MediumRequest::PreventRemoval),
+                    prevent => {
+                        error!("PREVENT ALLOW MEDIUM REMOVAL with prevent {}", prevent);
+                        self.set_sense(
+                            SenseKey::IllegalRequest,
+                            AdditionalSenseCode::InvalidFieldInCdb,
+                        );
+                        return Err(CommandError::Failed);
+                    }
+                };
+                self.removal_prevented = prevent;
+                self.medium_request = Some(request);
+                Ok(())
+            }
+            Command::TestUnitReady(_) => {
+                // ready whenever there's a formatted medium, any unit attention having been
+                // reported above
+                self.medium()?;
+                self.check_formatted()
+            }
+            Command::StartStopUnit(start_stop_unit) => self.start_stop_unit(start_stop_unit).await,
+            Command::SynchronizeCache(SynchronizeCacheXCommand {
+                lba,
+                number_of_blocks,
+                ..
+            }) => {
+                // zero blocks is to the end of the medium, which is flushed all the same
+                self.check_lba_range(lba, number_of_blocks.max(1))?;
+                self.flush().await
+            }
+            Command::ReportLuns(_) => {
+                unreachable!("REPORT LUNS is addressed to the target, not a logical unit")
+            }
+            Command::Verify(verify) => self.verify(verify, data).await,
+            Command::Unmap(unmap) => self.unmap(unmap, data).await,
+            Command::WriteSame(write_same) => self.write_same(write_same, data).await,
+            Command::Format(format) => self.format_unit(format, data).await,
+            Command::SendDiagnostic(send_diagnostic) => {
+                self.send_diagnostic(send_diagnostic, data).await
+            }
+            Command::ReceiveDiagnosticResults(receive_diagnostic_results) => {
+                self.receive_diagnostic_results(receive_diagnostic_results, data)
+                    .await
+            }
+        }
+    }
+
+    /// We've no power conditions to change, so START STOP UNIT only ejects or loads the medium
+    async fn start_stop_unit(&mut self, command: StartStopUnitCommand) -> Result<(), CommandError> {
+        // LOEJ only counts when the power condition isn't being changed
+        if !command.load_eject() || command.power_condition() != 0 {
+            return Ok(());
+        }
+
+        if command.start() {
+            if self.block_device.is_none() {
+                // the firmware may insert one, which the host will learn of by a unit attention
+                self.medium_request = Some(MediumRequest::Load);
+                self.medium()?;
+            }
+        } else if self.block_device.is_some() {
+            if self.removal_prevented {
+                error!("eject while medium removal is prevented");
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::MediumRemovalPrevented,
+                );
+                return Err(CommandError::Failed);
+            }
+            // nothing may be lost once the host has been told it can take the medium
+            self.flush().await?;
+            self.medium_request = Some(MediumRequest::Eject);
+        }
+        Ok(())
+    }
+
+    /// Start formatting the medium, returning once it's done or, if the parameter list asks,
+    /// straight away, leaving it to carry on in the background
+    async fn format_unit(
+        &mut self,
+        command: FormatCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        if command.format_protection_information() != 0 {
+            error!("FORMAT UNIT with protection information");
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        }
+        self.medium()?;
+        self.check_writable()?;
+
+        // without a parameter list, the defaults: no defect list, and status once it's done
+        let mut immediate = false;
+        if command.format_data() {
+            let mut buf = [0u8; LongFormatParameterListHeader::BYTE_LEN];
+            let len = match command.long_list() {
+                true => LongFormatParameterListHeader::BYTE_LEN,
+                false => ShortFormatParameterListHeader::BYTE_LEN,
+            };
+            let header = &mut buf[..len];
+            data.read_exact(header)
+                .await
+                .map_err(|e| self.transfer_error(e.into()))?;
+
+            // we've no defects to list, nor a pattern to initialize blocks with
+            let (initialization_pattern, defect_list_length) = if command.long_list() {
+                let header = LongFormatParameterListHeader::overlay(header).unwrap();
+                immediate = header.immediate();
+                (header.initialization_pattern(), header.defect_list_length())
+            } else {
+                let header = ShortFormatParameterListHeader::overlay(header).unwrap();
+                immediate = header.immediate();
+                (
+                    header.initialization_pattern(),
+                    header.defect_list_length() as u32,
+                )
+            };
+            if initialization_pattern || defect_list_length != 0 {
+                error!(
+                    "unsupported FORMAT UNIT parameters, IP {} defect list {} bytes",
+                    initialization_pattern, defect_list_length
+                );
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::InvalidFieldInParameterList,
+                );
+                return Err(CommandError::Failed);
+            }
+        }
+
+        info!("formatting, immediate {}", immediate);
+        self.format = Some(Format::InProgress(0));
+        if immediate {
+            return Ok(());
+        }
+        while self.format_step().await {}
+        if self.format == Some(Format::Failed) {
+            self.set_sense(
+                SenseKey::MediumError,
+                AdditionalSenseCode::FormatCommandFailed,
+            );
+            return Err(CommandError::Failed);
+        }
+        Ok(())
+    }
+
+    /// Format the next few blocks of the medium, if it's being formatted, returning whether
+    /// there are more to format
+    ///
+    /// A step cut short is made again from the start, as the format only moves on once it's done.
+    pub(crate) async fn format_step(&mut self) -> bool {
+        let Some(Format::InProgress(lba)) = self.format else {
+            return false;
+        };
+        let Some(block_device) = self.block_device.as_deref_mut() else {
+            return false;
+        };
+
+        let end = block_device.block_count().saturating_add(1);
+        let count = (BD::FORMAT_BLOCKS as u64).min(end - lba);
+        let mut result = block_device.format(lba, count as u32).await;
+        if result.is_ok() && lba + count == end {
+            result = block_device.flush().await;
+        }
+
+        self.format = match result {
+            Ok(()) if lba + count == end => {
+                info!("format complete");
+                None
+            }
+            Ok(()) => Some(Format::InProgress(lba + count)),
+            Err(e) => {
+                error!("formatting lba {} failed: {}", lba, e);
+                Some(Format::Failed)
+            }
+        };
+        matches!(self.format, Some(Format::InProgress(_)))
+    }
+
+    /// How far formatting in the background has got, out of 65536, if it's in progress
+    fn format_progress(&self) -> Option<u16> {
+        let Some(Format::InProgress(lba)) = self.format else {
+            return None;
+        };
+        let blocks = self.block_device.as_deref()?.block_count() as u128 + 1;
+        Some((lba as u128 * 0x1_0000 / blocks) as u16)
+    }
+
+    /// Fail a command that accesses the medium's blocks after formatting it failed
+    fn check_formatted(&mut self) -> Result<(), CommandError> {
+        if self.format == Some(Format::Failed) {
+            error!("medium format corrupted");
+            self.set_sense(
+                SenseKey::MediumError,
+                AdditionalSenseCode::MediumFormatCorrupted,
+            );
+            return Err(CommandError::Failed);
+        }
+        Ok(())
+    }
+
+    /// Respond to READ FORMAT CAPACITIES with the medium's capacity, and the one capacity it can
+    /// be formatted to, truncated to the allocation length
+    ///
+    /// Hosts ask whether or not there's a medium, so this doesn't fail without one.
+    async fn read_format_capacities(
+        &mut self,
+        command: ReadFormatCapacitiesCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        const LEN: usize = CapacityListHeader::BYTE_LEN + 2 * CapacityDescriptor::BYTE_LEN;
+        let mut buf = [0u8; LEN];
+        let mut len = CapacityListHeader::BYTE_LEN;
+
+        let mut current = CapacityDescriptor::new();
+        current.set_block_length(BD::BLOCK_BYTES as u32);
+        let mut formattable = None;
+        match self.block_device.as_deref() {
+            Some(block_device) => {
+                let blocks = block_device.block_count().saturating_add(1);
+                let blocks = u32::try_from(blocks).unwrap_or(u32::MAX);
+                current.set_number_of_blocks(blocks);
+                current.set_descriptor_code(match self.format {
+                    Some(Format::Failed) => CapacityDescriptor::UNFORMATTED_MEDIA,
+                    _ => CapacityDescriptor::FORMATTED_MEDIA,
+                });
+
+                let mut descriptor = CapacityDescriptor::new();
+                descriptor.set_number_of_blocks(blocks);
+                descriptor.set_block_length(BD::BLOCK_BYTES as u32);
+                formattable = Some(descriptor);
+            }
+            // we can't know what might be inserted
+            None => current.set_descriptor_code(CapacityDescriptor::NO_MEDIA_PRESENT),
+        }
+
+        for descriptor in core::iter::once(current).chain(formattable) {
+            buf[len..len + CapacityDescriptor::BYTE_LEN].copy_from_slice(descriptor.as_bytes());
+            len += CapacityDescriptor::BYTE_LEN;
+        }
+        let mut header = CapacityListHeader::new();
+        header.set_capacity_list_length((len - CapacityListHeader::BYTE_LEN) as u8);
+        buf[..CapacityListHeader::BYTE_LEN].copy_from_slice(header.as_bytes());
+
+        write_response(data, &buf[..len], command.allocation_length() as usize).await
+    }
+
+    /// Run the self-test, or take the diagnostic page RECEIVE DIAGNOSTIC RESULTS is to return.
+    /// Sending the self-test results page runs the self-test too
+    async fn send_diagnostic(
+        &mut self,
+        command: SendDiagnosticCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let len = command.parameter_list_length() as usize;
+        let self_test = match (command.self_test(), command.self_test_code()) {
+            (false, 0) => Some(false),
+            // the default self-test, or a foreground one, which are all the same to us
+            (true, 0)
+            | (
+                false,
+                SendDiagnosticCommand::SELF_TEST_CODE_FOREGROUND_SHORT
+                | SendDiagnosticCommand::SELF_TEST_CODE_FOREGROUND_EXTENDED,
+            ) => Some(true),
+            // the background self-tests, and aborting them, aren't supported
+            _ => None,
+        };
+        // a self-test has no parameter list, which otherwise holds a diagnostic page
+        let valid = match self_test {
+            Some(true) => len == 0,
+            Some(false) => len == 0 || command.page_format(),
+            None => false,
+        };
+        if !valid {
+            error!(
+                "unsupported SEND DIAGNOSTIC, SELFTEST {} self-test code {} PF {}",
+                command.self_test(),
+                command.self_test_code(),
+                command.page_format()
+            );
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        }
+        if self_test == Some(true) {
+            return self.run_self_test().await;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        // our pages are only headers, having no parameters
+        let mut header = [0u8; DiagnosticPageHeader::BYTE_LEN];
+        if len != header.len() {
+            error!("diagnostic page of {} bytes", len);
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::ParameterListLengthError,
+            );
+            return Err(CommandError::Failed);
+        }
+        data.read_exact(&mut header)
+            .await
+            .map_err(|e| self.transfer_error(e.into()))?;
+        let header = DiagnosticPageHeader::overlay(&header).unwrap();
+        let Ok(page) = DiagnosticPage::try_from(header.page_code()) else {
+            error!("unsupported diagnostic page {}", header.page_code());
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInParameterList,
+            );
+            return Err(CommandError::Failed);
+        };
+
+        self.diagnostic_page = page;
+        match page {
+            DiagnosticPage::SupportedDiagnosticPages => Ok(()),
+            DiagnosticPage::SelfTestResults => self.run_self_test().await,
+        }
+    }
+
+    /// Check the medium is healthy, keeping the outcome for RECEIVE DIAGNOSTIC RESULTS
+    async fn run_self_test(&mut self) -> Result<(), CommandError> {
+        let result = self.medium()?.self_test().await;
+        self.self_test = Some(result);
+        if let Err(e) = result {
+            error!("self-test failed: {}", e);
+            self.set_sense(
+                SenseKey::HardwareError,
+                AdditionalSenseCode::LogicalUnitFailedSelfTest,
+            );
+            return Err(CommandError::Failed);
+        }
+        info!("self-test passed");
+        Ok(())
+    }
+
+    /// Respond to RECEIVE DIAGNOSTIC RESULTS with the page it asks for, or the page last sent
+    /// with SEND DIAGNOSTIC, truncated to the allocation length
+    async fn receive_diagnostic_results(
+        &mut self,
+        command: ReceiveDiagnosticResultsCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let page = match command.page_code_valid() {
+            true => DiagnosticPage::try_from(command.page_code()).ok(),
+            false => Some(self.diagnostic_page),
+        };
+        let Some(page) = page else {
+            error!("unsupported diagnostic page {}", command.page_code());
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        };
+
+        const LEN: usize = DiagnosticPageHeader::BYTE_LEN + SelfTestResults::BYTE_LEN;
+        let mut buf = [0u8; LEN];
+        let header_len = DiagnosticPageHeader::BYTE_LEN;
+        let len = match (page, self.self_test) {
+            (DiagnosticPage::SupportedDiagnosticPages, _) => {
+                for (i, page) in DiagnosticPage::ALL.into_iter().enumerate() {
+                    buf[header_len + i] = page as u8;
+                }
+                header_len + DiagnosticPage::ALL.len()
+            }
+            // empty until there's been a self-test
+            (DiagnosticPage::SelfTestResults, None) => header_len,
+            (DiagnosticPage::SelfTestResults, Some(result)) => {
+                let mut results = SelfTestResults::new();
+                match result {
+                    Ok(()) => {
+                        results.set_self_test_results(SelfTestResults::COMPLETED_WITHOUT_ERROR)
+                    }
+                    Err(e) => {
+                        let sense = Sense::from(e);
+                        results.set_self_test_results(SelfTestResults::FAILED);
+                        results.set_sense_key(sense.key);
+                        results.set_additional_sense_code(sense.code);
+                    }
+                }
+                buf[header_len..].copy_from_slice(results.as_bytes());
+                header_len + SelfTestResults::BYTE_LEN
+            }
+        };
+
+        let mut header = DiagnosticPageHeader::new();
+        header.set_page_code(page as u8);
+        header.set_page_length((len - header_len) as u16);
+        buf[..header_len].copy_from_slice(header.as_bytes());
+
+        write_response(data, &buf[..len], command.allocation_length() as usize).await
+    }
+
+    /// Discard the ranges of blocks listed in the UNMAP parameter list, one at a time as they're
+    /// read
+    async fn unmap(
+        &mut self,
+        command: UnmapCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        if command.anchor() {
+            error!("UNMAP with ANCHOR");
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        }
+        self.medium()?;
+        self.check_writable()?;
+
+        let len = command.parameter_list_length() as usize;
+        if len == 0 {
+            // nothing to unmap
+            return Ok(());
+        }
+        let mut header = [0u8; UnmapParameterListHeader::BYTE_LEN];
+        if len < header.len() {
+            error!("UNMAP parameter list of {} bytes is too short", len);
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::ParameterListLengthError,
+            );
+            return Err(CommandError::Failed);
+        }
+        data.read_exact(&mut header)
+            .await
+            .map_err(|e| self.transfer_error(e.into()))?;
+        let header = UnmapParameterListHeader::overlay(&header).unwrap();
+
+        // a descriptor cut short is ignored
+        let descriptors_len = (header.block_descriptor_data_length() as usize)
+            .min(len - UnmapParameterListHeader::BYTE_LEN);
+        for _ in 0..descriptors_len / UnmapBlockDescriptor::BYTE_LEN {
+            let mut descriptor = [0u8; UnmapBlockDescriptor::BYTE_LEN];
+            data.read_exact(&mut descriptor)
+                .await
+                .map_err(|e| self.transfer_error(e.into()))?;
+            let descriptor = UnmapBlockDescriptor::overlay(&descriptor).unwrap();
+            let lba = u64::from_be_bytes(*descriptor.lba());
+            self.discard(lba, descriptor.number_of_blocks()).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the one block of data-out to every block in the range, or discard them if the host
+    /// allows it and the medium can
+    ///
+    /// Discarded blocks read back as anything, not the block sent, which is what most targets do.
+    /// Blocks larger than [`DEFAULT_BUFFER_BYTES`] can't be held, so are only written if they're a
+    /// single byte repeated.
+    async fn write_same(
+        &mut self,
+        command: WriteSameXCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let WriteSameXCommand {
+            lba: lba_start,
+            number_of_blocks,
+            unmap,
+            anchor,
+            no_data_out_buffer,
+        } = command;
+        if anchor {
+            error!("unsupported WRITE SAME with ANCHOR");
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        }
+        self.medium()?;
+        self.check_writable()?;
+        self.check_lba_range(lba_start, number_of_blocks.max(1))?;
+
+        // zero blocks is to the end of the medium
+        let end = match number_of_blocks {
+            0 => self.medium()?.block_count().saturating_add(1),
+            count => lba_start + count as u64,
+        };
+
+        // discarded blocks aren't written, though the block is still sent
+        let discarding = unmap && BD::DISCARDS;
+
+        // the block, unless it's a single byte over and over, which needn't be held to be written
+        let mut buf = [0u8; DEFAULT_BUFFER_BYTES];
+        let fill = if no_data_out_buffer {
+            Some(0)
+        } else if BD::BLOCK_BYTES <= DEFAULT_BUFFER_BYTES {
+            data.read_exact(&mut buf[..BD::BLOCK_BYTES])
+                .await
+                .map_err(|e| self.transfer_error(e.into()))?;
+            None
+        } else {
+            // too large to hold, so it can only be written if it's a single byte over and over,
+            // as when zeroing, which is what WRITE SAME is mostly for
+            let mut fill = None;
+            let mut uniform = true;
+            for start in (0..BD::BLOCK_BYTES).step_by(DEFAULT_BUFFER_BYTES) {
+                let chunk = &mut buf[..DEFAULT_BUFFER_BYTES.min(BD::BLOCK_BYTES - start)];
+                data.read_exact(chunk)
+                    .await
+                    .map_err(|e| self.transfer_error(e.into()))?;
+                let byte = *fill.get_or_insert(chunk[0]);
+                uniform &= chunk.iter().all(|&b| b == byte);
+            }
+            if !uniform && !discarding {
+                error!(
+                    "WRITE SAME of a {} byte block that isn't one byte repeated",
+                    BD::BLOCK_BYTES
+                );
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::InvalidFieldInParameterList,
+                );
+                return Err(CommandError::Failed);
+            }
+            fill
+        };
+
+        let mut lba = lba_start;
+        if discarding {
+            while lba < end {
+                let blocks = (end - lba).min(u32::MAX as u64) as u32;
+                self.discard(lba, blocks).await?;
+                lba += blocks as u64;
+            }
+            return Ok(());
+        }
+
+        if let Some(byte) = fill {
+            while lba < end {
+                let blocks = (end - lba).min(u32::MAX as u64) as u32;
+                match self
+                    .medium()?
+                    .write_from(lba, blocks, &mut Fill(byte))
+                    .await
+                {
+                    Ok(()) => {}
+                    Err(TransferError::BlockDevice(e)) => {
+                        error!("write same: writing from lba {} failed: {}", lba, e);
+                        self.set_sense_from_blockdev_error(e);
+                        return Err(CommandError::Failed);
+                    }
+                    Err(TransferError::UnexpectedEof | TransferError::Io(_)) => {
+                        unreachable!("the fill doesn't run out")
+                    }
+                }
+                lba += blocks as u64;
+            }
+        } else {
+            // as many copies of the block as fit, written a buffer at a time
+            let blocks_per_buffer = DEFAULT_BUFFER_BYTES / BD::BLOCK_BYTES;
+            for i in 1..blocks_per_buffer {
+                buf.copy_within(..BD::BLOCK_BYTES, i * BD::BLOCK_BYTES);
+            }
+            while lba < end {
+                let blocks = (blocks_per_buffer as u64).min(end - lba);
+                let blocks_bytes = &buf[..blocks as usize * BD::BLOCK_BYTES];
+                if let Err(e) = self.medium()?.write_blocks(lba, blocks_bytes).await {
+                    error!("write same: writing lba {} failed: {}", lba, e);
+                    self.set_sense_from_blockdev_error(e);
+                    return Err(CommandError::Failed);
+                }
+                lba += blocks;
+            }
+        }
+
+        // with the write cache disabled, every write is forced to the medium
+        if !self.mode_pages.caching.write_cache_enabled() {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Discard `count` blocks from `lba`, if the medium can. Otherwise they're kept as they are,
+    /// unmapping being only a hint
+    async fn discard(&mut self, lba: u64, count: u32) -> Result<(), CommandError> {
+        self.check_lba_range(lba, count)?;
+        if !BD::DISCARDS || count == 0 {
+            return Ok(());
+        }
+        if let Err(e) = self.medium()?.discard(lba, count).await {
+            error!("discarding lba {} + {} blocks failed: {}", lba, count, e);
+            self.set_sense_from_blockdev_error(e);
+            return Err(CommandError::Failed);
+        }
+        Ok(())
+    }
+
+    /// Read back the blocks to be verified, comparing them with the data-out if BYTCHK asks. A
+    /// block that can't be read, or doesn't match, is reported in the sense's information
+    async fn verify<T: Read<Error = TransportError> + Write<Error = TransportError>>(
+        &mut self,
+        command: VerifyXCommand,
+        data: &mut DataPhase<'_, T>,
+    ) -> Result<(), CommandError> {
+        let VerifyXCommand {
+            lba: lba_start,
+            verification_length,
+            byte_check,
+        } = command;
+        if !matches!(
+            byte_check,
+            VerifyXCommand::BYTE_CHECK_MEDIUM
+                | VerifyXCommand::BYTE_CHECK_EACH_BLOCK
+                | VerifyXCommand::BYTE_CHECK_SINGLE_BLOCK
+        ) {
+            error!("unsupported VERIFY, BYTCHK {}", byte_check);
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        }
+        self.check_lba_range(lba_start, verification_length)?;
+
+        // the single block is only sent once, so the blocks after the first are compared with
+        // the first, which matched it, a window at a time
+        let mut window = [0u8; DEFAULT_BUFFER_BYTES];
+        let window_len = DEFAULT_BUFFER_BYTES.min(BD::BLOCK_BYTES);
+
+        for lba in lba_start..lba_start + verification_length as u64 {
+            if byte_check == VerifyXCommand::BYTE_CHECK_SINGLE_BLOCK && lba > lba_start {
+                for start in (0..BD::BLOCK_BYTES).step_by(window_len) {
+                    let window = &mut window[..window_len.min(BD::BLOCK_BYTES - start)];
+                    // a window of the whole block need only be read once
+                    if window.len() < BD::BLOCK_BYTES || lba == lba_start + 1 {
+                        self.read_back(lba_start, &mut Capture::new(window, start))
+                            .await?;
+                    }
+                    let expected = Expected::Window {
+                        bytes: window,
+                        start,
+                    };
+                    self.compare(lba, Compare::<DataPhase<T>>::new(expected))
+                        .await?;
+                }
+                continue;
+            }
+
+            let expected = match byte_check {
+                VerifyXCommand::BYTE_CHECK_MEDIUM => Expected::Nothing,
+                _ => Expected::DataOut(&mut *data),
+            };
+            self.compare(lba, Compare::new(expected)).await?;
+        }
+        Ok(())
+    }
+
+    /// Read back block `lba` for VERIFY, failing if it doesn't match what's expected of it
+    async fn compare<R: Read<Error = TransportError>>(
+        &mut self,
+        lba: u64,
+        mut compare: Compare<'_, R>,
+    ) -> Result<(), CommandError> {
+        self.read_back(lba, &mut compare).await?;
+        if compare.short {
+            return Err(self.transfer_error(TransferError::UnexpectedEof));
+        }
+        if compare.miscompared {
+            error!("verify: lba {} miscompared", lba);
+            self.set_sense(
+                SenseKey::Miscompare,
+                AdditionalSenseCode::MiscompareDuringVerifyOperation,
+            );
+            self.sense.information = Some(lba);
+            return Err(CommandError::Failed);
+        }
+        Ok(())
+    }
+
+    /// Read block `lba` to `to`, for VERIFY. A block that can't be read is reported in the
+    /// sense's information
+    async fn read_back<W: Write<Error = TransportError>>(
+        &mut self,
+        lba: u64,
+        to: &mut W,
+    ) -> Result<(), CommandError> {
+        match self.medium()?.read_to(lba, 1, to).await {
+            Ok(()) => Ok(()),
+            Err(TransferError::BlockDevice(e)) => {
+                error!("verify: reading lba {} failed: {}", lba, e);
+                self.set_sense_from_blockdev_error(e);
+                self.sense.information = Some(lba);
+                Err(CommandError::Failed)
+            }
+            Err(e) => Err(self.transfer_error(e)),
+        }
+    }
+
+    /// Respond to INQUIRY with EVPD set with the requested vital product data page, truncated to
+    /// the allocation length
+    async fn vital_product_data(
+        &mut self,
+        inquiry: InquiryCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let Ok(page_code) = VpdPage::try_from(inquiry.page_code()) else {
+            error!("unsupported VPD page {}", inquiry.page_code());
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        };
+
+        let mut buf = [0u8; VPD_PAGE_MAX_BYTES];
+        let header_len = VpdPageHeader::BYTE_LEN;
+        // without a serial number, the spec has us report spaces
+        let serial_number = self.serial_number.unwrap_or(b"        ");
+
+        let len = match page_code {
+            VpdPage::SupportedVpdPages => {
+                for (i, page) in VpdPage::ALL.into_iter().enumerate() {
+                    buf[header_len + i] = page as u8;
+                }
+                header_len + VpdPage::ALL.len()
+            }
+            VpdPage::UnitSerialNumber => {
+                buf[header_len..header_len + serial_number.len()].copy_from_slice(serial_number);
+                header_len + serial_number.len()
+            }
+            VpdPage::DeviceIdentification => {
+                // a single designator: the vendor, product and serial number
+                let vendor = self.inquiry_response.vendor_identification();
+                let product = self.inquiry_response.product_identification();
+                let designator_len = vendor.len() + product.len() + serial_number.len();
+
+                let mut descriptor = DesignationDescriptorHeader::new();
+                descriptor.set_code_set(DesignationDescriptorHeader::CODE_SET_ASCII);
+                descriptor.set_association(DesignationDescriptorHeader::ASSOCIATION_LOGICAL_UNIT);
+                descriptor.set_designator_type(
+                    DesignationDescriptorHeader::DESIGNATOR_TYPE_T10_VENDOR_ID,
+                );
+                descriptor.set_designator_length(designator_len as u8);
+
+                let mut len = header_len;
+                for part in [
+                    descriptor.as_bytes(),
+                    &vendor[..],
+                    &product[..],
+                    serial_number,
+                ] {
+                    buf[len..len + part.len()].copy_from_slice(part);
+                    len += part.len();
+                }
+                len
+            }
+            VpdPage::BlockLimits => {
+                let mut page = BlockLimitsPage::new();
+                // READ and WRITE are streamed, so are only limited by the CBW's transfer length
+                page.set_maximum_transfer_length(u32::MAX / BD::BLOCK_BYTES as u32);
+                if BD::DISCARDS {
+                    // UNMAP's block descriptors are streamed too, so there's no limit
+                    page.set_maximum_unmap_lba_count(u32::MAX);
+                    page.set_maximum_unmap_block_descriptor_count(u32::MAX);
+                }
+                buf.copy_from_slice(page.as_bytes());
+                BlockLimitsPage::BYTE_LEN
+            }
+            VpdPage::BlockDeviceCharacteristics => {
+                let mut page = BlockDeviceCharacteristicsPage::new();
+                page.set_medium_rotation_rate(BlockDeviceCharacteristicsPage::NON_ROTATING_MEDIUM);
+                buf.copy_from_slice(page.as_bytes());
+                BlockDeviceCharacteristicsPage::BYTE_LEN
+            }
+            VpdPage::LogicalBlockProvisioning => {
+                let mut page = LogicalBlockProvisioningPage::new();
+                page.set_unmap(BD::DISCARDS);
+                page.set_write_same_16_unmap(BD::DISCARDS);
+                page.set_write_same_10_unmap(BD::DISCARDS);
+                page.set_provisioning_type(
+                    LogicalBlockProvisioningPage::PROVISIONING_TYPE_RESOURCE,
+                );
+                let len = LogicalBlockProvisioningPage::BYTE_LEN;
+                buf[..len].copy_from_slice(page.as_bytes());
+                len
+            }
+        };
+
+        let mut header = VpdPageHeader::new();
+        header.set_peripheral_qualifier(
+            self.inquiry_response
+                .peripheral_qualifier()
+                .unwrap_or_default(),
+        );
+        header.set_peripheral_device_type(
+            self.inquiry_response
+                .peripheral_device_type()
+                .unwrap_or_default(),
+        );
+        header.set_page_code(page_code);
+        header.set_page_length((len - header_len) as u16);
+        buf[..header_len].copy_from_slice(header.as_bytes());
+
+        write_response(data, &buf[..len], inquiry.allocation_length() as usize).await
+    }
+
+    /// Respond to MODE SENSE(6) or (10) with the header, a block descriptor unless disabled, and
+    /// the requested page(s), truncated to the allocation length
+    async fn mode_sense(
+        &mut self,
+        command: ModeSenseXCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        // we have no subpages, but all pages may be asked for along with all their subpages
+        let page_code = match PageCode::try_from(command.page_code) {
+            Ok(PageCode::AllPages) if matches!(command.subpage_code, 0x00 | 0xFF) => {
+                PageCode::AllPages
+            }
+            Ok(page_code) if command.subpage_code == 0 => page_code,
+            _ => {
+                error!(
+                    "unsupported mode page {}, subpage {}",
+                    command.page_code, command.subpage_code
+                );
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::InvalidFieldInCdb,
+                );
+                return Err(CommandError::Failed);
+            }
+        };
+
+        let changeable = command.page_control == PageControl::ChangeableValues;
+        let mut pages = match command.page_control {
+            PageControl::CurrentValues => self.mode_pages,
+            PageControl::ChangeableValues => ModePages::changeable(BD::CACHES_WRITES),
+            PageControl::DefaultValues => ModePages::defaults(BD::CACHES_WRITES),
+            // the defaults, until the host saves some
+            PageControl::SavedValues if BD::SAVES_MODE_PARAMETERS => self
+                .saved_mode_pages
+                .unwrap_or(ModePages::defaults(BD::CACHES_WRITES)),
+            PageControl::SavedValues => {
+                error!("saved mode pages requested");
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::SavingParametersNotSupported,
+                );
+                return Err(CommandError::Failed);
+            }
+        };
+
+        pages.set_parameters_saveable(BD::SAVES_MODE_PARAMETERS);
+
+        let mut buf = [0u8; MODE_PARAMETER_LIST_MAX_BYTES];
+        let header_len = match command.command_length {
+            CommandLength::C6 => ModeParameterHeader6::BYTE_LEN,
+            CommandLength::C10 => ModeParameterHeader10::BYTE_LEN,
+        };
+        let mut len = header_len;
+
+        // none of the block descriptor can be changed, so its changeable values are all zero
+        let blocks = self.block_device.as_deref().map_or(0, |block_device| {
+            block_device.block_count().saturating_add(1)
+        });
+        let long_lba = command.long_lba_accepted && u32::try_from(blocks).is_err();
+        if !command.disable_block_descriptors {
+            let mut long = LongLbaBlockDescriptor::new();
+            let mut short = ShortLbaBlockDescriptor::new();
+            let descriptor = if long_lba {
+                if !changeable {
+                    long.set_number_of_blocks(&blocks.to_be_bytes());
+                    long.set_block_length(BD::BLOCK_BYTES as u32);
+                }
+                &long.as_bytes()[..]
+            } else {
+                if !changeable {
+                    short.set_number_of_blocks(u32::try_from(blocks).unwrap_or(u32::MAX));
+                    short.set_block_length(BD::BLOCK_BYTES as u32);
+                }
+                &short.as_bytes()[..]
+            };
+            buf[len..len + descriptor.len()].copy_from_slice(descriptor);
+            len += descriptor.len();
+        }
+        let block_descriptor_len = len - header_len;
+
+        let page_codes = match page_code {
+            PageCode::AllPages => &ModePages::PAGE_CODES[..],
+            _ => core::slice::from_ref(&page_code),
+        };
+        for &page_code in page_codes {
+            let page = pages.page(page_code).unwrap();
+            buf[len..len + page.len()].copy_from_slice(page);
+            len += page.len();
+        }
+
+        match command.command_length {
+            CommandLength::C6 => {
+                let mut header = ModeParameterHeader6::default();
+                header.set_mode_data_length(len as u8 - 1);
+                let device_specific_parameter = header.device_specific_parameter_mut();
+                device_specific_parameter.set_write_protect(self.read_only());
+                device_specific_parameter
+                    .set_disable_page_out_and_force_unit_access_available(BD::CACHES_WRITES);
+                header.set_block_descriptor_length(block_descriptor_len as u8);
+                buf[..header_len].copy_from_slice(header.as_bytes());
+            }
+            CommandLength::C10 => {
+                let mut header = ModeParameterHeader10::default();
+                header.set_mode_data_length(len as u16 - 2);
+                let device_specific_parameter = header.device_specific_parameter_mut();
+                device_specific_parameter.set_write_protect(self.read_only());
+                device_specific_parameter
+                    .set_disable_page_out_and_force_unit_access_available(BD::CACHES_WRITES);
+                header.set_long_lba(long_lba && !command.disable_block_descriptors);
+                header.set_block_descriptor_length(block_descriptor_len as u16);
+                buf[..header_len].copy_from_slice(header.as_bytes());
+            }
+        }
+
+        write_response(data, &buf[..len], command.allocation_length as usize).await
+    }
+
+    /// Apply the mode pages in a MODE SELECT parameter list to the current values, and save them
+    /// if asked
+    async fn mode_select(
+        &mut self,
+        command: ModeSelectXCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let len = command.parameter_list_length as usize;
+        if len == 0 {
+            // nothing to change
+            return Ok(());
+        }
+        if !command.page_format || (command.save_pages && !BD::SAVES_MODE_PARAMETERS) {
+            error!(
+                "unsupported MODE SELECT, PF {} SP {}",
+                command.page_format, command.save_pages
+            );
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInCdb,
+            );
+            return Err(CommandError::Failed);
+        }
+
+        let mut buf = [0u8; MODE_PARAMETER_LIST_MAX_BYTES];
+        let Some(parameters) = buf.get_mut(..len) else {
+            error!("mode parameter list of {} bytes is too long", len);
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::ParameterListLengthError,
+            );
+            return Err(CommandError::Failed);
+        };
+        data.read_exact(parameters)
+            .await
+            .map_err(|e| self.transfer_error(e.into()))?;
+
+        let header = match command.command_length {
+            CommandLength::C6 => ModeParameterHeader6::overlay(parameters).ok().map(|h| {
+                let len = h.block_descriptor_length() as usize;
+                (ModeParameterHeader6::BYTE_LEN, len, false)
+            }),
+            CommandLength::C10 => ModeParameterHeader10::overlay(parameters).ok().map(|h| {
+                let len = h.block_descriptor_length() as usize;
+                (ModeParameterHeader10::BYTE_LEN, len, h.long_lba())
+            }),
+        };
+        let Some((header_len, descriptors_len, long_lba)) =
+            header.filter(|(header_len, descriptors_len, _)| header_len + descriptors_len <= len)
+        else {
+            error!("mode parameter list of {} bytes is too short", len);
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::ParameterListLengthError,
+            );
+            return Err(CommandError::Failed);
+        };
+
+        let (descriptors, pages) = parameters[header_len..].split_at(descriptors_len);
+        let write_cache_was_enabled = self.mode_pages.caching.write_cache_enabled();
+        if !Self::block_descriptors_valid(descriptors, long_lba)
+            || !self.mode_pages.select(pages, BD::CACHES_WRITES)
+        {
+            error!("invalid mode parameters");
+            self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidFieldInParameterList,
+            );
+            return Err(CommandError::Failed);
+        }
+
+        // writes cached so far are made durable, as all those after will be
+        if write_cache_was_enabled && !self.mode_pages.caching.write_cache_enabled() {
+            self.flush().await?;
+        }
+
+        if command.save_pages {
+            let pages = self.mode_pages;
+            if let Err(e) = self.medium()?.save_mode_parameters(&pages.to_bytes()).await {
+                error!("saving mode parameters failed: {}", e);
+                self.set_sense_from_blockdev_error(e);
+                return Err(CommandError::Failed);
+            }
+            self.saved_mode_pages = Some(pages);
+        }
+        Ok(())
+    }
+
+    /// MODE SELECT can't change the medium's blocks, but its block descriptor may restate them
+    fn block_descriptors_valid(descriptors: &[u8], long_lba: bool) -> bool {
+        let block_length = match (descriptors.len(), long_lba) {
+            (0, _) => return true,
+            (8, false) => ShortLbaBlockDescriptor::overlay(descriptors).map(|d| d.block_length()),
+            (16, true) => LongLbaBlockDescriptor::overlay(descriptors).map(|d| d.block_length()),
+            _ => return false,
+        };
+        matches!(block_length, Ok(len) if len == 0 || len as usize == BD::BLOCK_BYTES)
+    }
+
+    /// Restore the mode pages the host saved, which are the current values until it changes them
+    async fn load_mode_pages(&mut self) {
+        // they're saved on the medium, so are loaded once there is one
+        let Some(block_device) = self.block_device.as_deref_mut() else {
+            return;
+        };
+        self.mode_pages_loaded = true;
+        if !BD::SAVES_MODE_PARAMETERS {
+            return;
+        }
+
+        let mut bytes = [0; MODE_PARAMETERS_BYTES];
+        match block_device.load_mode_parameters(&mut bytes).await {
+            Ok(true) => match ModePages::from_bytes(&bytes) {
+                Some(pages) => {
+                    self.mode_pages = pages;
+                    self.saved_mode_pages = Some(pages);
+                }
+                None => error!("saved mode parameters are invalid, using the defaults"),
+            },
+            Ok(false) => {}
+            Err(e) => error!("loading mode parameters failed: {}", e),
+        }
+    }
+
+    pub(crate) fn set_sense(&mut self, key: SenseKey, code: AdditionalSenseCode) {
+        self.sense = Sense::new(key, code);
+
+        info!("sense: set to {}, {}", key, code);
+    }
+
+    pub(crate) fn set_sense_from_error(&mut self, e: Error) {
+        self.set_sense(
+            SenseKey::IllegalRequest,
+            match e {
+                Error::UnhandledOpCode => AdditionalSenseCode::InvalidCommandOperationCode,
+                Error::InsufficientDataForCommand => AdditionalSenseCode::InvalidPacketSize,
+                Error::BlockDeviceError(_) => AdditionalSenseCode::WriteError,
+            },
+        );
+    }
+
+    /// Write back any blocks the medium has cached
+    async fn flush(&mut self) -> Result<(), CommandError> {
+        if let Err(e) = self.medium()?.flush().await {
+            error!("flushing the medium failed: {}", e);
+            self.set_sense_from_blockdev_error(e);
+            return Err(CommandError::Failed);
+        }
+        Ok(())
+    }
+
+    /// Fail a command that would change the medium while it's write protected
+    fn check_writable(&mut self) -> Result<(), CommandError> {
+        if self.read_only() {
+            error!("write to read only logical unit");
+            self.set_sense(SenseKey::DataProtect, AdditionalSenseCode::WriteProtected);
+            return Err(CommandError::Failed);
+        }
+        Ok(())
+    }
+
+    /// The medium, or fail a command that accesses it when there's none
+    fn medium(&mut self) -> Result<&mut BD, CommandError> {
+        if self.block_device.is_none() {
+            error!("no medium");
+            self.set_sense(SenseKey::NotReady, AdditionalSenseCode::MediumNotPresent);
+            return Err(CommandError::Failed);
+        }
+        Ok(self.block_device.as_deref_mut().unwrap())
+    }
+
+    /// Fail a command whose blocks aren't all on the medium, before any data is transferred
+    fn check_lba_range(&mut self, lba: u64, blocks: u32) -> Result<(), CommandError> {
+        let block_count = self.medium()?.block_count();
+        self.check_formatted()?;
+        match lba.checked_add(blocks as u64) {
+            Some(end) if end <= block_count.saturating_add(1) => Ok(()),
+            _ => {
+                error!("lba {} + {} blocks is out of range", lba, blocks);
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+                );
+                Err(CommandError::Failed)
+            }
+        }
+    }
+
+    fn transfer_error(&mut self, e: TransferError<TransportError>) -> CommandError {
+        match e {
+            TransferError::BlockDevice(e) => {
+                error!("block device error: {}", e);
+                self.set_sense_from_blockdev_error(e);
+                CommandError::Failed
+            }
+            TransferError::UnexpectedEof => {
+                error!("Unexpected EOF reading block to write to device");
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::InvalidCommandOperationCode,
+                );
+                CommandError::Failed
+            }
+            TransferError::Io(e) => CommandError::TransportError(e),
+        }
+    }
+
+    fn set_sense_from_blockdev_error(&mut self, e: BlockDeviceError) {
+        let sense = Sense::from(e);
+        self.set_sense(sense.key, sense.code);
+    }
+}
+
+/// A write protect switch for one or more [`LogicalUnit`]s, shared with the firmware so it can be
+/// flipped while the host is connected: from a GPIO, say, or to protect logs while they're written
+///
+/// The host only learns of the change when it next asks, so it's best made while the medium is
+/// unmounted.
+pub struct WriteProtect(AtomicBool);
+
+impl WriteProtect {
+    pub const fn new(protected: bool) -> Self {
+        Self(AtomicBool::new(protected))
+    }
+
+    pub fn set(&self, protected: bool) {
+        self.0.store(protected, Ordering::Relaxed);
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{logical_unit, RamBlockDevice};
+
+    #[test]
+    fn inserting_a_medium_raises_unit_attention() {
+        let mut first = RamBlockDevice::<512>::new(16);
+        let mut second = RamBlockDevice::<512>::new(16);
+        let mut logical_unit = logical_unit(&mut first);
+        logical_unit.unit_attention = None;
+
+        assert!(logical_unit.eject_medium().is_some());
+        assert!(!logical_unit.has_medium());
+        assert_eq!(logical_unit.unit_attention, None);
+
+        assert!(logical_unit.insert_medium(&mut second).is_none());
+        assert_eq!(
+            logical_unit.unit_attention,
+            Some(AdditionalSenseCode::NotReadyToReadyChange)
+        );
+    }
+
+    #[test]
+    fn reset_outranks_medium_change() {
+        let mut first = RamBlockDevice::<512>::new(16);
+        let mut second = RamBlockDevice::<512>::new(16);
+        let mut logical_unit = logical_unit(&mut first);
+
+        logical_unit.reset();
+        assert!(logical_unit.insert_medium(&mut second).is_some());
+        assert_eq!(
+            logical_unit.unit_attention,
+            Some(AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred)
+        );
+    }
+}
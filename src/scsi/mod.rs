@@ -1,361 +1,255 @@
+use core::convert::Infallible;
+
 use defmt::{error, info};
+use embassy_futures::yield_now;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_usb::driver::Driver;
-use embedded_io_async::ReadExactError;
+use embedded_io_async::{Read, Write};
 
 use crate::{
-    bulk_only_transport::{self, BulkOnlyTransport, CommandBlock, CommandError},
+    bulk_only_transport::{self, BulkOnlyTransport, CommandBlock, CommandError, DataPhase},
     scsi::enums::{AdditionalSenseCode, SenseKey},
     usb_mass_storage::{endpoints::Endpoints, TransportError},
 };
 
 use self::{
     commands::*,
-    enums::{PageControl, SpcVersion},
+    enums::{PeripheralDeviceType, PeripheralQualifier},
     responses::*,
+    sense::Sense,
 };
 
 mod block_device;
 pub use block_device::*;
 
+mod logical_unit;
+pub use logical_unit::*;
+
+mod removable_medium;
+pub use removable_medium::*;
+
 mod commands;
+mod compare;
 mod enums;
+mod mode_pages;
 mod responses;
+mod sense;
 
 mod error;
 use error::Error;
 
-use self::{
-    commands::Command,
-    responses::{InquiryResponse, RequestSenseResponse},
-};
+#[cfg(test)]
+mod tests;
 
-pub struct Scsi<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex> {
+/// The most logical units a bulk-only transport device may have
+pub const MAX_LUNS: usize = 16;
+
+/// A SCSI block device (SBC) target, with `LUNS` logical units each backed by a [`BlockDevice`]
+pub struct Scsi<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex, const LUNS: usize> {
     transport: BulkOnlyTransport<'d, B, M>,
-    inquiry_response: InquiryResponse,
-    request_sense_response: RequestSenseResponse,
-    block_device: &'bd mut BD,
-    packet_size: u16,
+    logical_units: [LogicalUnit<'bd, BD>; LUNS],
+    removable_media: [Option<&'bd RemovableMedium<'bd, M, BD>>; LUNS],
 }
 
-impl<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex> Scsi<'d, 'bd, B, BD, M> {
-    /// Creates a new Scsi block device
-    ///
-    /// `block_device` provides reading and writing of blocks to the underlying filesystem
-    ///
-    /// `vendor_identification` is an ASCII string that forms part of the SCSI inquiry response.
-    ///      Should come from [t10](https://www.t10.org/lists/2vid.htm). Any semi-unique non-blank
-    ///      string should work fine for local development. Panics if > 8 characters are supplied.
+impl<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex, const LUNS: usize>
+    Scsi<'d, 'bd, B, BD, M, LUNS>
+{
+    /// Creates a new Scsi target
     ///
-    /// `product_identification` is an ASCII string that forms part of the SCSI inquiry response.
-    ///      Vendor (probably you...) defined so pick whatever you want. Panics if > 16 characters
-    ///      are supplied.
-    ///
-    /// `product_revision_level` is an ASCII string that forms part of the SCSI inquiry response.
-    ///      Vendor (probably you...) defined so pick whatever you want. Typically a version number.
-    ///      Panics if > 4 characters are supplied.
+    /// `logical_units` are addressed by their index. Panics if there are none, or more than
+    /// [`MAX_LUNS`].
     pub fn new(
         endpoints: Endpoints<'d, B, M>,
-        block_device: &'bd mut BD,
-        vendor_identification: &[u8; 8],
-        product_identification: &[u8; 16],
-        product_revision_level: &[u8; 4],
-        packet_size: u16,
-    ) -> Scsi<'d, 'bd, B, BD, M> {
-        let mut inquiry_response = InquiryResponse::default();
-        inquiry_response.set_vendor_identification(vendor_identification);
-        inquiry_response.set_product_identification(product_identification);
-        inquiry_response.set_product_revision_level(product_revision_level);
-
-        inquiry_response.set_version(SpcVersion::Spc2); // we are compliant (???)
+        logical_units: [LogicalUnit<'bd, BD>; LUNS],
+    ) -> Self {
+        assert!(
+            (1..=MAX_LUNS).contains(&LUNS),
+            "between 1 and 16 logical units are supported"
+        );
 
         Self {
             transport: BulkOnlyTransport::new(endpoints),
-            inquiry_response,
-            request_sense_response: Default::default(),
-            block_device,
-            packet_size,
+            logical_units,
+            removable_media: [None; LUNS],
         }
     }
 
+    /// The logical unit addressed by `lun`
+    pub fn logical_unit(&mut self, lun: u8) -> Option<&mut LogicalUnit<'bd, BD>> {
+        self.logical_units.get_mut(lun as usize)
+    }
+
+    /// Let the firmware insert, eject and swap the medium of the logical unit addressed by `lun`
+    /// through `removable_medium`, while the target is running. Panics if there's no such
+    /// logical unit
+    pub fn set_removable_medium(
+        &mut self,
+        lun: u8,
+        removable_medium: &'bd RemovableMedium<'bd, M, BD>,
+    ) {
+        self.removable_media[lun as usize] = Some(removable_medium);
+    }
+
+    /// Process commands from the transport forever
     pub async fn run(&mut self) -> ! {
         let mut handler = BulkHandler {
-            block_device: self.block_device,
-            inquiry_response: &self.inquiry_response,
-            request_sense_response: &mut self.request_sense_response,
-            packet_size: self.packet_size,
+            logical_units: &mut self.logical_units,
+            removable_media: &self.removable_media,
         };
         self.transport.run(&mut handler).await
     }
 }
 
-struct BulkHandler<'scsi, BD> {
-    block_device: &'scsi mut BD,
-    inquiry_response: &'scsi InquiryResponse,
-    request_sense_response: &'scsi mut RequestSenseResponse,
-    packet_size: u16,
+/// Send `response` as a command's data-in, truncated to the command's allocation length and to
+/// the data-in the host expects. Whatever the host expected but wasn't sent is the residue
+///
+/// Responses are always cut short like this rather than failing the command (SPC-4 4.3.5.6). Data
+/// the host expects in the other direction is still passed on, so the transport sees the phase
+/// error.
+pub(crate) async fn write_response(
+    data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    response: &[u8],
+    allocation_length: usize,
+) -> Result<(), CommandError> {
+    let len = response.len().min(allocation_length);
+    let len = data
+        .data_in_remaining()
+        .map_or(len, |remaining| len.min(remaining as usize));
+    data.write_all(&response[..len]).await?;
+    Ok(())
+}
+
+struct BulkHandler<'scsi, 'bd, BD: BlockDevice, M: RawMutex> {
+    logical_units: &'scsi mut [LogicalUnit<'bd, BD>],
+    removable_media: &'scsi [Option<&'bd RemovableMedium<'bd, M, BD>>],
 }
 
-impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi, BD> {
-    async fn data_transfer_from_host(
+impl<'scsi, 'bd, BD: BlockDevice, M: RawMutex> bulk_only_transport::Handler
+    for BulkHandler<'scsi, 'bd, BD, M>
+{
+    async fn handle(
         &mut self,
         cb: &CommandBlock<'_>,
-        reader: &mut impl embedded_io_async::Read<Error = TransportError>,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
     ) -> Result<(), CommandError> {
-        let command = Command::extract_from_cbw(cb).map_err(|e| {
-            error!("scsi (from-host) couldn't parse command");
-            self.set_sense_from_error(e);
-            CommandError::Invalid
-        })?;
-        info!("scsi from-host command: {}", command);
-
-        match command {
-            Command::Write(WriteXCommand {
-                lba: lba_start,
-                transfer_length,
-            }) => {
-                let lba_end = lba_start + transfer_length - 1;
-
-                for lba in lba_start..=lba_end {
-                    let mut buf = [0u8; 2048];
-                    assert!(buf.len() >= BD::BLOCK_BYTES); // TODO: almighty hack
-                    let buf = &mut buf[0..BD::BLOCK_BYTES];
-
-                    reader.read_exact(buf).await.map_err(|e| match e {
-                        ReadExactError::UnexpectedEof => {
-                            error!("Unexpected EOF reading block to write to device");
-                            self.set_sense(
-                                SenseKey::IllegalRequest,
-                                AdditionalSenseCode::InvalidCommandOperationCode,
-                            );
-                            CommandError::Failed
-                        }
-                        ReadExactError::Other(e) => CommandError::TransportError(e),
-                    })?;
+        // media are changed between commands, so the host sees a change from one to the next
+        self.apply_medium_changes().await;
+        let result = self.handle_command(cb, data).await;
+        self.report_medium_requests();
+        result
+    }
 
-                    self.block_device.write_block(lba, buf).await.map_err(|e| {
-                        error!("block device error: {}", e);
-                        self.set_sense_from_blockdev_error(e);
-                        CommandError::Failed
-                    })?;
-                }
+    fn reset(&mut self) {
+        for logical_unit in self.logical_units.iter_mut() {
+            logical_unit.reset();
+        }
+        self.report_medium_requests();
+    }
 
-                Ok(())
+    /// Formats the logical units formatting in the background, a step at a time
+    async fn background(&mut self) -> Infallible {
+        loop {
+            let mut formatting = false;
+            for logical_unit in self.logical_units.iter_mut() {
+                formatting |= logical_unit.format_step().await;
             }
-            _ => {
-                error!("invalid from-host command");
-                self.set_sense_invalid_dir();
-                Err(CommandError::Invalid)
+            if !formatting {
+                core::future::pending::<()>().await;
             }
+            // so the host's next command isn't kept waiting
+            yield_now().await;
         }
     }
-    async fn data_transfer_to_host(
+}
+
+impl<'bd, BD: BlockDevice, M: RawMutex> BulkHandler<'_, 'bd, BD, M> {
+    async fn handle_command(
         &mut self,
         cb: &CommandBlock<'_>,
-        writer: &mut impl embedded_io_async::Write<Error = TransportError>,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
     ) -> Result<(), CommandError> {
-        let command = Command::extract_from_cbw(cb).map_err(|e| {
-            error!("scsi (to-host) couldn't parse command");
-            self.set_sense_from_error(e);
+        let command = Command::extract_from_cbw(cb);
+        let lun_count = self.logical_units.len();
+
+        let Some(logical_unit) = self.logical_units.get_mut(cb.lun as usize) else {
+            return Self::handle_unsupported_lun(cb.lun, command, data).await;
+        };
+
+        let command = command.map_err(|e| {
+            error!("scsi couldn't parse command");
+            logical_unit.set_sense_from_error(e);
             CommandError::Invalid
         })?;
-        info!("scsi to-host command: {}", command);
+        info!("scsi command: lun {}: {}", cb.lun, command);
 
         match command {
-            Command::ReadCapacity(_read_capacity10) => {
-                // TODO: support read_capacity16 etc
-                let max_lba = self.block_device.block_count();
-                let block_size = BD::BLOCK_BYTES as u32;
-                let mut cap = ReadCapacity10Response::new();
-
-                cap.set_max_lba(max_lba);
-                cap.set_block_size(block_size);
-
-                writer.write_all(cap.as_bytes()).await?;
-                Ok(())
-
-                // TODO: readcap16:
-                //let mut data = [0u8; 16];
-                //let _ = &mut data[0..8].copy_from_slice(&u32::to_be_bytes(BLOCKS - 1));
-                //let _ = &mut data[8..12].copy_from_slice(&u32::to_be_bytes(BLOCK_SIZE));
-            }
-
-            Command::Read(ReadXCommand {
-                lba: lba_start,
-                transfer_length,
-            }) => {
-                // transfer_length == number of blocks to read
-                let lba_end = lba_start + transfer_length - 1;
-
-                // FIXME: what if block_size isn't a multiple of packet_size?
-                assert!(
-                    BD::BLOCK_BYTES % self.packet_size as usize == 0,
-                    "block device's block size must be a multiple of the (usb) packet size (for the current implementation)"
-                );
-
-                let mut buf = [0u8; 2048];
-                assert!(buf.len() >= BD::BLOCK_BYTES); // TODO: almighty hack
-                let buf = &mut buf[0..BD::BLOCK_BYTES];
-
-                for lba in lba_start..=lba_end {
-                    self.block_device.read_block(lba, buf).await.map_err(|e| {
-                        error!("block device error: {}", e);
-                        self.set_sense_from_blockdev_error(e);
-                        CommandError::Failed
-                    })?;
-
-                    for offset in (0..buf.len()).step_by(self.packet_size as usize) {
-                        writer
-                            .write_all(&buf[offset..offset + self.packet_size as usize])
-                            .await?;
-                    }
+            Command::ReportLuns(report_luns) => {
+                // the target's LUN inventory rather than anything specific to this logical unit.
+                // We've no well known logical units, so every report lists the same LUNs.
+                // An allocation length too short for the header and one LUN isn't allowed
+                if report_luns.select_report() > 0x02 || report_luns.allocation_length() < 16 {
+                    logical_unit.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
                 }
 
-                Ok(())
-            }
-            Command::Inquiry { .. } => {
-                // FIXME - VPD page should specify maximum transfer_length for read/write
-                let buf = &self.inquiry_response.as_bytes()[..InquiryResponse::MINIMUM_SIZE];
-
-                writer.write_all(buf).await?;
-
-                Ok(())
-            }
-            Command::RequestSense(_) => {
-                writer
-                    .write_all(self.request_sense_response.as_bytes())
-                    .await?;
-                Ok(())
-            }
-            Command::ModeSense(ModeSenseXCommand {
-                command_length: CommandLength::C6, // FIXME: handle other mode senses
-                page_control: PageControl::CurrentValues,
-            }) => {
-                let data = [
-                    0x03, // number of bytes that follow
-                    0x00, // the media type is SBC
-                    0x00, // not write-protected, no cache-control bytes support
-                    0x00, // no mode-parameter block descriptors
-                ];
-                writer.write_all(&data).await?;
-                Ok(())
-
-                /*
-                 * FIXME
-                let mut header = ModeParameterHeader6::default();
-                header.increase_length_for_page(PageCode::CachingModePage);
-
-                // Default is both caches disabled
-                let cache_page = CachingModePage::default();
-
-                let mut buf = [0u8; ModeParameterHeader6::BYTES + CachingModePage::BYTES];
-
-                header.pack(&mut buf[..ModeParameterHeader6::BYTES]).unwrap();
-                cache_page.pack(&mut buf[ModeParameterHeader6::BYTES..]).unwrap();
-                // FIXME?: original modesense6 response only had 4 bytes, none of this cache_page
-                writer.write_all(&buf).await?;
-                Ok(())
-                */
-            }
-            Command::ModeSense(_) => todo!(),
-            Command::ReadFormatCapacities(ReadFormatCapacitiesCommand { .. }) => {
-                //let mut data = [0u8; 12];
-                //let _ = &mut data[0..4].copy_from_slice(&[
-                //    0x00, 0x00, 0x00, 0x08, // capacity list length
-                //]);
-                //let _ = &mut data[4..8].copy_from_slice(&u32::to_be_bytes(BLOCKS)); // number of blocks
-                //data[8] = 0x01; //unformatted media
-                //let block_length_be = u32::to_be_bytes(BLOCK_SIZE);
-                //data[9] = block_length_be[1];
-                //data[10] = block_length_be[2];
-                //data[11] = block_length_be[3];
-                todo!()
-            }
-            _ => {
-                error!("invalid to-host command");
-                self.set_sense_invalid_dir();
-                Err(CommandError::Invalid)
+                let response = ReportLunsResponse::with_luns(lun_count);
+                let allocation_length = report_luns.allocation_length() as usize;
+                write_response(data, response.as_used_bytes(), allocation_length).await
             }
+            command => logical_unit.handle(command, data).await,
         }
     }
-    async fn no_data_transfer(&mut self, cb: &CommandBlock<'_>) -> Result<(), CommandError> {
-        let command = Command::extract_from_cbw(cb).map_err(|e| {
-            error!("scsi (no-data) couldn't parse command");
-            self.set_sense_from_error(e);
-            CommandError::Invalid
-        })?;
-        info!("scsi no-data command: {}", command);
 
-        match command {
-            Command::PreventAllowMediumRemoval(PreventAllowMediumRemovalCommand { .. }) => {
-                // TODO: pass up a level?
-                Ok(())
-            }
-            Command::TestUnitReady(_) => {
-                // TODO: after enough errors apparently the host will keep sending TUR
-                // requests and nothing else. There may be additional data in the
-                // request that indicates we should respond CommandError and prepare
-                // sense response data with more info
-                Ok(())
-            }
-            Command::StartStopUnit(StartStopUnitCommand { .. }) => Ok(()),
-            Command::Format(_)
-            | Command::ModeSelect(_)
-            | Command::ReportLuns(_)
-            | Command::SendDiagnostic(_)
-            | Command::SynchronizeCache(_)
-            | Command::Verify(_) => {
-                unimplemented!();
-            }
-            _ => {
-                error!("invalid no-data command");
-                self.set_sense_invalid_dir();
-                Err(CommandError::Invalid)
+    async fn apply_medium_changes(&mut self) {
+        for (logical_unit, removable_medium) in
+            self.logical_units.iter_mut().zip(self.removable_media)
+        {
+            if let Some(removable_medium) = removable_medium {
+                removable_medium.apply(logical_unit).await;
             }
         }
     }
-}
-
-impl<BD> BulkHandler<'_, BD> {
-    fn set_sense(&mut self, key: SenseKey, code: AdditionalSenseCode) {
-        self.request_sense_response.set_sense_key(key);
-        self.request_sense_response.set_additional_sense_code(code);
 
-        info!("sense: set to {}, {}", key, code);
+    fn report_medium_requests(&mut self) {
+        for (logical_unit, removable_medium) in
+            self.logical_units.iter_mut().zip(self.removable_media)
+        {
+            if let Some(removable_medium) = removable_medium {
+                removable_medium.report(logical_unit);
+            }
+        }
     }
 
-    fn set_sense_from_error(&mut self, e: Error) {
-        self.set_sense(
-            SenseKey::IllegalRequest,
-            match e {
-                Error::UnhandledOpCode => AdditionalSenseCode::InvalidCommandOperationCode,
-                Error::InsufficientDataForCommand => AdditionalSenseCode::InvalidPacketSize,
-                Error::BlockDeviceError(_) => AdditionalSenseCode::WriteError,
-            },
-        );
-    }
+    /// A command addressed to a logical unit that doesn't exist. Only INQUIRY and REQUEST SENSE
+    /// are answered, so the host can find out why every other command fails (SPC-4 4.6.3)
+    async fn handle_unsupported_lun(
+        lun: u8,
+        command: Result<Command, Error>,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        match command {
+            Ok(Command::Inquiry(inquiry)) => {
+                let mut response = InquiryResponse::default();
+                response.set_peripheral_qualifier(PeripheralQualifier::Incapable);
+                response.set_peripheral_device_type(PeripheralDeviceType::UnknownOrNone);
 
-    fn set_sense_from_blockdev_error(&mut self, e: BlockDeviceError) {
-        match e {
-            BlockDeviceError::WriteError => {
-                self.set_sense(
-                    SenseKey::HardwareError, // or SenseKey::MediumError
-                    AdditionalSenseCode::WriteError,
-                );
+                let response = &response.as_bytes()[..InquiryResponse::MINIMUM_SIZE];
+                write_response(data, response, inquiry.allocation_length() as usize).await
             }
-            BlockDeviceError::InvalidAddress => {
-                self.set_sense(
+            Ok(Command::RequestSense(request_sense)) => {
+                let sense = Sense::new(
                     SenseKey::IllegalRequest,
-                    AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+                    AdditionalSenseCode::LogicalUnitNotSupported,
                 );
+                sense.respond(request_sense, data).await
+            }
+            _ => {
+                error!("scsi command for unsupported lun {}", lun);
+                Err(CommandError::Failed)
             }
         }
     }
-
-    fn set_sense_invalid_dir(&mut self) {
-        self.set_sense(
-            SenseKey::IllegalRequest,
-            AdditionalSenseCode::InvalidCommandOperationCode,
-        );
-    }
 }
@@ -0,0 +1,204 @@
+use super::commands::{
+    CachingModePage, ControlModePage, InformationalExceptionsControlModePage, PageCode,
+};
+
+/// The mode pages of a logical unit, returned by MODE SENSE
+///
+/// There's one set for each [`PageControl`](super::enums::PageControl): the current values, the
+/// defaults, and a mask of the bits the host may change.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub(crate) struct ModePages {
+    pub caching: CachingModePage,
+    pub control: ControlModePage,
+    pub informational_exceptions: InformationalExceptionsControlModePage,
+}
+
+impl ModePages {
+    /// Every page, in the order they're returned for [`PageCode::AllPages`]
+    pub const PAGE_CODES: [PageCode; 3] = [
+        PageCode::CachingModePage,
+        PageCode::ControlModePage,
+        PageCode::InformationalExceptionsControlModePage,
+    ];
+
+    /// Length of all the pages together
+    pub const BYTE_LEN: usize = CachingModePage::BYTE_LEN
+        + ControlModePage::BYTE_LEN
+        + InformationalExceptionsControlModePage::BYTE_LEN;
+
+    /// The default values, with the write cache enabled if there's one to enable
+    pub fn defaults(write_cache: bool) -> Self {
+        let mut pages = Self::default();
+        pages.caching.set_write_cache_enabled(write_cache);
+        pages
+    }
+
+    /// The changeable values: a page where each bit the host may change is set. The write cache
+    /// can only be enabled or disabled if there's one
+    pub fn changeable(write_cache: bool) -> Self {
+        let mut pages = Self::default();
+        pages.caching.set_write_cache_enabled(write_cache);
+        pages.caching.set_read_cache_disable(false);
+
+        // exceptions can be disabled, and reported in any way, as there are never any to report
+        pages.informational_exceptions.set_mrie(0b1111);
+
+        pages
+    }
+
+    /// Set the PS bit of every page, which says whether it can be saved
+    pub fn set_parameters_saveable(&mut self, saveable: bool) {
+        self.caching.set_parameters_saveable(saveable);
+        self.control.set_parameters_saveable(saveable);
+        self.informational_exceptions
+            .set_parameters_saveable(saveable);
+    }
+
+    /// Apply the pages of a MODE SELECT parameter list, following the header and any block
+    /// descriptors
+    ///
+    /// Returns `false`, changing nothing, if a page isn't one of ours or would change a bit that
+    /// isn't [`changeable`](Self::changeable) with or without a `write_cache`.
+    pub fn select(&mut self, mut parameters: &[u8], write_cache: bool) -> bool {
+        let changeable = Self::changeable(write_cache);
+        let mut selected = *self;
+
+        while !parameters.is_empty() {
+            // no subpages (SPF, bit 6), and PS is reserved in MODE SELECT
+            let Some(page_code) = parameters
+                .first()
+                .filter(|&&b| b & 0xC0 == 0)
+                .and_then(|&b| PageCode::try_from(b).ok())
+            else {
+                return false;
+            };
+            let (Some(page), Some(mask)) =
+                (selected.page_mut(page_code), changeable.page(page_code))
+            else {
+                return false;
+            };
+            if parameters.len() < page.len() || parameters[1] as usize != page.len() - 2 {
+                return false;
+            }
+
+            let (new, rest) = parameters.split_at(page.len());
+            let unchangeable = new[2..]
+                .iter()
+                .zip(&page[2..])
+                .zip(&mask[2..])
+                .any(|((new, current), mask)| (new ^ current) & !mask != 0);
+            if unchangeable {
+                return false;
+            }
+            page[2..].copy_from_slice(&new[2..]);
+            parameters = rest;
+        }
+
+        *self = selected;
+        true
+    }
+
+    /// Every page, one after another, as kept by [`BlockDevice::save_mode_parameters`]
+    ///
+    /// [`BlockDevice::save_mode_parameters`]: super::BlockDevice::save_mode_parameters
+    pub fn to_bytes(self) -> [u8; Self::BYTE_LEN] {
+        let mut bytes = [0; Self::BYTE_LEN];
+        let mut len = 0;
+        for page_code in Self::PAGE_CODES {
+            let page = self.page(page_code).unwrap();
+            bytes[len..len + page.len()].copy_from_slice(page);
+            len += page.len();
+        }
+        bytes
+    }
+
+    /// The inverse of [`to_bytes`](Self::to_bytes), `None` if `bytes` doesn't hold our pages
+    pub fn from_bytes(bytes: &[u8; Self::BYTE_LEN]) -> Option<Self> {
+        let mut pages = Self::default();
+        let mut len = 0;
+        for page_code in Self::PAGE_CODES {
+            let page = pages.page_mut(page_code).unwrap();
+            let saved = &bytes[len..len + page.len()];
+            if saved[0] & 0x3F != page[0] || saved[1] != page[1] {
+                return None;
+            }
+            page[2..].copy_from_slice(&saved[2..]);
+            len += page.len();
+        }
+        Some(pages)
+    }
+
+    /// The bytes of page `page_code`, `None` for [`PageCode::AllPages`]
+    pub fn page(&self, page_code: PageCode) -> Option<&[u8]> {
+        match page_code {
+            PageCode::CachingModePage => Some(self.caching.as_bytes()),
+            PageCode::ControlModePage => Some(self.control.as_bytes()),
+            PageCode::InformationalExceptionsControlModePage => {
+                Some(self.informational_exceptions.as_bytes())
+            }
+            PageCode::AllPages => None,
+        }
+    }
+
+    fn page_mut(&mut self, page_code: PageCode) -> Option<&mut [u8]> {
+        match page_code {
+            PageCode::CachingModePage => Some(self.caching.as_bytes_mut()),
+            PageCode::ControlModePage => Some(self.control.as_bytes_mut()),
+            PageCode::InformationalExceptionsControlModePage => {
+                Some(self.informational_exceptions.as_bytes_mut())
+            }
+            PageCode::AllPages => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_only_changes_changeable_bits() {
+        let mut pages = ModePages::default();
+        let mut caching = *pages.caching.as_bytes();
+        caching[2] = 0b101;
+        assert!(pages.select(&caching, true));
+        assert!(pages.caching.write_cache_enabled());
+
+        // read cache disable can't be changed, and a failed select changes nothing
+        let mut informational_exceptions = *pages.informational_exceptions.as_bytes();
+        informational_exceptions[3] = 0b0110;
+        caching[2] = 0b000;
+        let parameters = [&informational_exceptions[..], &caching[..]].concat();
+        assert!(!pages.select(&parameters, true));
+        assert_eq!(pages.informational_exceptions.mrie(), 0);
+        assert!(pages.caching.read_cache_disable());
+    }
+
+    #[test]
+    fn write_cache_only_changeable_if_there_is_one() {
+        let mut pages = ModePages::defaults(false);
+        let mut caching = *pages.caching.as_bytes();
+        caching[2] |= 0b100;
+        assert!(!pages.select(&caching, false));
+        assert!(!pages.caching.write_cache_enabled());
+        assert!(!ModePages::changeable(false).caching.write_cache_enabled());
+    }
+
+    #[test]
+    fn select_rejects_unknown_and_short_pages() {
+        let mut pages = ModePages::default();
+        assert!(!pages.select(&[0x01, 0x0A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], true));
+        let control = *pages.control.as_bytes();
+        assert!(!pages.select(&control[..6], true));
+        assert_eq!(pages, ModePages::default());
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut pages = ModePages::default();
+        pages.caching.set_write_cache_enabled(true);
+        pages.informational_exceptions.set_mrie(6);
+        assert_eq!(ModePages::from_bytes(&pages.to_bytes()), Some(pages));
+        assert_eq!(ModePages::from_bytes(&[0; ModePages::BYTE_LEN]), None);
+    }
+}
@@ -0,0 +1,151 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::{raw::RawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+use super::{BlockDevice, LogicalUnit};
+
+/// What the host asked of a [`RemovableMedium`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum MediumRequest {
+    /// START STOP UNIT, to eject the medium. It has already been removed from the logical unit
+    /// and is returned by [`RemovableMedium::removed`]
+    Eject,
+    /// START STOP UNIT, to load a medium while there's none. Firmware may
+    /// [`insert`](RemovableMedium::insert) one in response
+    Load,
+    /// PREVENT ALLOW MEDIUM REMOVAL, while the host has the medium mounted
+    PreventRemoval,
+    /// PREVENT ALLOW MEDIUM REMOVAL, or a reset
+    AllowRemoval,
+}
+
+/// The firmware's side of a logical unit's medium, shared with a [`UsbMassStorage`] so media can
+/// be inserted, ejected and swapped while the host is connected
+///
+/// Changes are made between commands, before the next one the host sends. Hosts poll removable
+/// media with TEST UNIT READY every second or two, so it isn't long. The host then learns of the
+/// change: NOT READY while there's no medium, and a unit attention once one is inserted.
+///
+/// [`UsbMassStorage`]: crate::UsbMassStorage
+pub struct RemovableMedium<'bd, M: RawMutex, BD: BlockDevice> {
+    slot: Mutex<M, RefCell<Slot<'bd, BD>>>,
+    removed_signal: Signal<M, ()>,
+    request_signal: Signal<M, MediumRequest>,
+    removal_prevented: AtomicBool,
+}
+
+struct Slot<'bd, BD> {
+    /// Waiting for the next command. `Some(None)` ejects
+    change: Option<Option<&'bd mut BD>>,
+    removed: Option<&'bd mut BD>,
+}
+
+impl<'bd, M: RawMutex, BD: BlockDevice> RemovableMedium<'bd, M, BD> {
+    pub const fn new() -> Self {
+        Self {
+            slot: Mutex::new(RefCell::new(Slot {
+                change: None,
+                removed: None,
+            })),
+            removed_signal: Signal::new(),
+            request_signal: Signal::new(),
+            removal_prevented: AtomicBool::new(false),
+        }
+    }
+
+    /// Present `block_device` to the host, in place of any medium there already
+    ///
+    /// Replaces any change not yet made.
+    pub fn insert(&self, block_device: &'bd mut BD) {
+        self.change(Some(block_device));
+    }
+
+    /// Remove the medium, regardless of whether the host prevents its removal
+    ///
+    /// Replaces any change not yet made.
+    pub fn eject(&self) {
+        self.change(None);
+    }
+
+    /// Wait for a medium to be removed from the logical unit: by [`eject`](Self::eject), swapped
+    /// out by [`insert`](Self::insert) or ejected by the host. Only the most recently removed
+    /// medium is kept, so wait for one before making the next change
+    pub async fn removed(&self) -> &'bd mut BD {
+        loop {
+            if let Some(block_device) = self.slot.lock(|slot| slot.borrow_mut().removed.take()) {
+                return block_device;
+            }
+            self.removed_signal.wait().await;
+        }
+    }
+
+    /// Wait for the host's next request. Only the most recent is kept
+    pub async fn request(&self) -> MediumRequest {
+        self.request_signal.wait().await
+    }
+
+    /// Whether the host has asked that the medium isn't removed, while it's mounted
+    pub fn removal_prevented(&self) -> bool {
+        self.removal_prevented.load(Ordering::Relaxed)
+    }
+
+    fn change(&self, block_device: Option<&'bd mut BD>) {
+        self.slot
+            .lock(|slot| slot.borrow_mut().change = Some(block_device));
+    }
+
+    /// Make the change the firmware asked for, if any, to `logical_unit`
+    pub(crate) async fn apply(&self, logical_unit: &mut LogicalUnit<'bd, BD>) {
+        let Some(change) = self.slot.lock(|slot| slot.borrow_mut().change.take()) else {
+            return;
+        };
+        let removed = match change {
+            Some(block_device) => {
+                info!("medium inserted");
+                logical_unit.insert_medium(block_device)
+            }
+            None => {
+                info!("medium ejected");
+                logical_unit.eject_medium()
+            }
+        };
+        if let Some(removed) = removed {
+            // the host wasn't asked, so may have left writes cached
+            if let Err(e) = removed.flush().await {
+                error!("flushing the removed medium failed: {}", e);
+            }
+            self.return_medium(removed);
+        }
+    }
+
+    /// Pass on what the host asked of `logical_unit`, if anything
+    pub(crate) fn report(&self, logical_unit: &mut LogicalUnit<'bd, BD>) {
+        let Some(request) = logical_unit.take_medium_request() else {
+            return;
+        };
+        info!("host medium request: {}", request);
+        if request == MediumRequest::Eject {
+            if let Some(removed) = logical_unit.eject_medium() {
+                self.return_medium(removed);
+            }
+        }
+        self.removal_prevented
+            .store(logical_unit.removal_prevented(), Ordering::Relaxed);
+        self.request_signal.signal(request);
+    }
+
+    fn return_medium(&self, block_device: &'bd mut BD) {
+        self.slot
+            .lock(|slot| slot.borrow_mut().removed = Some(block_device));
+        self.removed_signal.signal(());
+    }
+}
+
+impl<'bd, M: RawMutex, BD: BlockDevice> Default for RemovableMedium<'bd, M, BD> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
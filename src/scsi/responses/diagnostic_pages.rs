@@ -0,0 +1,39 @@
+use overlay_macro::overlay;
+
+use crate::scsi::enums::{AdditionalSenseCode, SenseKey};
+
+/// The start of every diagnostic page, whether sent with SEND DIAGNOSTIC or returned by RECEIVE
+/// DIAGNOSTIC RESULTS
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct DiagnosticPageHeader {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub page_code: u8,
+
+    /// Length in bytes of the page that follows the header
+    #[overlay(bytes=2..=3)]
+    pub page_length: u16,
+}
+
+/// The self-test results page, following the header once a self-test has been run
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SelfTestResults {
+    /// As in the self-test results log parameter (SPC-4 7.3.18.2)
+    #[overlay(bytes=0..=0, bits=0..=3)]
+    pub self_test_results: u8,
+
+    /// Why the self-test failed
+    #[overlay(bytes=1..=1, bits=0..=3)]
+    pub sense_key: SenseKey,
+
+    /// The ASC and ASCQ
+    #[overlay(bytes=2..=3)]
+    pub additional_sense_code: AdditionalSenseCode,
+}
+
+impl SelfTestResults {
+    pub const COMPLETED_WITHOUT_ERROR: u8 = 0x0;
+    /// Completed, with a failure in an unknown segment
+    pub const FAILED: u8 = 0x4;
+}
@@ -12,14 +12,14 @@ const ASCII_SPACE: u8 = 0x20;
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct InquiryResponse {
     #[overlay(bytes=0..=0, bits=5..=7)]
-    peripheral_qualifier: PeripheralQualifier,
+    pub peripheral_qualifier: PeripheralQualifier,
 
     #[overlay(bytes=0..=0, bits=0..=4)]
-    peripheral_device_type: PeripheralDeviceType,
+    pub peripheral_device_type: PeripheralDeviceType,
 
     ///A removable medium ( RMB ) bit set to zero indicates that the medium is not removable. A RMB bit set to one indicates that the medium is removable.
     #[overlay(bytes=1..=1, bits=7..=7)]
-    removable_medium: bool,
+    pub removable_medium: bool,
 
     ///The VERSION field indicates the implemented version of this standard and is defined in table 142
     #[overlay(bytes=2..=2, bits=0..=7)]
@@ -157,7 +157,7 @@ impl Default for InquiryResponse {
         let mut s = Self::new();
 
         s.set_removable_medium(true);
-        s.set_additional_length(0); // we have no additional length, 36 bytes works fine
+        s.set_additional_length(Self::MINIMUM_SIZE as u8 - 5); // only the standard 36 bytes
         s.set_vendor_identification(&[ASCII_SPACE; 8]);
         s.set_product_identification(&[ASCII_SPACE; 16]);
         s.set_product_revision_level(&[ASCII_SPACE; 4]);
@@ -6,3 +6,15 @@ pub use inquiry::*;
 
 mod request_sense;
 pub use request_sense::*;
+
+mod report_luns;
+pub use report_luns::*;
+
+mod vital_product_data;
+pub use vital_product_data::*;
+
+mod read_format_capacities;
+pub use read_format_capacities::*;
+
+mod diagnostic_pages;
+pub use diagnostic_pages::*;
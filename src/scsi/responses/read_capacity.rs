@@ -9,3 +9,25 @@ pub struct ReadCapacity10Response {
     #[overlay(bytes=4..=7)]
     pub block_size: u32,
 }
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReadCapacity16Response {
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=0..=7)]
+    pub max_lba: [u8; 8],
+
+    #[overlay(bytes=8..=11)]
+    pub block_size: u32,
+
+    /// LBPME: blocks may be unmapped
+    #[overlay(bytes=14..=14, bits=7..=7)]
+    pub logical_block_provisioning_management_enabled: bool,
+
+    /// LBPRZ: unmapped blocks read back as zeros
+    #[overlay(bytes=14..=14, bits=6..=6)]
+    pub logical_block_provisioning_read_zeros: bool,
+
+    #[overlay(bytes=16..=31)]
+    _reserved: [u8; 16],
+}
@@ -0,0 +1,40 @@
+use overlay_macro::overlay;
+
+/// Starts the READ FORMAT CAPACITIES response (UFI 4.10), followed by the current/maximum
+/// capacity descriptor and any formattable capacity descriptors
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct CapacityListHeader {
+    /// Length in bytes of the descriptors that follow
+    #[overlay(bytes=3..=3, bits=0..=7)]
+    pub capacity_list_length: u8,
+}
+
+/// A capacity the medium has, or could be formatted to
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct CapacityDescriptor {
+    /// `0xFFFFFFFF` if there are too many blocks to fit
+    #[overlay(bytes=0..=3)]
+    pub number_of_blocks: u32,
+
+    /// Zero in a formattable capacity descriptor
+    #[overlay(bytes=4..=4, bits=2..=7)]
+    pub format_type: u8,
+
+    /// Only in the current/maximum capacity descriptor
+    #[overlay(bytes=4..=4, bits=0..=1)]
+    pub descriptor_code: u8,
+
+    #[overlay(bytes=5..=7)]
+    pub block_length: u32,
+}
+
+impl CapacityDescriptor {
+    /// There's a medium, which hasn't been formatted; the maximum it could be formatted to
+    pub const UNFORMATTED_MEDIA: u8 = 0b01;
+    /// There's a formatted medium; its capacity
+    pub const FORMATTED_MEDIA: u8 = 0b10;
+    /// There's no medium; the maximum capacity of any that could be inserted
+    pub const NO_MEDIA_PRESENT: u8 = 0b11;
+}
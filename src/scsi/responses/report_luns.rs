@@ -0,0 +1,39 @@
+use overlay_macro::overlay;
+
+use crate::scsi::MAX_LUNS;
+
+const LUN_LEN: usize = 8;
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReportLunsResponse {
+    /// Length in bytes of the LUN list that follows the header, 8 bytes per LUN
+    #[overlay(bytes=0..=3)]
+    pub lun_list_length: u32,
+
+    #[overlay(bytes=8..=135)]
+    pub lun_list: [u8; 128],
+}
+
+impl ReportLunsResponse {
+    /// Lists LUNs `0..lun_count`
+    pub fn with_luns(lun_count: usize) -> Self {
+        assert!(lun_count <= MAX_LUNS);
+
+        let mut lun_list = [0u8; MAX_LUNS * LUN_LEN];
+        for lun in 0..lun_count {
+            // single level LUN, peripheral device addressing method
+            lun_list[lun * LUN_LEN + 1] = lun as u8;
+        }
+
+        let mut response = Self::new();
+        response.set_lun_list_length((lun_count * LUN_LEN) as u32);
+        response.set_lun_list(&lun_list);
+        response
+    }
+
+    /// The header and the LUNs actually listed
+    pub fn as_used_bytes(&self) -> &[u8] {
+        &self.as_bytes()[..8 + self.lun_list_length() as usize]
+    }
+}
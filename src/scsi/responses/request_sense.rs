@@ -2,9 +2,11 @@ use overlay_macro::overlay;
 
 use crate::scsi::enums::{AdditionalSenseCode, ResponseCode, SenseKey};
 
+/// Fixed format sense data (SPC-4 4.5.3), the format REQUEST SENSE returns by default
 #[overlay]
 #[derive(Clone, Copy)]
-pub struct RequestSenseResponse {
+pub struct FixedSenseData {
+    /// The information field is valid
     #[overlay(bytes=0..=0, bits=7..=7)]
     pub valid: bool,
 
@@ -30,73 +32,123 @@ pub struct RequestSenseResponse {
     /// n-7
     pub additional_sense_length: u8,
 
-    #[overlay(bytes=8..=11, bits=0..=7)]
+    #[overlay(bytes=8..=11)]
     pub command_specific_information: u32,
 
-    #[overlay(bytes=12..=13, bits=0..=7)]
+    /// The ASC and ASCQ
+    #[overlay(bytes=12..=13)]
     pub additional_sense_code: AdditionalSenseCode,
 
     #[overlay(bytes=14..=14, bits=0..=7)]
     pub field_replaceable_unit_code: u8,
 
-    #[overlay(bytes=15..=15, bits=7..=7)]
-    pub sense_key_specific_valid: bool,
-
+    /// The SKSV bit and the sense key specific field
     #[overlay(bytes=15..=17)]
-    pub sense_key_specific: u32,
+    pub sense_key_specific: [u8; 3],
+}
 
-    #[overlay(bytes=18..=252)]
-    pub additional_sense_data: [u8; 235],
+impl FixedSenseData {
+    pub fn with_sense(sense_key: SenseKey, additional_sense_code: AdditionalSenseCode) -> Self {
+        let mut sense = Self::new();
+        sense.set_response_code(ResponseCode::FixedSenseData);
+        sense.set_additional_sense_length(Self::BYTE_LEN as u8 - 8);
+        sense.set_sense_key(sense_key);
+        sense.set_additional_sense_code(additional_sense_code);
+        sense
+    }
 }
 
-/*
-information
-command_specifc_information
-additional_sense_code
-additional_sense_code_qualifier
-sense_key_specific
-*/
-
-impl Default for RequestSenseResponse {
-    fn default() -> Self {
-        let mut response = Self::new();
-
-        response.set_valid(true);
-        response.set_additional_sense_length(Self::BYTE_LEN as u8 - 7);
-        response.set_sense_key_specific_valid(true);
-        response.set_additional_sense_data(&[0; 235]);
-
-        response.set_response_code(Default::default());
-        response.set_filemark(Default::default());
-        response.set_end_of_medium(Default::default());
-        response.set_incorrect_length_indicator(Default::default());
-        response.set_sense_key(Default::default());
-        response.set_information(Default::default());
-        response.set_command_specific_information(Default::default());
-        response.set_additional_sense_code(Default::default());
-        response.set_field_replaceable_unit_code(Default::default());
-        response.set_sense_key_specific(Default::default());
-
-        response
+/// Descriptor format sense data (SPC-4 4.5.2), returned by REQUEST SENSE with DESC set. This is
+/// the header, followed by any sense data descriptors
+#[overlay]
+#[derive(Clone, Copy)]
+pub struct DescriptorSenseData {
+    #[overlay(bytes=0..=0, bits=0..=6)]
+    pub response_code: ResponseCode,
+
+    #[overlay(bytes=1..=1, bits=0..=3)]
+    pub sense_key: SenseKey,
+
+    /// The ASC and ASCQ
+    #[overlay(bytes=2..=3)]
+    pub additional_sense_code: AdditionalSenseCode,
+
+    /// Length in bytes of the sense data descriptors that follow
+    #[overlay(bytes=7..=7, bits=0..=7)]
+    pub additional_sense_length: u8,
+}
+
+impl DescriptorSenseData {
+    pub fn with_sense(sense_key: SenseKey, additional_sense_code: AdditionalSenseCode) -> Self {
+        let mut sense = Self::new();
+        sense.set_response_code(ResponseCode::DescriptorSenseData);
+        sense.set_sense_key(sense_key);
+        sense.set_additional_sense_code(additional_sense_code);
+        sense
     }
 }
 
-impl RequestSenseResponse {
-    #[allow(dead_code)]
-    pub fn reset_status(&mut self) {
-        *self = Default::default()
+/// The information sense data descriptor (SPC-4 4.5.2.2): the LBA a command failed at
+#[overlay]
+#[derive(Clone, Copy)]
+pub struct InformationSenseDataDescriptor {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub descriptor_type: u8,
+
+    /// n-1
+    #[overlay(bytes=1..=1, bits=0..=7)]
+    pub additional_length: u8,
+
+    #[overlay(bytes=2..=2, bits=7..=7)]
+    pub valid: bool,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=4..=11)]
+    pub information: [u8; 8],
+}
+
+impl InformationSenseDataDescriptor {
+    pub fn with_information(information: u64) -> Self {
+        let mut descriptor = Self::new();
+        descriptor.set_additional_length(Self::BYTE_LEN as u8 - 2);
+        descriptor.set_valid(true);
+        descriptor.set_information(&information.to_be_bytes());
+        descriptor
     }
 }
 
-/*
-    if !descriptor_format
-        return fixed sense data
-    else
-        if descriptor sense data supported
-            return descriptor sense data
-        else
-            return CHECK CONDITION with sense:
-                key: ILLEGAL REQUEST
-                additional code: INVALID FIELD IN CDB
-
-*/
+/// The sense key specific sense data descriptor (SPC-4 4.5.2.3), here the progress indication of
+/// NOT READY, FORMAT IN PROGRESS
+#[overlay]
+#[derive(Clone, Copy)]
+pub struct SenseKeySpecificSenseDataDescriptor {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub descriptor_type: u8,
+
+    /// n-1
+    #[overlay(bytes=1..=1, bits=0..=7)]
+    pub additional_length: u8,
+
+    #[overlay(bytes=4..=4, bits=7..=7)]
+    pub sense_key_specific_valid: bool,
+
+    /// How much has been done, out of 65536
+    #[overlay(bytes=5..=6)]
+    pub progress_indication: u16,
+
+    #[overlay(bytes=7..=7)]
+    _reserved: [u8; 1],
+}
+
+impl SenseKeySpecificSenseDataDescriptor {
+    pub const DESCRIPTOR_TYPE: u8 = 0x02;
+
+    pub fn with_progress(progress: u16) -> Self {
+        let mut descriptor = Self::new();
+        descriptor.set_descriptor_type(Self::DESCRIPTOR_TYPE);
+        descriptor.set_additional_length(Self::BYTE_LEN as u8 - 2);
+        descriptor.set_sense_key_specific_valid(true);
+        descriptor.set_progress_indication(progress);
+        descriptor
+    }
+}
@@ -0,0 +1,130 @@
+use overlay_macro::overlay;
+
+use crate::scsi::enums::{PeripheralDeviceType, PeripheralQualifier, VpdPage};
+
+/// The start of every VPD page
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct VpdPageHeader {
+    #[overlay(bytes=0..=0, bits=5..=7)]
+    pub peripheral_qualifier: PeripheralQualifier,
+
+    #[overlay(bytes=0..=0, bits=0..=4)]
+    pub peripheral_device_type: PeripheralDeviceType,
+
+    #[overlay(bytes=1..=1, bits=0..=7)]
+    pub page_code: VpdPage,
+
+    /// Length in bytes of the page that follows the header
+    #[overlay(bytes=2..=3)]
+    pub page_length: u16,
+}
+
+/// Header of a designation descriptor in the device identification page (SPC-4 7.8.6.1)
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct DesignationDescriptorHeader {
+    #[overlay(bytes=0..=0, bits=0..=3)]
+    pub code_set: u8,
+
+    /// Which of the logical unit, target port or target device is identified
+    #[overlay(bytes=1..=1, bits=4..=5)]
+    pub association: u8,
+
+    #[overlay(bytes=1..=1, bits=0..=3)]
+    pub designator_type: u8,
+
+    /// Length in bytes of the designator that follows
+    #[overlay(bytes=3..=3, bits=0..=7)]
+    pub designator_length: u8,
+}
+
+impl DesignationDescriptorHeader {
+    pub const CODE_SET_ASCII: u8 = 0x2;
+    pub const ASSOCIATION_LOGICAL_UNIT: u8 = 0b00;
+    /// T10 vendor identification, followed by a vendor specific identifier
+    pub const DESIGNATOR_TYPE_T10_VENDOR_ID: u8 = 0x1;
+}
+
+/// SBC-3 6.5.3. Offsets are from the start of the page, which begins with a [`VpdPageHeader`]
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct BlockLimitsPage {
+    /// In blocks, transfers should be a multiple of this
+    #[overlay(bytes=6..=7)]
+    pub optimal_transfer_length_granularity: u16,
+
+    /// In blocks, 0 if there's no limit
+    #[overlay(bytes=8..=11)]
+    pub maximum_transfer_length: u32,
+
+    #[overlay(bytes=12..=15)]
+    pub optimal_transfer_length: u32,
+
+    #[overlay(bytes=20..=23)]
+    pub maximum_unmap_lba_count: u32,
+
+    #[overlay(bytes=24..=27)]
+    pub maximum_unmap_block_descriptor_count: u32,
+
+    #[overlay(bytes=28..=31)]
+    pub optimal_unmap_granularity: u32,
+
+    /// Big-endian, as overlay integers are at most 32 bits
+    #[overlay(bytes=36..=43)]
+    pub maximum_write_same_length: [u8; 8],
+
+    #[overlay(bytes=44..=63)]
+    _reserved: [u8; 20],
+}
+
+/// SBC-3 6.5.2. Offsets are from the start of the page, which begins with a [`VpdPageHeader`]
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct BlockDeviceCharacteristicsPage {
+    /// 1 for a non-rotating medium, such as flash
+    #[overlay(bytes=4..=5)]
+    pub medium_rotation_rate: u16,
+
+    #[overlay(bytes=7..=7, bits=0..=3)]
+    pub nominal_form_factor: u8,
+
+    #[overlay(bytes=8..=63)]
+    _reserved: [u8; 56],
+}
+
+impl BlockDeviceCharacteristicsPage {
+    pub const NON_ROTATING_MEDIUM: u16 = 0x0001;
+}
+
+/// SBC-3 6.6.4. Offsets are from the start of the page, which begins with a [`VpdPageHeader`]
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct LogicalBlockProvisioningPage {
+    /// UNMAP is supported
+    #[overlay(bytes=5..=5, bits=7..=7)]
+    pub unmap: bool,
+
+    /// WRITE SAME(16) with UNMAP set is supported
+    #[overlay(bytes=5..=5, bits=6..=6)]
+    pub write_same_16_unmap: bool,
+
+    /// WRITE SAME(10) with UNMAP set is supported
+    #[overlay(bytes=5..=5, bits=5..=5)]
+    pub write_same_10_unmap: bool,
+
+    /// Unmapped blocks read back as zeros
+    #[overlay(bytes=5..=5, bits=2..=2)]
+    pub read_zeros: bool,
+
+    #[overlay(bytes=6..=6, bits=0..=2)]
+    pub provisioning_type: u8,
+
+    #[overlay(bytes=7..=7)]
+    _reserved: [u8; 1],
+}
+
+impl LogicalBlockProvisioningPage {
+    /// Every block has somewhere to be stored, whether or not it's mapped
+    pub const PROVISIONING_TYPE_RESOURCE: u8 = 0b001;
+}
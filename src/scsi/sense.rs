@@ -0,0 +1,108 @@
+use embedded_io_async::{Read, Write};
+
+use crate::{
+    bulk_only_transport::{CommandError, DataPhase},
+    usb_mass_storage::TransportError,
+};
+
+use super::{
+    block_device::BlockDeviceError,
+    commands::RequestSenseCommand,
+    enums::{AdditionalSenseCode, SenseKey},
+    responses::{
+        DescriptorSenseData, FixedSenseData, InformationSenseDataDescriptor,
+        SenseKeySpecificSenseDataDescriptor,
+    },
+    write_response,
+};
+
+/// Why a logical unit's last command failed, for the host to find out with REQUEST SENSE
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub(crate) struct Sense {
+    pub key: SenseKey,
+    pub code: AdditionalSenseCode,
+    /// The LBA the command failed at, if that's of interest
+    pub information: Option<u64>,
+    /// How far FORMAT UNIT has got, out of 65536, with NOT READY, FORMAT IN PROGRESS
+    pub progress: Option<u16>,
+}
+
+impl Sense {
+    pub const fn new(key: SenseKey, code: AdditionalSenseCode) -> Self {
+        Self {
+            key,
+            code,
+            information: None,
+            progress: None,
+        }
+    }
+
+    /// Respond to REQUEST SENSE, in the fixed or descriptor format it asks for
+    pub async fn respond(
+        self,
+        request_sense: RequestSenseCommand,
+        data: &mut DataPhase<'_, impl Read<Error = TransportError> + Write<Error = TransportError>>,
+    ) -> Result<(), CommandError> {
+        let allocation_length = request_sense.allocation_length() as usize;
+        if request_sense.descriptor_format() {
+            const LEN: usize = DescriptorSenseData::BYTE_LEN
+                + InformationSenseDataDescriptor::BYTE_LEN
+                + SenseKeySpecificSenseDataDescriptor::BYTE_LEN;
+            let mut buf = [0u8; LEN];
+            let mut header = DescriptorSenseData::with_sense(self.key, self.code);
+            let mut len = DescriptorSenseData::BYTE_LEN;
+            if let Some(information) = self.information {
+                let descriptor = InformationSenseDataDescriptor::with_information(information);
+                buf[len..len + descriptor.as_bytes().len()].copy_from_slice(descriptor.as_bytes());
+                len += descriptor.as_bytes().len();
+            }
+            if let Some(progress) = self.progress {
+                let descriptor = SenseKeySpecificSenseDataDescriptor::with_progress(progress);
+                buf[len..len + descriptor.as_bytes().len()].copy_from_slice(descriptor.as_bytes());
+                len += descriptor.as_bytes().len();
+            }
+            header.set_additional_sense_length((len - DescriptorSenseData::BYTE_LEN) as u8);
+            buf[..DescriptorSenseData::BYTE_LEN].copy_from_slice(header.as_bytes());
+            write_response(data, &buf[..len], allocation_length).await
+        } else {
+            let mut sense = FixedSenseData::with_sense(self.key, self.code);
+            // without room for the whole LBA, the information isn't valid
+            if let Some(information) = self.information.and_then(|i| u32::try_from(i).ok()) {
+                sense.set_valid(true);
+                sense.set_information(information);
+            }
+            if let Some(progress) = self.progress {
+                let [msb, lsb] = progress.to_be_bytes();
+                // SKSV, then the progress indication
+                sense.set_sense_key_specific(&[0x80, msb, lsb]);
+            }
+            write_response(data, sense.as_bytes(), allocation_length).await
+        }
+    }
+}
+
+impl From<BlockDeviceError> for Sense {
+    fn from(e: BlockDeviceError) -> Self {
+        match e {
+            BlockDeviceError::WriteError => Sense::new(
+                SenseKey::HardwareError, // or SenseKey::MediumError
+                AdditionalSenseCode::WriteError,
+            ),
+            BlockDeviceError::ReadError => Sense::new(
+                SenseKey::MediumError,
+                AdditionalSenseCode::UnrecoveredReadError,
+            ),
+            BlockDeviceError::EraseError => {
+                Sense::new(SenseKey::MediumError, AdditionalSenseCode::EraseFailure)
+            }
+            BlockDeviceError::HardwareError => Sense::new(
+                SenseKey::HardwareError,
+                AdditionalSenseCode::LogicalUnitCommunicationFailure,
+            ),
+            BlockDeviceError::InvalidAddress => Sense::new(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+            ),
+        }
+    }
+}
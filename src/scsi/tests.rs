@@ -0,0 +1,1743 @@
+use super::responses::FixedSenseData;
+use super::{BlockDevice, MediumRequest, RemovableMedium, WriteProtect};
+use crate::block_devices::{FlashBlockDevice, WriteBackCache};
+use crate::mock::{
+    logical_unit, run_device, run_logical_units, run_logical_units_from_power_on,
+    run_removable_medium, ControlResponse, RamBlockDevice, SimulatedFlash, SparseBlockDevice,
+    PRODUCT_IDENTIFICATION, VENDOR_IDENTIFICATION,
+};
+
+const PACKET_SIZE: u16 = 64;
+
+const STATUS_PASSED: u8 = 0x00;
+const STATUS_FAILED: u8 = 0x01;
+
+const REQUEST_SENSE: [u8; 6] = [0x03, 0, 0, 0, FixedSenseData::BYTE_LEN as u8, 0];
+
+#[test]
+fn inquiry() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(0x1234, 0, &[0x12, 0, 0, 0, 36, 0], &[], 36)
+            .await
+    });
+
+    assert_eq!(csw.tag, 0x1234);
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data.len(), 36);
+    assert_eq!(data[0], 0x00); // connected, direct access block device
+    assert_eq!(data[1], 0x80); // removable
+    assert_eq!(&data[8..16], VENDOR_IDENTIFICATION);
+    assert_eq!(&data[16..32], PRODUCT_IDENTIFICATION);
+}
+
+#[test]
+fn test_unit_ready() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(7, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await
+    });
+
+    assert!(data.is_empty());
+    assert_eq!(csw.tag, 7);
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn read_capacity_10() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[], 8)
+            .await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data, [0, 0, 0, 15, 0, 0, 2, 0]); // max lba 15, 512 byte blocks
+}
+
+#[test]
+fn read_capacity_16() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let cb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0];
+        usb.command(1, 0, &cb, &[], 32).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data[..12], [0, 0, 0, 0, 0, 0, 0, 15, 0, 0, 2, 0]);
+    assert_eq!(data.len(), 32);
+}
+
+#[test]
+fn larger_than_32_bit_lbas() {
+    let blocks = 0x1_0000_0010;
+    let mut block_device = SparseBlockDevice::new(blocks);
+    let lba: u64 = 0x1_0000_0005;
+
+    let (capacity_10, capacity_16, read, write_csw) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let cb = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            let (capacity_10, _) = usb.command(1, 0, &cb, &[], 8).await;
+
+            let cb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0];
+            let (capacity_16, _) = usb.command(2, 0, &cb, &[], 12).await;
+
+            let mut cb = [0x88, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0];
+            cb[2..10].copy_from_slice(&lba.to_be_bytes());
+            let (read, _) = usb.command(3, 0, &cb, &[], 1024).await;
+
+            cb[0] = 0x8A;
+            cb[13] = 1;
+            let (_, write_csw) = usb.command(4, 0, &cb, &[0xAA; 512], 0).await;
+
+            (capacity_10, capacity_16, read, write_csw)
+        });
+
+    // too large for READ CAPACITY(10)
+    assert_eq!(capacity_10, [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 2, 0]);
+    assert_eq!(capacity_16[..8], (blocks - 1).to_be_bytes());
+    assert_eq!(read[..512], SparseBlockDevice::block(lba));
+    assert_eq!(read[512..], SparseBlockDevice::block(lba + 1));
+    assert_eq!(write_csw.status, STATUS_PASSED);
+    assert_eq!(block_device.written, Some((lba, std::vec![0xAA; 512])));
+}
+
+#[test]
+fn lba_range_overflow_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let cb = [
+            0x88, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 2, 0, 0,
+        ];
+        let (data, csw) = usb.command(1, 0, &cb, &[], 1024).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        (data, csw, sense)
+    });
+
+    assert!(data.is_empty());
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(csw.data_residue, 1024);
+    assert_eq!(sense[2] & 0x0F, 0x05); // ILLEGAL REQUEST
+}
+
+#[test]
+fn write_10_then_read_10() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let written: std::vec::Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+
+    let (read, write_csw, read_csw) = run_device(&mut block_device, PACKET_SIZE, |usb| {
+        let written = written.clone();
+        async move {
+            // two blocks from lba 3
+            let cb = [0x2A, 0, 0, 0, 0, 3, 0, 0, 2, 0];
+            let (_, write_csw) = usb.command(1, 0, &cb, &written, 0).await;
+
+            let cb = [0x28, 0, 0, 0, 0, 3, 0, 0, 2, 0];
+            let (read, read_csw) = usb.command(2, 0, &cb, &[], 1024).await;
+
+            (read, write_csw, read_csw)
+        }
+    });
+
+    assert_eq!(write_csw.status, STATUS_PASSED);
+    assert_eq!(read_csw.status, STATUS_PASSED);
+    assert_eq!(read, written);
+    assert_eq!(block_device.block(2), [0; 512]);
+    assert_eq!(block_device.block(3), &written[..512]);
+    assert_eq!(block_device.block(4), &written[512..]);
+    assert_eq!(block_device.block(5), [0; 512]);
+}
+
+#[test]
+fn many_blocks() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let written: std::vec::Vec<u8> = (0..11 * 512).map(|i| (i % 251) as u8).collect();
+
+    let (read, write_csw, read_csw) = run_device(&mut block_device, PACKET_SIZE, |usb| {
+        let written = written.clone();
+        async move {
+            let cb = [0x2A, 0, 0, 0, 0, 1, 0, 0, 11, 0];
+            let (_, write_csw) = usb.command(1, 0, &cb, &written, 0).await;
+
+            let cb = [0x28, 0, 0, 0, 0, 1, 0, 0, 11, 0];
+            let (read, read_csw) = usb.command(2, 0, &cb, &[], 11 * 512).await;
+
+            (read, write_csw, read_csw)
+        }
+    });
+
+    assert_eq!(write_csw.status, STATUS_PASSED);
+    assert_eq!(read_csw.status, STATUS_PASSED);
+    assert_eq!(read, written);
+    assert_eq!(block_device.block(0), [0; 512]);
+    assert_eq!(block_device.block(11), &written[10 * 512..]);
+    assert_eq!(block_device.block(12), [0; 512]);
+}
+
+#[test]
+fn blocks_larger_than_default_buffer() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_, 4096, 4096> =
+        FlashBlockDevice::new(flash, 0, 16384, 16384);
+    let written: std::vec::Vec<u8> = (0..2 * 4096).map(|i| (i % 251) as u8).collect();
+
+    let (capacity, read, write_csw, read_csw) = run_device(&mut block_device, PACKET_SIZE, |usb| {
+        let written = written.clone();
+        async move {
+            let cb = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            let (capacity, _) = usb.command(1, 0, &cb, &[], 8).await;
+
+            let cb = [0x2A, 0, 0, 0, 0, 1, 0, 0, 2, 0];
+            let (_, write_csw) = usb.command(2, 0, &cb, &written, 0).await;
+
+            let cb = [0x28, 0, 0, 0, 0, 1, 0, 0, 2, 0];
+            let (read, read_csw) = usb.command(3, 0, &cb, &[], 2 * 4096).await;
+
+            (capacity, read, write_csw, read_csw)
+        }
+    });
+
+    assert_eq!(capacity, [0, 0, 0, 3, 0, 0, 0x10, 0]);
+    assert_eq!(write_csw.status, STATUS_PASSED);
+    assert_eq!(read_csw.status, STATUS_PASSED);
+    assert_eq!(read, written);
+    assert_eq!(&block_device.release().bytes()[4096..3 * 4096], written);
+}
+
+/// Blocks that don't fit a whole number of packets are split across them, with only the last
+/// packet of the data stage short
+fn odd_sized_blocks<const BLOCK_BYTES: usize>(packet_size: u16) {
+    let mut block_device = RamBlockDevice::<BLOCK_BYTES>::new(8);
+    let len = 3 * BLOCK_BYTES;
+    let written: std::vec::Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+    let (read, write_csw, read_csw) = run_device(&mut block_device, packet_size, |usb| {
+        let written = written.clone();
+        async move {
+            let cb = [0x2A, 0, 0, 0, 0, 2, 0, 0, 3, 0];
+            let (_, write_csw) = usb.command(1, 0, &cb, &written, 0).await;
+
+            // the host asks for more than the command reads, so the device has to end the
+            // data stage itself
+            let cb = [0x28, 0, 0, 0, 0, 2, 0, 0, 3, 0];
+            let (read, read_csw) = usb.command(2, 0, &cb, &[], (len + 100) as u32).await;
+
+            (read, write_csw, read_csw)
+        }
+    });
+
+    assert_eq!(write_csw.status, STATUS_PASSED);
+    assert_eq!(read_csw.status, STATUS_PASSED);
+    assert_eq!(read_csw.data_residue, 100);
+    assert_eq!(read, written);
+    assert_eq!(
+        block_device.block(3),
+        &written[BLOCK_BYTES..2 * BLOCK_BYTES]
+    );
+}
+
+#[test]
+fn block_sizes_not_a_multiple_of_packet_size() {
+    for packet_size in [8, 16, 32, 64, 512] {
+        odd_sized_blocks::<520>(packet_size);
+        odd_sized_blocks::<100>(packet_size);
+    }
+    odd_sized_blocks::<2048>(512);
+    odd_sized_blocks::<512>(512);
+}
+
+#[test]
+fn unsupported_op_code_sets_sense() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (csw, sense, sense_csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (_, csw) = usb.command(1, 0, &[0xFF, 0, 0, 0, 0, 0], &[], 0).await;
+        let len = FixedSenseData::BYTE_LEN as u8;
+        let (sense, sense_csw) = usb
+            .command(2, 0, &[0x03, 0, 0, 0, len, 0], &[], len as u32)
+            .await;
+        (csw, sense, sense_csw)
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(sense_csw.status, STATUS_PASSED);
+    assert_eq!(sense[0] & 0x7F, 0x70); // fixed format
+    assert_eq!(sense[2] & 0x0F, 0x05); // ILLEGAL REQUEST
+}
+
+#[test]
+fn read_past_capacity_fails_without_data() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let cb = [0x28, 0, 0, 0, 0, 16, 0, 0, 1, 0];
+        usb.command(1, 0, &cb, &[], 512).await
+    });
+
+    // terminated with a zero length packet, nothing was transferred
+    assert!(data.is_empty());
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!(csw.data_residue, 512);
+}
+
+#[test]
+fn logical_units_are_independent() {
+    let mut block_device_0 = RamBlockDevice::<512>::new(16);
+    let mut block_device_1 = RamBlockDevice::<512>::new(32);
+    let logical_units = [
+        logical_unit(&mut block_device_0),
+        logical_unit(&mut block_device_1),
+    ];
+
+    let (max_lun, capacity, write_csw) =
+        run_logical_units(logical_units, PACKET_SIZE, |usb| async move {
+            let max_lun = usb.get_max_lun().await;
+
+            let read_capacity = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            let (capacity, _) = usb.command(1, 1, &read_capacity, &[], 8).await;
+
+            let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+            let (_, write_csw) = usb.command(2, 1, &write, &[0xAA; 512], 0).await;
+
+            (max_lun, capacity, write_csw)
+        });
+
+    assert_eq!(max_lun, ControlResponse::Accepted(std::vec![1]));
+    assert_eq!(capacity, [0, 0, 0, 31, 0, 0, 2, 0]);
+    assert_eq!(write_csw.status, STATUS_PASSED);
+    assert_eq!(block_device_0.block(3), [0; 512]);
+    assert_eq!(block_device_1.block(3), [0xAA; 512]);
+}
+
+#[test]
+fn report_luns() {
+    let mut block_device_0 = RamBlockDevice::<512>::new(16);
+    let mut block_device_1 = RamBlockDevice::<512>::new(16);
+    let mut block_device_2 = RamBlockDevice::<512>::new(16);
+    let logical_units = [
+        logical_unit(&mut block_device_0),
+        logical_unit(&mut block_device_1),
+        logical_unit(&mut block_device_2),
+    ];
+
+    let (data, csw) = run_logical_units(logical_units, PACKET_SIZE, |usb| async move {
+        let cb = [0xA0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 0];
+        usb.command(1, 0, &cb, &[], 64).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(&data[..8], [0, 0, 0, 24, 0, 0, 0, 0]);
+    assert_eq!(&data[8..16], [0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&data[16..24], [0, 1, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(&data[24..32], [0, 2, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(data.len(), 32);
+}
+
+#[test]
+fn report_luns_allocation_length_too_short() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (csw, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let cb = [0xA0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0];
+        let (_, csw) = usb.command(1, 0, &cb, &[], 8).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        (csw, sense)
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+    // ILLEGAL REQUEST, INVALID FIELD IN CDB
+    assert_eq!(sense[2], 0x05);
+    assert_eq!(sense[12..14], [0x24, 0x00]);
+}
+
+#[test]
+fn unsupported_lun() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (inquiry, tur_csw, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (inquiry, _) = usb.command(1, 3, &[0x12, 0, 0, 0, 36, 0], &[], 36).await;
+        let (_, tur_csw) = usb.command(2, 3, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(3, 3, &REQUEST_SENSE, &[], len).await;
+        (inquiry, tur_csw, sense)
+    });
+
+    assert_eq!(inquiry[0], 0x7F); // not capable of supporting a device, unknown device type
+    assert_eq!(tur_csw.status, STATUS_FAILED);
+    assert_eq!(sense[2] & 0x0F, 0x05); // ILLEGAL REQUEST
+}
+
+#[test]
+fn read_only_rejects_writes() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut read_only = logical_unit(&mut block_device);
+    read_only.set_read_only(true);
+
+    let (write_csw, sense) = run_logical_units([read_only], PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+        let (_, write_csw) = usb.command(1, 0, &write, &[0xAA; 512], 0).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        (write_csw, sense)
+    });
+
+    assert_eq!(write_csw.status, STATUS_FAILED);
+    assert_eq!(sense[2] & 0x0F, 0x07); // DATA PROTECT
+    assert_eq!(block_device.block(3), [0; 512]);
+}
+
+#[test]
+fn not_removable() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut fixed = logical_unit(&mut block_device);
+    fixed.set_removable(false);
+
+    let (data, _) = run_logical_units([fixed], PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x12, 0, 0, 0, 36, 0], &[], 36).await
+    });
+
+    assert_eq!(data[1], 0x00);
+}
+
+#[test]
+fn mode_sense_6_all_pages() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x1A, 0, 0x3F, 0, 0xFF, 0], &[], 0xFF)
+            .await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data.len(), 4 + 8 + 20 + 12 + 12);
+    assert_eq!(data[..4], [data.len() as u8 - 1, 0, 0, 8]);
+    assert_eq!(data[4..12], [0, 0, 0, 16, 0, 0, 2, 0]); // 16 blocks of 512 bytes
+
+    // all saveable
+    assert_eq!(data[12..15], [0x88, 18, 0b001]); // caching, read cache disabled
+    assert_eq!(data[32..34], [0x8A, 10]); // control
+    assert_eq!(data[44..47], [0x9C, 10, 0b1000]); // informational exceptions, disabled
+}
+
+#[test]
+fn mode_sense_10_single_page_without_block_descriptors() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut read_only = logical_unit(&mut block_device);
+    read_only.set_read_only(true);
+
+    let (data, csw) = run_logical_units([read_only], PACKET_SIZE, |usb| async move {
+        let cb = [0x5A, 0b1000, 0x1C, 0, 0, 0, 0, 0, 0xFF, 0];
+        usb.command(1, 0, &cb, &[], 0xFF).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data.len(), 8 + 12);
+    assert_eq!(data[..8], [0, 18, 0, 0x80, 0, 0, 0, 0]); // write protected, no descriptors
+    assert_eq!(data[8], 0x9C);
+}
+
+#[test]
+fn mode_sense_10_long_lba_block_descriptor() {
+    let blocks = 0x1_0000_0010;
+    let mut block_device = SparseBlockDevice::new(blocks);
+
+    let (short, long) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let mut cb = [0x5A, 0, 0x08, 0, 0, 0, 0, 0, 0xFF, 0];
+        let (short, _) = usb.command(1, 0, &cb, &[], 0xFF).await;
+        cb[1] = 0b1_0000; // LLBAA
+        let (long, _) = usb.command(2, 0, &cb, &[], 0xFF).await;
+        (short, long)
+    });
+
+    assert_eq!(short[4], 0);
+    assert_eq!(short[6..8], [0, 8]);
+    assert_eq!(short[8..16], [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 2, 0]);
+
+    assert_eq!(long[4], 1); // LONGLBA
+    assert_eq!(long[6..8], [0, 16]);
+    assert_eq!(long[8..16], blocks.to_be_bytes());
+    assert_eq!(long[20..24], [0, 0, 2, 0]);
+    assert_eq!(long[24], 0x08);
+}
+
+#[test]
+fn mode_sense_changeable_and_default_values() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (changeable, default) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (changeable, _) = usb
+            .command(1, 0, &[0x1A, 0, 0b0111_1111, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        let (default, _) = usb
+            .command(2, 0, &[0x1A, 0, 0b1011_1111, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        (changeable, default)
+    });
+
+    assert_eq!(changeable[4..12], [0; 8]);
+    assert_eq!(changeable[12..15], [0x88, 18, 0]); // no write cache to enable
+    assert_eq!(changeable[44..48], [0x9C, 10, 0b1000, 0b1111]);
+    assert_eq!(default[12..15], [0x88, 18, 0b001]);
+
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+    let (changeable, _) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x1A, 0, 0b0100_1000, 0, 0xFF, 0], &[], 0xFF)
+            .await
+    });
+    assert_eq!(changeable[12..15], [0x88, 18, 0b100]); // write cache
+}
+
+#[test]
+fn mode_sense_truncated_to_allocation_length() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x1A, 0, 0x3F, 0, 4, 0], &[], 4).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data, [4 + 8 + 20 + 12 + 12 - 1, 0, 0, 8]);
+}
+
+#[test]
+fn mode_sense_unsupported_page_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (unsupported_page, unsupported_subpage, sense) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let (_, unsupported_page) = usb
+                .command(1, 0, &[0x1A, 0, 0x01, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            let (_, unsupported_subpage) = usb
+                .command(2, 0, &[0x1A, 0, 0x08, 0x01, 0xFF, 0], &[], 0xFF)
+                .await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+            (unsupported_page, unsupported_subpage, sense)
+        });
+
+    assert_eq!(unsupported_page.status, STATUS_FAILED);
+    assert_eq!(unsupported_subpage.status, STATUS_FAILED);
+    assert_eq!(sense[2] & 0x0F, 0x05); // ILLEGAL REQUEST
+}
+
+/// MODE SELECT(6) parameters: a header without block descriptors, then the caching page with
+/// `byte_2` as its WCE and RCD bits
+fn caching_page_parameters(byte_2: u8) -> [u8; 24] {
+    let mut parameters = [0; 24];
+    parameters[4..7].copy_from_slice(&[0x08, 18, byte_2]);
+    parameters
+}
+
+#[test]
+fn mode_select_changes_current_values() {
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    let (select_csw, current, default) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let parameters = caching_page_parameters(0b001); // write cache disabled
+            let (_, select_csw) = usb
+                .command(1, 0, &[0x15, 0x10, 0, 0, 24, 0], &parameters, 0)
+                .await;
+            let (current, _) = usb
+                .command(2, 0, &[0x1A, 0, 0x08, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            let (default, _) = usb
+                .command(3, 0, &[0x1A, 0, 0x88, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            (select_csw, current, default)
+        });
+
+    assert_eq!(select_csw.status, STATUS_PASSED);
+    assert_eq!(current[12..15], [0x88, 18, 0b001]); // saveable
+    assert_eq!(default[12..15], [0x88, 18, 0b101]);
+    assert_eq!(block_device.get_ref().saved_mode_parameters, None);
+}
+
+#[test]
+fn mode_select_rejects_unchangeable_parameters() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (select_csw, sense, current) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            // software write protect, which the host can't change
+            let mut parameters = [0; 16];
+            parameters[4..6].copy_from_slice(&[0x0A, 10]);
+            parameters[8] = 0b1000;
+            let (_, select_csw) = usb
+                .command(1, 0, &[0x15, 0x10, 0, 0, 16, 0], &parameters, 0)
+                .await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+            let (current, _) = usb
+                .command(3, 0, &[0x1A, 0, 0x0A, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            (select_csw, sense, current)
+        });
+
+    assert_eq!(select_csw.status, STATUS_FAILED);
+    assert_eq!(sense[2] & 0x0F, 0x05); // ILLEGAL REQUEST
+    assert_eq!(current[16], 0);
+}
+
+#[test]
+fn mode_select_saved_pages_are_restored() {
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    let (select_csw, saved) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let parameters = caching_page_parameters(0b001);
+        let (_, select_csw) = usb
+            .command(1, 0, &[0x15, 0x11, 0, 0, 24, 0], &parameters, 0)
+            .await;
+        let (saved, _) = usb
+            .command(2, 0, &[0x1A, 0, 0xC8, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        (select_csw, saved)
+    });
+
+    assert_eq!(select_csw.status, STATUS_PASSED);
+    assert_eq!(saved[14], 0b001);
+    assert!(block_device.get_ref().saved_mode_parameters.is_some());
+
+    // as if after a power cycle
+    let (current, _) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x1A, 0, 0x08, 0, 0xFF, 0], &[], 0xFF)
+            .await
+    });
+
+    assert_eq!(current[14], 0b001);
+}
+
+#[test]
+fn mode_select_saved_pages_are_restored_from_flash() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device =
+        WriteBackCache::<_, 8>::new(FlashBlockDevice::<_>::new(flash, 0, 16384, 16384));
+
+    let select_csw = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let parameters = caching_page_parameters(0b001);
+        let (_, select_csw) = usb
+            .command(1, 0, &[0x15, 0x11, 0, 0, 24, 0], &parameters, 0)
+            .await;
+        select_csw
+    });
+
+    assert_eq!(select_csw.status, STATUS_PASSED);
+    // kept in the sector after the blocks
+    let flash = block_device.release().release();
+    assert_eq!(flash.erases, [0, 0, 0, 0, 0]);
+    assert_eq!(flash.bytes()[..16384], [0xFF; 16384]);
+
+    // powered off, and back on with nothing but the flash
+    let mut block_device =
+        WriteBackCache::<_, 8>::new(FlashBlockDevice::<_>::new(flash, 0, 16384, 16384));
+    let (current, saved) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (current, _) = usb
+            .command(1, 0, &[0x1A, 0, 0x08, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        let (saved, _) = usb
+            .command(2, 0, &[0x1A, 0, 0xC8, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        (current, saved)
+    });
+
+    assert_eq!(current[12..15], [0x88, 18, 0b001]);
+    assert_eq!(saved[14], 0b001);
+}
+
+#[test]
+fn saving_mode_pages_unsupported() {
+    let mut block_device = SparseBlockDevice::new(16);
+
+    let (select_csw, saved_csw, current) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let parameters = caching_page_parameters(0b001);
+            let (_, select_csw) = usb
+                .command(1, 0, &[0x15, 0x11, 0, 0, 24, 0], &parameters, 0)
+                .await;
+            let (_, saved_csw) = usb
+                .command(2, 0, &[0x1A, 0, 0xC8, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            let (current, _) = usb
+                .command(3, 0, &[0x1A, 0, 0x08, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            (select_csw, saved_csw, current)
+        });
+
+    assert_eq!(select_csw.status, STATUS_FAILED);
+    assert_eq!(saved_csw.status, STATUS_FAILED);
+    assert_eq!(current[12..15], [0x08, 18, 0b001]); // not saveable, unchanged
+}
+
+#[test]
+fn write_protect_switched_at_runtime() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let write_protect = WriteProtect::new(false);
+    let mut logical_unit = logical_unit(&mut block_device);
+    logical_unit.set_write_protect(&write_protect);
+
+    let write_protect = &write_protect;
+    let (writable, protected, sense, mode_sense, unprotected) =
+        run_logical_units([logical_unit], PACKET_SIZE, |usb| async move {
+            let write_6 = [0x0A, 0, 0, 1, 1, 0];
+            let (_, writable) = usb.command(1, 0, &write_6, &[0xAA; 512], 0).await;
+
+            write_protect.set(true);
+            let write_6 = [0x0A, 0, 0, 2, 1, 0];
+            let (_, protected) = usb.command(2, 0, &write_6, &[0xBB; 512], 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+            let (mode_sense, _) = usb
+                .command(4, 0, &[0x1A, 0b1000, 0x08, 0, 4, 0], &[], 4)
+                .await;
+
+            write_protect.set(false);
+            let write_12 = [0xAA, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0];
+            let (_, unprotected) = usb.command(5, 0, &write_12, &[0xCC; 512], 0).await;
+
+            (writable, protected, sense, mode_sense, unprotected)
+        });
+
+    assert_eq!(writable.status, STATUS_PASSED);
+    assert_eq!(protected.status, STATUS_FAILED);
+    assert_eq!(sense[2] & 0x0F, 0x07); // DATA PROTECT
+    assert_eq!(mode_sense[2], 0x80); // WP
+    assert_eq!(unprotected.status, STATUS_PASSED);
+    assert_eq!(block_device.block(1), [0xAA; 512]);
+    assert_eq!(block_device.block(2), [0; 512]);
+    assert_eq!(block_device.block(3), [0xCC; 512]);
+}
+
+/// INQUIRY with EVPD set, for vital product data page `page`
+const fn vpd_inquiry(page: u8) -> [u8; 6] {
+    [0x12, 1, page, 0, 0xFF, 0]
+}
+
+#[test]
+fn vpd_pages() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut logical_unit = logical_unit(&mut block_device);
+    logical_unit.set_serial_number(b"SN1234");
+
+    let pages = run_logical_units([logical_unit], PACKET_SIZE, |usb| async move {
+        let mut pages = std::vec::Vec::new();
+        for (tag, page) in [0x00, 0x80, 0x83, 0xB0, 0xB1, 0xB2].into_iter().enumerate() {
+            let (data, csw) = usb
+                .command(tag as u32, 0, &vpd_inquiry(page), &[], 0xFF)
+                .await;
+            assert_eq!(csw.status, STATUS_PASSED);
+            pages.push(data);
+        }
+        pages
+    });
+
+    let [supported, serial, identification, limits, characteristics, provisioning] = &pages[..]
+    else {
+        unreachable!()
+    };
+    assert_eq!(
+        supported[..],
+        [0, 0x00, 0, 6, 0x00, 0x80, 0x83, 0xB0, 0xB1, 0xB2]
+    );
+    assert_eq!(serial[..], *b"\x00\x80\x00\x06SN1234");
+
+    assert_eq!(identification[..4], [0, 0x83, 0, 4 + 8 + 16 + 6]);
+    assert_eq!(identification[4..8], [0x02, 0x01, 0, 8 + 16 + 6]); // ASCII, T10 vendor ID
+    assert_eq!(&identification[8..16], VENDOR_IDENTIFICATION);
+    assert_eq!(&identification[16..32], PRODUCT_IDENTIFICATION);
+    assert_eq!(identification[32..], *b"SN1234");
+
+    assert_eq!(limits[..4], [0, 0xB0, 0, 0x3C]);
+    assert_eq!(limits[8..12], (u32::MAX / 512).to_be_bytes()); // maximum transfer length
+    assert_eq!(limits.len(), 64);
+
+    assert_eq!(characteristics[..6], [0, 0xB1, 0, 0x3C, 0, 1]); // non-rotating
+    assert_eq!(characteristics.len(), 64);
+
+    // the RAM disk doesn't discard, so can't unmap
+    assert_eq!(provisioning[..], [0, 0xB2, 0, 4, 0, 0, 1, 0]);
+}
+
+#[test]
+fn vpd_serial_number_defaults_to_spaces() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &vpd_inquiry(0x80), &[], 0xFF).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data[..], *b"\x00\x80\x00\x08        ");
+}
+
+#[test]
+fn vpd_truncated_to_allocation_length() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (data, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.command(1, 0, &[0x12, 1, 0xB0, 0, 4, 0], &[], 4).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data, [0, 0xB0, 0, 0x3C]);
+}
+
+#[test]
+fn inquiry_invalid_page_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (unsupported, without_evpd, sense) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let (_, unsupported) = usb.command(1, 0, &vpd_inquiry(0x99), &[], 0xFF).await;
+            let (_, without_evpd) = usb
+                .command(2, 0, &[0x12, 0, 0x80, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+            (unsupported, without_evpd, sense)
+        });
+
+    assert_eq!(unsupported.status, STATUS_FAILED);
+    assert_eq!(without_evpd.status, STATUS_FAILED);
+    assert_eq!(sense[2] & 0x0F, 0x05); // ILLEGAL REQUEST
+}
+
+#[test]
+fn responses_truncated_to_allocation_length() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (inquiry, inquiry_csw, sense, sense_csw) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let (inquiry, inquiry_csw) = usb.command(1, 0, &[0x12, 0, 0, 0, 5, 0], &[], 36).await;
+            let (sense, sense_csw) = usb.command(2, 0, &[0x03, 0, 0, 0, 18, 0], &[], 252).await;
+            (inquiry, inquiry_csw, sense, sense_csw)
+        });
+
+    assert_eq!(inquiry, [0x00, 0x80, 0x04, 0x02, 31]); // SPC-2, 31 more bytes
+    assert_eq!(inquiry_csw.status, STATUS_PASSED);
+    assert_eq!(inquiry_csw.data_residue, 31);
+    assert_eq!(sense.len(), 18);
+    assert_eq!(sense_csw.status, STATUS_PASSED);
+    assert_eq!(sense_csw.data_residue, 252 - 18);
+}
+
+#[test]
+fn responses_truncated_to_transfer_length() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (inquiry, inquiry_csw, luns, luns_csw) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let (inquiry, inquiry_csw) = usb.command(1, 0, &[0x12, 0, 0, 0, 36, 0], &[], 8).await;
+            let report_luns = [0xA0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+            let (luns, luns_csw) = usb.command(2, 0, &report_luns, &[], 4).await;
+            (inquiry, inquiry_csw, luns, luns_csw)
+        });
+
+    // no phase error, as the host asked for less than the response
+    assert_eq!(inquiry.len(), 8);
+    assert_eq!(inquiry_csw.status, STATUS_PASSED);
+    assert_eq!(inquiry_csw.data_residue, 0);
+    assert_eq!(luns, [0, 0, 0, 8]);
+    assert_eq!(luns_csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn sense_in_fixed_and_descriptor_formats() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (fixed, descriptor) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let read_past_end = [0x28, 0, 0, 0, 0, 16, 0, 0, 1, 0];
+        usb.command(1, 0, &read_past_end, &[], 512).await;
+        let (fixed, _) = usb
+            .command(2, 0, &[0x03, 0, 0, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+
+        usb.command(3, 0, &read_past_end, &[], 512).await;
+        let (descriptor, _) = usb
+            .command(4, 0, &[0x03, 1, 0, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        (fixed, descriptor)
+    });
+
+    // ILLEGAL REQUEST, LOGICAL BLOCK ADDRESS OUT OF RANGE
+    assert_eq!(
+        fixed,
+        [0x70, 0, 0x05, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0x21, 0x00, 0, 0, 0, 0]
+    );
+    assert_eq!(descriptor, [0x72, 0x05, 0x21, 0x00, 0, 0, 0, 0]);
+}
+
+#[test]
+fn sense_cleared_once_reported() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut read_only = logical_unit(&mut block_device);
+    read_only.set_read_only(true);
+
+    let (reported, again, after_another_command) =
+        run_logical_units([read_only], PACKET_SIZE, |usb| async move {
+            let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+            let len = REQUEST_SENSE[4] as u32;
+
+            usb.command(1, 0, &write, &[0xAA; 512], 0).await;
+            let (reported, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+            let (again, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+
+            usb.command(4, 0, &write, &[0xAA; 512], 0).await;
+            usb.command(5, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            let (after_another_command, _) = usb.command(6, 0, &REQUEST_SENSE, &[], len).await;
+
+            (reported, again, after_another_command)
+        });
+
+    // DATA PROTECT, WRITE PROTECTED
+    assert_eq!(
+        (reported[2], reported[12], reported[13]),
+        (0x07, 0x27, 0x00)
+    );
+    assert_eq!((again[2], again[12], again[13]), (0, 0, 0));
+    assert_eq!(after_another_command[2], 0);
+}
+
+#[test]
+fn power_on_unit_attention() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (inquiry_csw, first_csw, sense, second_csw) = run_logical_units_from_power_on(
+        [logical_unit(&mut block_device)],
+        PACKET_SIZE,
+        |usb| async move {
+            let test_unit_ready = [0x00, 0, 0, 0, 0, 0];
+            let (_, inquiry_csw) = usb.command(1, 0, &[0x12, 0, 0, 0, 36, 0], &[], 36).await;
+            let (_, first_csw) = usb.command(2, 0, &test_unit_ready, &[], 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+            let (_, second_csw) = usb.command(4, 0, &test_unit_ready, &[], 0).await;
+            (inquiry_csw, first_csw, sense, second_csw)
+        },
+    );
+
+    assert_eq!(inquiry_csw.status, STATUS_PASSED);
+    assert_eq!(first_csw.status, STATUS_FAILED);
+    // UNIT ATTENTION, POWER ON, RESET, OR BUS DEVICE RESET OCCURRED
+    assert_eq!((sense[2], sense[12], sense[13]), (0x06, 0x29, 0x00));
+    assert_eq!(second_csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn unit_attention_reported_by_request_sense() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (sense, csw) = run_logical_units_from_power_on(
+        [logical_unit(&mut block_device)],
+        PACKET_SIZE,
+        |usb| async move {
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(1, 0, &REQUEST_SENSE, &[], len).await;
+            let (_, csw) = usb.command(2, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            (sense, csw)
+        },
+    );
+
+    assert_eq!((sense[2], sense[12], sense[13]), (0x06, 0x29, 0x00));
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn reset_raises_unit_attention() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (csw, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        usb.bulk_only_mass_storage_reset().await;
+        let (_, csw) = usb.command(1, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        (csw, sense)
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+    assert_eq!((sense[2], sense[12], sense[13]), (0x06, 0x29, 0x00));
+}
+
+#[test]
+fn no_medium_not_ready() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut empty = logical_unit(&mut block_device);
+    assert!(empty.eject_medium().is_some());
+
+    let (inquiry_csw, ready_csw, read_csw, sense) =
+        run_logical_units([empty], PACKET_SIZE, |usb| async move {
+            let (_, inquiry_csw) = usb.command(1, 0, &[0x12, 0, 0, 0, 36, 0], &[], 36).await;
+            let (_, ready_csw) = usb.command(2, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            let read = [0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+            let (_, read_csw) = usb.command(3, 0, &read, &[], 512).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(4, 0, &REQUEST_SENSE, &[], len).await;
+            (inquiry_csw, ready_csw, read_csw, sense)
+        });
+
+    assert_eq!(inquiry_csw.status, STATUS_PASSED);
+    assert_eq!(ready_csw.status, STATUS_FAILED);
+    assert_eq!(read_csw.status, STATUS_FAILED);
+    assert_eq!(read_csw.data_residue, 512);
+    // NOT READY, MEDIUM NOT PRESENT
+    assert_eq!((sense[2], sense[12], sense[13]), (0x02, 0x3A, 0x00));
+}
+
+#[test]
+fn firmware_swaps_medium() {
+    let mut first = RamBlockDevice::<512>::new(16);
+    let mut second = RamBlockDevice::<512>::new(32);
+    let second = &mut second;
+    let medium = &RemovableMedium::new();
+
+    let (ready_csw, sense, capacity, removed_blocks) = run_removable_medium(
+        logical_unit(&mut first),
+        medium,
+        PACKET_SIZE,
+        |usb| async move {
+            medium.insert(second);
+            let (_, ready_csw) = usb.command(1, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+            let read_capacity = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            let (capacity, _) = usb.command(3, 0, &read_capacity, &[], 8).await;
+            let removed = medium.removed().await;
+            (ready_csw, sense, capacity, removed.block_count())
+        },
+    );
+
+    assert_eq!(ready_csw.status, STATUS_FAILED);
+    // UNIT ATTENTION, NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    assert_eq!((sense[2], sense[12], sense[13]), (0x06, 0x28, 0x00));
+    assert_eq!(capacity, [0, 0, 0, 31, 0, 0, 2, 0]);
+    assert_eq!(removed_blocks, 15);
+}
+
+#[test]
+fn firmware_ejects_medium() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let medium = &RemovableMedium::new();
+
+    let (ready_csw, sense) = run_removable_medium(
+        logical_unit(&mut block_device),
+        medium,
+        PACKET_SIZE,
+        |usb| async move {
+            medium.eject();
+            let (_, ready_csw) = usb.command(1, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+            medium.removed().await;
+            (ready_csw, sense)
+        },
+    );
+
+    assert_eq!(ready_csw.status, STATUS_FAILED);
+    // NOT READY, MEDIUM NOT PRESENT
+    assert_eq!((sense[2], sense[12], sense[13]), (0x02, 0x3A, 0x00));
+}
+
+#[test]
+fn host_ejects_medium() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let medium = &RemovableMedium::new();
+
+    let (eject_csw, request, ready_csw) = run_removable_medium(
+        logical_unit(&mut block_device),
+        medium,
+        PACKET_SIZE,
+        |usb| async move {
+            let eject = [0x1B, 0, 0, 0, 0x02, 0];
+            let (_, eject_csw) = usb.command(1, 0, &eject, &[], 0).await;
+            let request = medium.request().await;
+            medium.removed().await;
+            let (_, ready_csw) = usb.command(2, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            (eject_csw, request, ready_csw)
+        },
+    );
+
+    assert_eq!(eject_csw.status, STATUS_PASSED);
+    assert_eq!(request, MediumRequest::Eject);
+    assert_eq!(ready_csw.status, STATUS_FAILED);
+}
+
+#[test]
+fn prevented_removal_fails_host_eject() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let medium = &RemovableMedium::new();
+
+    let (prevented, eject_csw, sense, allowed, second_eject_csw) = run_removable_medium(
+        logical_unit(&mut block_device),
+        medium,
+        PACKET_SIZE,
+        |usb| async move {
+            let eject = [0x1B, 0, 0, 0, 0x02, 0];
+            usb.command(1, 0, &[0x1E, 0, 0, 0, 0x01, 0], &[], 0).await;
+            let prevented = (medium.request().await, medium.removal_prevented());
+
+            let (_, eject_csw) = usb.command(2, 0, &eject, &[], 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+
+            usb.command(4, 0, &[0x1E, 0, 0, 0, 0x00, 0], &[], 0).await;
+            let allowed = (medium.request().await, medium.removal_prevented());
+            let (_, second_eject_csw) = usb.command(5, 0, &eject, &[], 0).await;
+            (prevented, eject_csw, sense, allowed, second_eject_csw)
+        },
+    );
+
+    assert_eq!(prevented, (MediumRequest::PreventRemoval, true));
+    assert_eq!(eject_csw.status, STATUS_FAILED);
+    // ILLEGAL REQUEST, MEDIUM REMOVAL PREVENTED
+    assert_eq!((sense[2], sense[12], sense[13]), (0x05, 0x53, 0x02));
+    assert_eq!(allowed, (MediumRequest::AllowRemoval, false));
+    assert_eq!(second_eject_csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn writes_cached_until_synchronized() {
+    let mut cached = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+    let mut synchronized = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    run_device(&mut cached, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+        usb.command(1, 0, &write, &[0xAA; 512], 0).await;
+    });
+    let csws = run_device(&mut synchronized, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+        usb.command(1, 0, &write, &[0xAA; 512], 0).await;
+        let synchronize_cache_10 = [0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let (_, csw_10) = usb.command(2, 0, &synchronize_cache_10, &[], 0).await;
+        let synchronize_cache_16 = [0x91, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0];
+        let (_, csw_16) = usb.command(3, 0, &synchronize_cache_16, &[], 0).await;
+        [csw_10.status, csw_16.status]
+    });
+
+    assert_eq!(cached.get_ref().block(3), [0; 512]);
+    assert_eq!(csws, [STATUS_PASSED; 2]);
+    assert_eq!(synchronized.get_ref().block(3), [0xAA; 512]);
+}
+
+#[test]
+fn synchronize_cache_out_of_range_fails() {
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    let (_, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let synchronize_cache = [0x35, 0, 0, 0, 0, 16, 0, 0, 0, 0];
+        usb.command(1, 0, &synchronize_cache, &[], 0).await
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+}
+
+#[test]
+fn force_unit_access_writes_through() {
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write_fua = [0x2A, 0b1000, 0, 0, 0, 3, 0, 0, 1, 0];
+        usb.command(1, 0, &write_fua, &[0xAA; 512], 0).await;
+    });
+
+    assert_eq!(block_device.get_ref().block(3), [0xAA; 512]);
+}
+
+#[test]
+fn write_cache_reported_and_disabled() {
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    let (mode_sense, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (mode_sense, _) = usb
+            .command(1, 0, &[0x1A, 0, 0x08, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+
+        // write cache disabled, read cache disabled
+        let parameters = caching_page_parameters(0b001);
+        let mode_select = [0x15, 0x10, 0, 0, parameters.len() as u8, 0];
+        let (_, csw) = usb.command(2, 0, &mode_select, &parameters, 0).await;
+
+        let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+        usb.command(3, 0, &write, &[0xAA; 512], 0).await;
+        (mode_sense, csw)
+    });
+
+    assert_eq!(mode_sense[2], 0x10); // DPOFUA
+    assert_eq!(mode_sense[12..15], [0x88, 18, 0b101]); // write cache enabled
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(block_device.get_ref().block(3), [0xAA; 512]);
+}
+
+#[test]
+fn disabling_write_cache_flushes_it() {
+    let mut block_device = WriteBackCache::<_, 4>::new(RamBlockDevice::<512>::new(16));
+
+    let csw = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+        usb.command(1, 0, &write, &[0xAA; 512], 0).await;
+
+        // write cache disabled, read cache disabled
+        let parameters = caching_page_parameters(0b001);
+        let mode_select = [0x15, 0x10, 0, 0, parameters.len() as u8, 0];
+        let (_, csw) = usb.command(2, 0, &mode_select, &parameters, 0).await;
+        csw
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(block_device.get_ref().block(3), [0xAA; 512]);
+}
+
+#[test]
+fn verify_reads_back_the_medium() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (_, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let verify = [0x2F, 0, 0, 0, 0, 0, 0, 0, 16, 0];
+        usb.command(1, 0, &verify, &[], 0).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+}
+
+#[test]
+fn verify_compares_each_block() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (matched, miscompared, fixed, descriptor) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let write = [0x2A, 0, 0, 0, 0, 2, 0, 0, 2, 0];
+            let blocks = [[0xAA; 512], [0xBB; 512]].concat();
+            usb.command(1, 0, &write, &blocks, 0).await;
+
+            let verify = [0x2F, 0b010, 0, 0, 0, 2, 0, 0, 2, 0];
+            let (_, matched) = usb.command(2, 0, &verify, &blocks, 0).await;
+            let (_, miscompared) = usb.command(3, 0, &verify, &[0xAA; 1024], 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (fixed, _) = usb.command(4, 0, &REQUEST_SENSE, &[], len).await;
+
+            usb.command(5, 0, &verify, &[0xAA; 1024], 0).await;
+            let (descriptor, _) = usb
+                .command(6, 0, &[0x03, 1, 0, 0, 0xFF, 0], &[], 0xFF)
+                .await;
+            (matched, miscompared, fixed, descriptor)
+        });
+
+    assert_eq!(matched.status, STATUS_PASSED);
+    assert_eq!(miscompared.status, STATUS_FAILED);
+    // MISCOMPARE, MISCOMPARE DURING VERIFY OPERATION, at LBA 3
+    assert_eq!(
+        fixed,
+        [0xF0, 0, 0x0E, 0, 0, 0, 3, 10, 0, 0, 0, 0, 0x1D, 0x00, 0, 0, 0, 0]
+    );
+    assert_eq!(
+        descriptor,
+        [0x72, 0x0E, 0x1D, 0x00, 0, 0, 0, 12, 0x00, 0x0A, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 3]
+    );
+}
+
+#[test]
+fn verify_compares_single_block() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (matched, miscompared) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let verify = [0x2F, 0b110, 0, 0, 0, 0, 0, 0, 4, 0];
+        let (_, matched) = usb.command(1, 0, &verify, &[0; 512], 0).await;
+        let (_, miscompared) = usb.command(2, 0, &verify, &[0xAA; 512], 0).await;
+        (matched, miscompared)
+    });
+
+    assert_eq!(matched.status, STATUS_PASSED);
+    assert_eq!(matched.data_residue, 0);
+    assert_eq!(miscompared.status, STATUS_FAILED);
+}
+
+#[test]
+fn verify_blocks_larger_than_default_buffer() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_, 4096, 4096> =
+        FlashBlockDevice::new(flash, 0, 16384, 16384);
+    let block: std::vec::Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+
+    let (each, single, miscompared, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| {
+        let block = block.clone();
+        async move {
+            // the last block differs in its last byte, in the second window compared
+            let mut blocks = [&block[..], &block[..], &block[..]].concat();
+            blocks[3 * 4096 - 1] ^= 0xFF;
+            let write = [0x2A, 0, 0, 0, 0, 1, 0, 0, 3, 0];
+            usb.command(1, 0, &write, &blocks, 0).await;
+
+            let verify = [0x2F, 0b010, 0, 0, 0, 1, 0, 0, 2, 0];
+            let (_, each) = usb.command(2, 0, &verify, &blocks[..2 * 4096], 0).await;
+            let verify = [0x2F, 0b110, 0, 0, 0, 1, 0, 0, 2, 0];
+            let (_, single) = usb.command(3, 0, &verify, &block, 0).await;
+            let verify = [0x2F, 0b110, 0, 0, 0, 1, 0, 0, 3, 0];
+            let (_, miscompared) = usb.command(4, 0, &verify, &block, 0).await;
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(5, 0, &REQUEST_SENSE, &[], len).await;
+            (each, single, miscompared, sense)
+        }
+    });
+
+    assert_eq!(each.status, STATUS_PASSED);
+    assert_eq!(single.status, STATUS_PASSED);
+    assert_eq!(miscompared.status, STATUS_FAILED);
+    // MISCOMPARE, MISCOMPARE DURING VERIFY OPERATION, at LBA 3
+    assert_eq!(sense[..7], [0xF0, 0, 0x0E, 0, 0, 0, 3]);
+    assert_eq!(sense[12..14], [0x1D, 0x00]);
+}
+
+#[test]
+fn verify_out_of_range_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (_, csw) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let verify = [0x2F, 0, 0, 0, 0, 15, 0, 0, 2, 0];
+        usb.command(1, 0, &verify, &[], 0).await
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+}
+
+#[test]
+fn format_unit_erases_medium() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let csw = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 14, 0, 0, 2, 0];
+        usb.command(1, 0, &write, &[0xAA; 1024], 0).await;
+
+        let (_, csw) = usb.command(2, 0, &[0x04, 0, 0, 0, 0, 0], &[], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(block_device.block(14), [0; 512]);
+    assert_eq!(block_device.block(15), [0; 512]);
+}
+
+#[test]
+fn format_unit_erases_flash() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_, 4096, 4096> =
+        FlashBlockDevice::new(flash, 0, 16384, 16384);
+
+    let csw = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 0, 0, 0, 4, 0];
+        usb.command(1, 0, &write, &[0xAA; 4 * 4096], 0).await;
+
+        let (_, csw) = usb.command(2, 0, &[0x04, 0, 0, 0, 0, 0], &[], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    let flash = block_device.release();
+    assert_eq!(flash.erases, [1, 1, 1, 1, 0]);
+    assert_eq!(flash.bytes()[..16384], [0xFF; 16384]);
+}
+
+#[test]
+fn format_unit_in_background() {
+    let mut block_device = RamBlockDevice::<512>::new(64);
+
+    let (format, progress, ready) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 63, 0, 0, 1, 0];
+        usb.command(1, 0, &write, &[0xAA; 512], 0).await;
+
+        // FMTDATA, with the short header's FOV and IMMED
+        let format = [0x04, 0x10, 0, 0, 0, 0];
+        let (_, format) = usb.command(2, 0, &format, &[0, 0x82, 0, 0], 0).await;
+
+        let mut progress = Vec::new();
+        let mut ready = None;
+        for tag in 3..100 {
+            let (_, csw) = usb.command(tag, 0, &[0x00, 0, 0, 0, 0, 0], &[], 0).await;
+            if csw.status == STATUS_PASSED {
+                ready = Some(csw);
+                break;
+            }
+            let len = REQUEST_SENSE[4] as u32;
+            let (sense, _) = usb.command(tag, 0, &REQUEST_SENSE, &[], len).await;
+            progress.push(sense);
+        }
+        (format, progress, ready)
+    });
+
+    assert_eq!(format.status, STATUS_PASSED);
+    assert!(ready.is_some());
+    assert!(!progress.is_empty());
+    let mut last = 0;
+    for sense in progress {
+        // NOT READY, FORMAT IN PROGRESS, with SKSV and the progress indication
+        assert_eq!(sense[2], 0x02);
+        assert_eq!(sense[12..14], [0x04, 0x04]);
+        assert_eq!(sense[15], 0x80);
+        let progress = u16::from_be_bytes([sense[16], sense[17]]);
+        assert!(progress >= last);
+        last = progress;
+    }
+    assert!(last > 0);
+    assert_eq!(block_device.block(63), [0; 512]);
+}
+
+#[test]
+fn unit_attention_reported_during_format() {
+    let mut block_device = RamBlockDevice::<512>::new(4096);
+
+    let (attention, progress) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        // IMMED
+        let format = [0x04, 0x10, 0, 0, 0, 0];
+        usb.command(1, 0, &format, &[0, 0x02, 0, 0], 0).await;
+        usb.bulk_only_mass_storage_reset().await;
+
+        let len = REQUEST_SENSE[4] as u32;
+        let (attention, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        let (progress, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+        (attention, progress)
+    });
+
+    // UNIT ATTENTION, POWER ON, RESET, OR BUS DEVICE RESET OCCURRED
+    assert_eq!(
+        (attention[2], attention[12], attention[13]),
+        (0x06, 0x29, 0x00)
+    );
+    // then NOT READY, FORMAT IN PROGRESS
+    assert_eq!(
+        (progress[2], progress[12], progress[13]),
+        (0x02, 0x04, 0x04)
+    );
+}
+
+#[test]
+fn format_unit_progress_in_descriptor_sense() {
+    let mut block_device = RamBlockDevice::<512>::new(64);
+
+    let sense = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let format = [0x04, 0x10, 0, 0, 0, 0];
+        usb.command(1, 0, &format, &[0, 0x82, 0, 0], 0).await;
+        let (sense, _) = usb
+            .command(2, 0, &[0x03, 1, 0, 0, 0xFF, 0], &[], 0xFF)
+            .await;
+        sense
+    });
+
+    assert_eq!(sense[..8], [0x72, 0x02, 0x04, 0x04, 0, 0, 0, 8]);
+    assert_eq!(sense[8..13], [0x02, 0x06, 0, 0, 0x80]);
+    assert_eq!(sense.len(), 16);
+}
+
+#[test]
+fn format_unit_with_defect_list_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (csw, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        // FMTDATA and LONGLIST, with a 4 byte defect list
+        let format = [0x04, 0x30, 0, 0, 0, 0];
+        let header = [0, 0x80, 0, 0, 0, 0, 0, 4];
+        let (_, csw) = usb.command(1, 0, &format, &header, 0).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        (csw, sense)
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+    // ILLEGAL REQUEST, INVALID FIELD IN PARAMETER LIST
+    assert_eq!(sense[2], 0x05);
+    assert_eq!(sense[12..14], [0x26, 0x00]);
+}
+
+#[test]
+fn read_format_capacities() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let ((data, csw), (truncated, truncated_csw)) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let command = [0x23, 0, 0, 0, 0, 0, 0, 0, 0xFC, 0, 0, 0];
+            let full = usb.command(1, 0, &command, &[], 0xFC).await;
+            let command = [0x23, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0, 0];
+            let truncated = usb.command(2, 0, &command, &[], 12).await;
+            (full, truncated)
+        });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(
+        data,
+        [
+            0, 0, 0, 16, // capacity list length
+            0, 0, 0, 16, 0x02, 0, 2, 0, // 16 formatted 512 byte blocks
+            0, 0, 0, 16, 0x00, 0, 2, 0, // which is what it can be formatted to
+        ]
+    );
+    assert_eq!(csw.data_residue, 0xFC - 20);
+    assert_eq!(truncated_csw.status, STATUS_PASSED);
+    assert_eq!(truncated, data[..12]);
+}
+
+#[test]
+fn read_format_capacities_without_medium() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    let mut empty = logical_unit(&mut block_device);
+    assert!(empty.eject_medium().is_some());
+
+    let (data, csw) = run_logical_units([empty], PACKET_SIZE, |usb| async move {
+        let command = [0x23, 0, 0, 0, 0, 0, 0, 0, 0xFC, 0, 0, 0];
+        usb.command(1, 0, &command, &[], 0xFC).await
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(data, [0, 0, 0, 8, 0, 0, 0, 0, 0x03, 0, 2, 0]);
+}
+
+#[test]
+fn send_diagnostic_self_test() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (csw, results) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        // SELFTEST, the default self-test
+        let (_, csw) = usb.command(1, 0, &[0x1D, 0x04, 0, 0, 0, 0], &[], 0).await;
+        let receive = [0x1C, 1, 0x80, 0, 0xFF, 0];
+        let (results, _) = usb.command(2, 0, &receive, &[], 0xFF).await;
+        (csw, results)
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(results, [0x80, 0, 0, 4, 0x00, 0, 0, 0]);
+}
+
+#[test]
+fn send_diagnostic_self_test_blocks_larger_than_default_buffer() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_, 4096, 4096> =
+        FlashBlockDevice::new(flash, 0, 16384, 16384);
+
+    let (csw, results) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (_, csw) = usb.command(1, 0, &[0x1D, 0x04, 0, 0, 0, 0], &[], 0).await;
+        let receive = [0x1C, 1, 0x80, 0, 0xFF, 0];
+        let (results, _) = usb.command(2, 0, &receive, &[], 0xFF).await;
+        (csw, results)
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    assert_eq!(results, [0x80, 0, 0, 4, 0, 0, 0, 0]);
+}
+
+#[test]
+fn send_diagnostic_self_test_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+    block_device.unreadable = Some(15);
+
+    let (csw, sense, results) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let (_, csw) = usb.command(1, 0, &[0x1D, 0x04, 0, 0, 0, 0], &[], 0).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        let receive = [0x1C, 1, 0x80, 0, 0xFF, 0];
+        let (results, _) = usb.command(3, 0, &receive, &[], 0xFF).await;
+        (csw, sense, results)
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+    // HARDWARE ERROR, LOGICAL UNIT FAILED SELF-TEST
+    assert_eq!((sense[2], sense[12], sense[13]), (0x04, 0x3E, 0x03));
+    // failed, with MEDIUM ERROR, UNRECOVERED READ ERROR
+    assert_eq!(results, [0x80, 0, 0, 4, 0x04, 0x03, 0x11, 0x00]);
+}
+
+#[test]
+fn diagnostic_pages() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (before, supported, sent, results) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let receive = [0x1C, 0, 0, 0, 0xFF, 0];
+            let (before, _) = usb.command(1, 0, &receive, &[], 0xFF).await;
+            let (supported, _) = usb.command(2, 0, &receive, &[], 0xFF).await;
+
+            // PF, sending the self-test results page, runs the self-test
+            let send = [0x1D, 0x10, 0, 0, 4, 0];
+            let (_, sent) = usb.command(3, 0, &send, &[0x80, 0, 0, 0], 0).await;
+            let (results, _) = usb.command(4, 0, &receive, &[], 0xFF).await;
+            (before, supported, sent, results)
+        });
+
+    assert_eq!(before, [0x00, 0, 0, 2, 0x00, 0x80]);
+    assert_eq!(supported, before);
+    assert_eq!(sent.status, STATUS_PASSED);
+    assert_eq!(results, [0x80, 0, 0, 4, 0x00, 0, 0, 0]);
+}
+
+#[test]
+fn diagnostic_page_not_supported() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (sent, received, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let send = [0x1D, 0x10, 0, 0, 4, 0];
+        let (_, sent) = usb.command(1, 0, &send, &[0x40, 0, 0, 0], 0).await;
+        let receive = [0x1C, 1, 0x40, 0, 0xFF, 0];
+        let (_, received) = usb.command(2, 0, &receive, &[], 0xFF).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(3, 0, &REQUEST_SENSE, &[], len).await;
+        (sent, received, sense)
+    });
+
+    assert_eq!(sent.status, STATUS_FAILED);
+    assert_eq!(received.status, STATUS_FAILED);
+    // ILLEGAL REQUEST, INVALID FIELD IN CDB
+    assert_eq!((sense[2], sense[12], sense[13]), (0x05, 0x24, 0x00));
+}
+
+#[test]
+fn unmap_erases_flash() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_> = FlashBlockDevice::new(flash, 0, 16384, 16384);
+
+    let (provisioning, limits, capacity, unmap_csw) =
+        run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+            let (provisioning, _) = usb.command(1, 0, &vpd_inquiry(0xB2), &[], 0xFF).await;
+            let (limits, _) = usb.command(2, 0, &vpd_inquiry(0xB0), &[], 0xFF).await;
+            let cb = [0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0];
+            let (capacity, _) = usb.command(3, 0, &cb, &[], 32).await;
+
+            let write = [0x2A, 0, 0, 0, 0, 0, 0, 0, 32, 0];
+            usb.command(4, 0, &write, &[0xAA; 32 * 512], 0).await;
+
+            // blocks 4 to 19, the whole of only the second sector
+            let unmap = [0x42, 0, 0, 0, 0, 0, 0, 0, 24, 0];
+            let mut parameters = std::vec![0, 22, 0, 16, 0, 0, 0, 0];
+            parameters.extend([0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 16, 0, 0, 0, 0]);
+            let (_, unmap_csw) = usb.command(5, 0, &unmap, &parameters, 0).await;
+            (provisioning, limits, capacity, unmap_csw)
+        });
+
+    // LBPU, LBPWS, LBPWS10, resource provisioned
+    assert_eq!(provisioning[..], [0, 0xB2, 0, 4, 0, 0xE0, 1, 0]);
+    assert_eq!(limits[20..28], [0xFF; 8]); // maximum unmap LBA and descriptor counts
+    assert_eq!(capacity[14], 0x80); // LBPME
+    assert_eq!(unmap_csw.status, STATUS_PASSED);
+
+    let flash = block_device.release();
+    assert_eq!(flash.erases, [0, 1, 0, 0, 0]);
+    assert_eq!(flash.bytes()[..4096], [0xAA; 4096]);
+    assert_eq!(flash.bytes()[4096..8192], [0xFF; 4096]);
+    assert_eq!(flash.bytes()[8192..16384], [0xAA; 8192]);
+}
+
+#[test]
+fn unmap_out_of_range_fails() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (csw, sense) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let unmap = [0x42, 0, 0, 0, 0, 0, 0, 0, 24, 0];
+        let mut parameters = std::vec![0, 22, 0, 16, 0, 0, 0, 0];
+        parameters.extend([0, 0, 0, 0, 0, 0, 0, 12, 0, 0, 0, 8, 0, 0, 0, 0]);
+        let (_, csw) = usb.command(1, 0, &unmap, &parameters, 0).await;
+        let len = REQUEST_SENSE[4] as u32;
+        let (sense, _) = usb.command(2, 0, &REQUEST_SENSE, &[], len).await;
+        (csw, sense)
+    });
+
+    assert_eq!(csw.status, STATUS_FAILED);
+    // ILLEGAL REQUEST, LOGICAL BLOCK ADDRESS OUT OF RANGE
+    assert_eq!(sense[2], 0x05);
+    assert_eq!(sense[12..14], [0x21, 0x00]);
+}
+
+#[test]
+fn write_same_writes_every_block() {
+    let mut block_device = RamBlockDevice::<512>::new(16);
+
+    let (same_10, same_16) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write_same = [0x41, 0, 0, 0, 0, 2, 0, 0, 3, 0];
+        let (_, same_10) = usb.command(1, 0, &write_same, &[0xAA; 512], 0).await;
+
+        // with UNMAP, which is written anyway as the RAM disk doesn't discard, to the end
+        let mut write_same = [0x93, 0b1000, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0];
+        let (_, same_16) = usb.command(2, 0, &write_same, &[0xBB; 512], 0).await;
+        write_same[1] = 0b1;
+        write_same[9] = 14;
+        usb.command(3, 0, &write_same, &[], 0).await;
+        (same_10, same_16)
+    });
+
+    assert_eq!(same_10.status, STATUS_PASSED);
+    assert_eq!(same_16.status, STATUS_PASSED);
+    for lba in 0..16 {
+        let expected = match lba {
+            2..=4 => 0xAA,
+            12 | 13 => 0xBB,
+            _ => 0,
+        };
+        assert_eq!(block_device.block(lba), [expected; 512], "lba {}", lba);
+    }
+}
+
+#[test]
+fn write_same_unmaps_flash() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_> = FlashBlockDevice::new(flash, 0, 16384, 16384);
+
+    let csw = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write = [0x2A, 0, 0, 0, 0, 0, 0, 0, 32, 0];
+        usb.command(1, 0, &write, &[0xAA; 32 * 512], 0).await;
+
+        let write_same = [0x93, 0b1000, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 8, 0, 0];
+        let (_, csw) = usb.command(2, 0, &write_same, &[0; 512], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    let flash = block_device.release();
+    assert_eq!(flash.erases, [0, 1, 0, 0, 0]);
+    assert_eq!(flash.bytes()[4096..8192], [0xFF; 4096]);
+}
+
+#[test]
+fn write_same_to_the_end_of_a_large_medium() {
+    let blocks = 0x1_0000_0010;
+    let mut block_device = SparseBlockDevice::new(blocks);
+
+    let csw = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        // with UNMAP, from LBA 5 to the end
+        let write_same = [0x93, 0b1001, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0];
+        let (_, csw) = usb.command(1, 0, &write_same, &[], 0).await;
+        csw
+    });
+
+    assert_eq!(csw.status, STATUS_PASSED);
+    let rest = (blocks - 5 - u32::MAX as u64) as u32;
+    assert_eq!(
+        block_device.discarded,
+        [(5, u32::MAX), (5 + u32::MAX as u64, rest)]
+    );
+}
+
+#[test]
+fn write_same_blocks_larger_than_default_buffer() {
+    let flash: SimulatedFlash = SimulatedFlash::new(5);
+    let mut block_device: FlashBlockDevice<_, 4096, 4096> =
+        FlashBlockDevice::new(flash, 0, 16384, 16384);
+
+    let (uniform, mixed) = run_device(&mut block_device, PACKET_SIZE, |usb| async move {
+        let write_same = [0x41, 0, 0, 0, 0, 1, 0, 0, 2, 0];
+        let (_, uniform) = usb.command(1, 0, &write_same, &[0xAA; 4096], 0).await;
+
+        // can't be held, so isn't written
+        let mut block = [0xBB; 4096];
+        block[4095] = 0;
+        let write_same = [0x41, 0, 0, 0, 0, 3, 0, 0, 1, 0];
+        let (_, mixed) = usb.command(2, 0, &write_same, &block, 0).await;
+        (uniform, mixed)
+    });
+
+    assert_eq!(uniform.status, STATUS_PASSED);
+    assert_eq!(mixed.status, STATUS_FAILED);
+    let flash = block_device.release();
+    assert_eq!(flash.bytes()[..4096], [0xFF; 4096]);
+    assert_eq!(flash.bytes()[4096..12288], [0xAA; 8192]);
+    assert_eq!(flash.bytes()[12288..16384], [0xFF; 4096]);
+}
@@ -29,6 +29,16 @@ impl<'d, D: Driver<'d>, M: RawMutex> Endpoints<'d, D, M> {
             reset_signal,
         }
     }
+
+    pub fn packet_size(&self) -> u16 {
+        self.in_ep.info().max_packet_size
+    }
+
+    /// Wait until the host has configured the device, enabling both endpoints
+    pub async fn wait_enabled(&mut self) {
+        self.in_ep.wait_enabled().await;
+        self.out_ep.wait_enabled().await;
+    }
 }
 
 impl From<EndpointError> for TransportError {
@@ -55,28 +65,32 @@ impl<'d, D: Driver<'d>, M: RawMutex> ErrorType for Endpoints<'d, D, M> {
 
 impl<'d, D: Driver<'d>, M: RawMutex> Read for Endpoints<'d, D, M> {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let read_future = self.out_ep.read(buf);
+        // a pending reset takes priority over any data the host sent after it
         let reset_future = self.reset_signal.wait();
-        match select(read_future, reset_future).await {
-            Either::First(read_result) => match read_result {
+        let read_future = self.out_ep.read(buf);
+        match select(reset_future, read_future).await {
+            Either::First(()) => Err(TransportError::Reset()),
+            Either::Second(read_result) => match read_result {
                 Ok(count) => Ok(count),
                 Err(e) => Err(e.into()),
             },
-            Either::Second(_) => Err(TransportError::Reset()),
         }
     }
 }
 
 impl<'d, D: Driver<'d>, M: RawMutex> embedded_io_async::Write for Endpoints<'d, D, M> {
+    /// Writes up to one packet from `buf`. An empty `buf` sends a zero length packet
     async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let write_future = self.in_ep.write(buf);
+        // the endpoint takes at most one packet per write, `write_all` loops for the rest
+        let len = buf.len().min(self.in_ep.info().max_packet_size as usize);
         let reset_future = self.reset_signal.wait();
-        match select(write_future, reset_future).await {
-            Either::First(write_result) => match write_result {
-                Ok(()) => Ok(buf.len()),
+        let write_future = self.in_ep.write(&buf[..len]);
+        match select(reset_future, write_future).await {
+            Either::First(()) => Err(TransportError::Reset()),
+            Either::Second(write_result) => match write_result {
+                Ok(()) => Ok(len),
                 Err(e) => Err(e.into()),
             },
-            Either::Second(()) => Err(TransportError::Reset()),
         }
     }
 }
@@ -5,16 +5,21 @@ use defmt::Format;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::signal::Signal;
 use embassy_usb::control::InResponse;
+use embassy_usb::control::OutResponse;
 use embassy_usb::control::Recipient;
 use embassy_usb::control::Request;
 use embassy_usb::control::RequestType;
 use embassy_usb::driver::Driver;
 use embassy_usb::driver::EndpointError;
+use embassy_usb::types::InterfaceNumber;
 use embassy_usb::Builder;
 
 use crate::bulk_only_transport::CommandError;
 use crate::scsi::BlockDevice;
+use crate::scsi::LogicalUnit;
+use crate::scsi::RemovableMedium;
 use crate::scsi::Scsi;
+use crate::scsi::MAX_LUNS;
 
 use self::endpoints::Endpoints;
 
@@ -27,6 +32,7 @@ const PROTOCOL_BULK_ONLY_TRANSPORT: u8 = 0x50;
 const CLASS_SPECIFIC_BULK_ONLY_MASS_STORAGE_RESET: u8 = 0xFF;
 const CLASS_SPECIFIC_GET_MAX_LUN: u8 = 0xFE;
 
+/// Errors reading from or writing to the bulk endpoints
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Format)]
 pub enum TransportError {
     Endpoint(EndpointError),
@@ -40,64 +46,98 @@ impl From<TransportError> for CommandError {
     }
 }
 
-pub struct UsbMassStorage<'d, 'bd, D: Driver<'d>, BD: BlockDevice, M: RawMutex> {
-    scsi: Scsi<'d, 'bd, D, BD, M>,
+/// A USB mass storage class function, exposing `LUNS` logical units to the host over SCSI and
+/// bulk-only transport
+pub struct UsbMassStorage<'d, 'bd, D: Driver<'d>, BD: BlockDevice, M: RawMutex, const LUNS: usize> {
+    scsi: Scsi<'d, 'bd, D, BD, M, LUNS>,
 }
 
-impl<'d, 'bd, D: Driver<'d>, BD: BlockDevice, M: RawMutex> UsbMassStorage<'d, 'bd, D, BD, M> {
-    #[allow(clippy::too_many_arguments)]
+impl<'d, 'bd, D: Driver<'d>, BD: BlockDevice, M: RawMutex, const LUNS: usize>
+    UsbMassStorage<'d, 'bd, D, BD, M, LUNS>
+{
+    /// Adds the mass storage interface and its endpoints to `builder`
+    ///
+    /// `packet_size` is the max packet size of the bulk endpoints (8, 16, 32 or 64 for full speed,
+    /// 512 for high speed)
+    ///
+    /// `logical_units` are addressed by the host by their index, between 1 and [`MAX_LUNS`] of
+    /// them. See [`Scsi::new`]
     pub fn new(
         state: &'d mut State<'d, M>,
         builder: &mut Builder<'d, D>,
         packet_size: u16,
-        max_lun: u8,
-        block_device: &'bd mut BD,
-        vendor_identification: &[u8; 8],
-        product_identification: &[u8; 16],
-        product_revision_level: &[u8; 4],
+        logical_units: [LogicalUnit<'bd, BD>; LUNS],
     ) -> Self {
-        let mut func = builder.function(
-            CLASS_MASS_STORAGE,
-            SUBCLASS_SCSI,
-            PROTOCOL_BULK_ONLY_TRANSPORT,
-        );
-        let mut interface = func.interface();
-        let mut alt = interface.alt_setting(
-            CLASS_MASS_STORAGE,
-            SUBCLASS_SCSI,
-            PROTOCOL_BULK_ONLY_TRANSPORT,
-            None,
-        );
-        let endpoints = Endpoints::new(
-            alt.endpoint_bulk_in(packet_size),
-            alt.endpoint_bulk_out(packet_size),
-            &state.reset_signal,
+        assert!(
+            (1..=MAX_LUNS).contains(&LUNS),
+            "between 1 and 16 logical units are supported"
         );
-        drop(func);
+        let max_lun = (LUNS - 1) as u8;
 
-        let control = state.control.write(Control {
-            reset_signal: &state.reset_signal,
-            max_lun,
-        });
-        builder.handler(control);
-
-        let scsi = Scsi::new(
-            endpoints,
-            block_device,
-            vendor_identification,
-            product_identification,
-            product_revision_level,
-            packet_size,
-        );
+        let endpoints = add_function(state, builder, packet_size, max_lun);
+        let scsi = Scsi::new(endpoints, logical_units);
 
         Self { scsi }
     }
 
+    /// The logical unit addressed by `lun`
+    pub fn logical_unit(&mut self, lun: u8) -> Option<&mut LogicalUnit<'bd, BD>> {
+        self.scsi.logical_unit(lun)
+    }
+
+    /// Let the firmware insert, eject and swap the medium of the logical unit addressed by `lun`
+    /// through `removable_medium`, while the host is connected. See [`Scsi::set_removable_medium`]
+    pub fn set_removable_medium(
+        &mut self,
+        lun: u8,
+        removable_medium: &'bd RemovableMedium<'bd, M, BD>,
+    ) {
+        self.scsi.set_removable_medium(lun, removable_medium);
+    }
+
+    /// Serve the host's requests. This must be run alongside the [`UsbDevice`](embassy_usb::UsbDevice)
     pub async fn run(&mut self) -> ! {
         self.scsi.run().await
     }
 }
 
+/// Adds the mass storage function to `builder`, returning its bulk endpoints. The class-specific
+/// control requests are handled from `state`.
+pub(crate) fn add_function<'d, D: Driver<'d>, M: RawMutex>(
+    state: &'d mut State<'d, M>,
+    builder: &mut Builder<'d, D>,
+    packet_size: u16,
+    max_lun: u8,
+) -> Endpoints<'d, D, M> {
+    let mut func = builder.function(
+        CLASS_MASS_STORAGE,
+        SUBCLASS_SCSI,
+        PROTOCOL_BULK_ONLY_TRANSPORT,
+    );
+    let mut interface = func.interface();
+    let interface_number = interface.interface_number();
+    let mut alt = interface.alt_setting(
+        CLASS_MASS_STORAGE,
+        SUBCLASS_SCSI,
+        PROTOCOL_BULK_ONLY_TRANSPORT,
+        None,
+    );
+    let endpoints = Endpoints::new(
+        alt.endpoint_bulk_in(packet_size),
+        alt.endpoint_bulk_out(packet_size),
+        &state.reset_signal,
+    );
+    drop(func);
+
+    let control = state
+        .control
+        .write(Control::new(&state.reset_signal, interface_number, max_lun));
+    builder.handler(control);
+
+    endpoints
+}
+
+/// Storage for the parts of [`UsbMassStorage`] that the USB device needs to reference
 pub struct State<'d, M: RawMutex> {
     reset_signal: Signal<M, ()>,
     control: MaybeUninit<Control<'d, M>>,
@@ -112,34 +152,79 @@ impl<'d, M: RawMutex> Default for State<'d, M> {
     }
 }
 
+/// Handles the class-specific control requests
+///
+/// A Bulk-Only Mass Storage Reset, or a USB bus reset, is passed on to the transport through
+/// `reset_signal`, abandoning any command in progress.
 pub struct Control<'d, M: RawMutex> {
     reset_signal: &'d Signal<M, ()>,
+    interface_number: InterfaceNumber,
     max_lun: u8,
 }
 
+impl<'d, M: RawMutex> Control<'d, M> {
+    pub fn new(
+        reset_signal: &'d Signal<M, ()>,
+        interface_number: InterfaceNumber,
+        max_lun: u8,
+    ) -> Self {
+        Self {
+            reset_signal,
+            interface_number,
+            max_lun,
+        }
+    }
+
+    /// Is `req` a class request to our interface
+    fn is_ours(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == u8::from(self.interface_number) as u16
+    }
+}
+
 impl<'d, M: RawMutex> embassy_usb::Handler for Control<'d, M> {
-    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
-        // not interested in this request
-        if !(req.request_type == RequestType::Class && req.recipient == Recipient::Interface) {
+    fn reset(&mut self) {
+        self.reset_signal.signal(());
+    }
+
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) {
             return None;
         }
 
-        info!("usb: bbb: Recv ctrl_in: {}", req);
+        info!("usb: bbb: Recv ctrl_out: {}", req);
 
         match req.request {
             // Spec. section 3.1
             CLASS_SPECIFIC_BULK_ONLY_MASS_STORAGE_RESET => {
+                if req.value != 0 || req.length != 0 {
+                    return Some(OutResponse::Rejected);
+                }
                 self.reset_signal.signal(());
-                Some(InResponse::Accepted(&[]))
+                Some(OutResponse::Accepted)
             }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        info!("usb: bbb: Recv ctrl_in: {}", req);
+
+        match req.request {
             // Spec. section 3.2
             CLASS_SPECIFIC_GET_MAX_LUN => {
-                // always respond with LUN
-                assert!(!buf.is_empty());
+                if req.value != 0 || req.length != 1 {
+                    return Some(InResponse::Rejected);
+                }
                 buf[0] = self.max_lun;
                 Some(InResponse::Accepted(&buf[0..1]))
             }
-            _ => None,
+            _ => Some(InResponse::Rejected),
         }
     }
 }